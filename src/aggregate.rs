@@ -0,0 +1,343 @@
+//! Reusable values for map-reduce jobs. Each one merges with another of
+//! its kind, so it serves as the reducer of a `MapReduceOp`:
+//!
+//! ```ignore
+//! MapReduceOp::new_map_reduce(|e| ... Ok(Box::new(Some((prop, Count(1))).into_iter())),
+//!                             aggregate::merge)
+//! ```
+//!
+//! Sketches (`Hll`, `TopK`, `Histogram`) have a bounded size whatever the
+//! count of values they have seen.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::hash::{Hash, Hasher};
+use std::ops::Add;
+
+pub trait Aggregate: Clone + Send + Sync {
+    fn merge(&self, other: &Self) -> Self;
+}
+
+/// Reducer for any `Aggregate`.
+pub fn merge<A: Aggregate>(a: &A, b: &A) -> A {
+    a.merge(b)
+}
+
+impl<A: Aggregate, B: Aggregate> Aggregate for (A, B) {
+    fn merge(&self, other: &(A, B)) -> (A, B) {
+        (self.0.merge(&other.0), self.1.merge(&other.1))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Count(pub u64);
+
+impl Aggregate for Count {
+    fn merge(&self, other: &Count) -> Count {
+        Count(self.0 + other.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct Sum<T>(pub T);
+
+impl<T: Add<Output = T> + Copy + Send + Sync> Aggregate for Sum<T> {
+    fn merge(&self, other: &Sum<T>) -> Sum<T> {
+        Sum(self.0 + other.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct Min<T>(pub T);
+
+impl<T: PartialOrd + Clone + Send + Sync> Aggregate for Min<T> {
+    fn merge(&self, other: &Min<T>) -> Min<T> {
+        if other.0 < self.0 { other.clone() } else { self.clone() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct Max<T>(pub T);
+
+impl<T: PartialOrd + Clone + Send + Sync> Aggregate for Max<T> {
+    fn merge(&self, other: &Max<T>) -> Max<T> {
+        if other.0 > self.0 { other.clone() } else { self.clone() }
+    }
+}
+
+/// 64 bits FNV-1a, with a final mix spreading the bits: HyperLogLog
+/// registers use the high bits, which FNV leaves poorly mixed on short
+/// values.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        // MurmurHash3 finalizer
+        let mut h = self.0;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51afd7ed558ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+        h ^ (h >> 33)
+    }
+}
+
+/// HyperLogLog distinct count, with 2^precision one-byte registers. The
+/// standard error is about 1.04 / sqrt(2^precision): 1.6% for the default
+/// precision of 12 (4KB). Only sketches of the same precision merge.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hll {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl Hll {
+    pub const DEFAULT_PRECISION: u8 = 12;
+
+    pub fn new(precision: u8) -> Hll {
+        assert!(precision >= 4 && precision <= 16,
+                "HyperLogLog precision must be between 4 and 16");
+        Hll {
+            precision: precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// A sketch holding one value.
+    pub fn of<T: Hash>(precision: u8, value: &T) -> Hll {
+        let mut hll = Hll::new(precision);
+        hll.insert(value);
+        hll
+    }
+
+    pub fn insert<T: Hash>(&mut self, value: &T) {
+        // not DefaultHasher, whose algorithm may change with the compiler:
+        // sketches saved by a run have to agree with the ones of the next
+        let mut hasher = Fnv::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let register = (hash >> (64 - self.precision)) as usize;
+        let rest = hash << self.precision;
+        let rank = ::std::cmp::min(rest.leading_zeros() as u8, 64 - self.precision) + 1;
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+        }
+    }
+
+    /// Estimated count of distinct values inserted.
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|&r| (-(r as f64)).exp2()).sum();
+        let raw = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // linear counting is better for small cardinalities
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+impl Aggregate for Hll {
+    fn merge(&self, other: &Hll) -> Hll {
+        assert_eq!(self.precision,
+                   other.precision,
+                   "can not merge HyperLogLogs of different precisions");
+        Hll {
+            precision: self.precision,
+            registers: self.registers
+                .iter()
+                .zip(other.registers.iter())
+                .map(|(a, b)| ::std::cmp::max(*a, *b))
+                .collect(),
+        }
+    }
+}
+
+/// Item of the bounded heap of `top_k_by`: the heap pops the lowest score.
+struct Ranked<S, T> {
+    score: S,
+    item: T,
+}
+
+impl<S: Ord, T> Ord for Ranked<S, T> {
+    fn cmp(&self, other: &Ranked<S, T>) -> Ordering {
+        other.score.cmp(&self.score)
+    }
+}
+
+impl<S: Ord, T> PartialOrd for Ranked<S, T> {
+    fn partial_cmp(&self, other: &Ranked<S, T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<S: Ord, T> PartialEq for Ranked<S, T> {
+    fn eq(&self, other: &Ranked<S, T>) -> bool {
+        self.score == other.score
+    }
+}
+
+impl<S: Ord, T> Eq for Ranked<S, T> {}
+
+/// The `k` items of highest score, highest first, in O(k) memory.
+pub fn top_k_by<I, T, S, F>(items: I, k: usize, score: F) -> Vec<T>
+    where I: IntoIterator<Item = T>,
+          S: Ord,
+          F: Fn(&T) -> S
+{
+    let mut heap = BinaryHeap::with_capacity(k + 1);
+    for item in items {
+        heap.push(Ranked {
+                      score: score(&item),
+                      item: item,
+                  });
+        if heap.len() > k {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec().into_iter().map(|r| r.item).collect()
+}
+
+/// The `k` (key, value) pairs of highest value, highest first.
+pub fn top_k<I, K, V>(pairs: I, k: usize) -> Vec<(K, V)>
+    where I: IntoIterator<Item = (K, V)>,
+          V: Ord + Clone
+{
+    top_k_by(pairs, k, |pair| pair.1.clone())
+}
+
+/// The `k` highest values seen, highest first.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopK<T> {
+    k: usize,
+    items: Vec<T>,
+}
+
+impl<T: Ord + Clone> TopK<T> {
+    pub fn new(k: usize) -> TopK<T> {
+        TopK { k: k, items: vec![] }
+    }
+
+    pub fn of(k: usize, item: T) -> TopK<T> {
+        TopK {
+            k: k,
+            items: if k > 0 { vec![item] } else { vec![] },
+        }
+    }
+
+    pub fn items(&self) -> &[T] {
+        &*self.items
+    }
+}
+
+impl<T: Ord + Clone + Send + Sync> Aggregate for TopK<T> {
+    fn merge(&self, other: &TopK<T>) -> TopK<T> {
+        let k = ::std::cmp::max(self.k, other.k);
+        let items = self.items.iter().chain(other.items.iter()).cloned();
+        TopK {
+            k: k,
+            items: top_k_by(items, k, |item| item.clone()),
+        }
+    }
+}
+
+/// Counts of values by power of two: bucket 0 counts zeros, bucket `i`
+/// the values in [2^(i-1), 2^i).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn of(value: u64) -> Histogram {
+        let bucket = 64 - value.leading_zeros() as usize;
+        let mut buckets = vec![0; bucket + 1];
+        buckets[bucket] = 1;
+        Histogram { buckets: buckets }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Non-empty buckets, as (lowest value, highest value, count).
+    pub fn buckets(&self) -> Vec<(u64, u64, u64)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(i, &count)| match i {
+                     0 => (0, 0, count),
+                     64 => (1 << 63, u64::max_value(), count),
+                     _ => (1 << (i - 1), (1 << i) - 1, count),
+                 })
+            .collect()
+    }
+}
+
+impl Aggregate for Histogram {
+    fn merge(&self, other: &Histogram) -> Histogram {
+        let len = ::std::cmp::max(self.buckets.len(), other.buckets.len());
+        Histogram {
+            buckets: (0..len)
+                .map(|i| {
+                         self.buckets.get(i).cloned().unwrap_or(0) +
+                         other.buckets.get(i).cloned().unwrap_or(0)
+                     })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hll_estimates_and_merges() {
+        let mut a = Hll::new(Hll::DEFAULT_PRECISION);
+        let mut b = Hll::new(Hll::DEFAULT_PRECISION);
+        for i in 0..60000 {
+            a.insert(&i);
+        }
+        for i in 40000..100000 {
+            b.insert(&i);
+        }
+        let merged = a.merge(&b).estimate();
+        assert!((merged - 100000.0).abs() < 5000.0, "estimate: {}", merged);
+        assert!((Hll::of(12, &"one").estimate() - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn hll_hash_is_fixed() {
+        let mut hasher = Fnv::new();
+        hasher.write(b"a");
+        assert_eq!(hasher.0, 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn top_k_keeps_highest() {
+        let pairs = vec![("a", 3), ("b", 7), ("c", 1), ("d", 5)];
+        assert_eq!(top_k(pairs, 2), vec![("b", 7), ("d", 5)]);
+    }
+}
@@ -0,0 +1,110 @@
+extern crate wolframite;
+extern crate clap;
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use clap::Arg;
+
+use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::mapred;
+use wolframite::store::DataStore;
+use wolframite::tabular;
+use wolframite::wiki;
+use wolframite::wiki::Page::Which::Text;
+use wolframite::wikidata;
+use wolframite::wikidata::EntityHelpers;
+
+fn main() {
+    let matches = cli::app("align",
+                           "Cross-language article alignment from Wikidata sitelinks")
+        .arg(Arg::with_name("missing")
+                 .short("m")
+                 .long("missing-in")
+                 .takes_value(true)
+                 .help("Only list items with an article in another wiki, but not in this one"))
+        .arg(Arg::with_name("wikis")
+                 .required(true)
+                 .multiple(true)
+                 .help("Wikis to align (enwiki frwiki ...)"))
+        .arg(cli::format_arg())
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let wikis: Vec<&str> = matches.values_of("wikis").unwrap().collect();
+        let format = try!(cli::format(&matches, tabular::Format::Tsv));
+        run(&store, &*wikis, matches.value_of("missing"), format)
+    });
+}
+
+/// Titles of the articles (main namespace, no redirects) of a compiled wiki.
+fn articles(store: &DataStore, name: &str) -> WikiResult<HashSet<String>> {
+    let wiki = try!(wiki::Wiki::latest_compiled(store, name));
+    let titles = Mutex::new(HashSet::new());
+    try!(mapred::par_try_foreach(try!(wiki.page_iter_iter()),
+                                 mapred::ErrorPolicy::FailFast,
+                                 Some(name),
+                                 &|page: WikiResult<wiki::MessageAndPage>| {
+                                      let page = try!(page);
+                                      let reader = try!(page.as_page_reader());
+                                      if let Text(_) = try!(reader.which()) {
+                                          if reader.get_ns() == 0 {
+                                              let title = try!(reader.get_title()).to_string();
+                                              titles.lock().unwrap().insert(title);
+                                          }
+                                      }
+                                      Ok(())
+                                  }));
+    Ok(titles.into_inner().unwrap())
+}
+
+fn run(store: &DataStore,
+       wikis: &[&str],
+       missing_in: Option<&str>,
+       format: tabular::Format)
+       -> WikiResult<()> {
+    let mut all: Vec<&str> = wikis.to_vec();
+    if let Some(missing) = missing_in {
+        if !all.contains(&missing) {
+            all.push(missing);
+        }
+    }
+    let mut titles = vec![];
+    for wiki in &all {
+        titles.push(try!(articles(store, wiki)));
+    }
+    let mut columns = vec!["qid"];
+    columns.extend(all.iter().cloned());
+    let out = Mutex::new(try!(tabular::stdout_writer(format, &*columns)));
+    let wd = try!(wikidata::Wikidata::latest_compiled(store));
+    try!(mapred::par_try_foreach(try!(wd.entity_iter_iter()),
+                                 mapred::ErrorPolicy::FailFast,
+                                 Some("wikidata"),
+                                 &|e: WikiResult<wikidata::EntityMessage>| {
+        let e = try!(e);
+        let mut row: Vec<String> = vec![String::new(); all.len()];
+        for sitelink in try!(e.get_sitelinks()) {
+            if let Some(ix) = all.iter().position(|w| *w == &*sitelink.site) {
+                if titles[ix].contains(&sitelink.title) {
+                    row[ix] = sitelink.title;
+                }
+            }
+        }
+        let keep = match missing_in {
+            Some(missing) => {
+                let ix = all.iter().position(|w| *w == missing).unwrap();
+                row[ix].is_empty() &&
+                row.iter().enumerate().any(|(i, t)| i != ix && !t.is_empty())
+            }
+            None => row.iter().any(|t| !t.is_empty()),
+        };
+        if keep {
+            row.insert(0, try!(e.get_id()).to_string());
+            try!(out.lock().unwrap().write_row(&*row));
+        }
+        Ok(())
+    }));
+    let mut out = out.into_inner().unwrap();
+    out.flush()
+}
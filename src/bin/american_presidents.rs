@@ -3,40 +3,45 @@
 extern crate wolframite;
 
 use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::store::DataStore;
 use wolframite::wikidata;
 use wolframite::wikidata::EntityHelpers;
 use wolframite::mapred;
 use wolframite::wikidata::EntityRef;
 
 fn main() {
-    count().unwrap()
+    let matches = cli::app("american_presidents", "List US presidents found in Wikidata, real or fictional").get_matches();
+    cli::run(|| count(&try!(cli::setup(&matches))));
 }
 
-fn count() -> WikiResult<()> {
-    let wd = wikidata::Wikidata::latest_compiled().unwrap();
+fn count(store: &DataStore) -> WikiResult<()> {
+    let wd = try!(wikidata::Wikidata::latest_compiled(store));
 
-    mapred::par_foreach(wd.entity_iter_iter().unwrap(),
-                        &|e: WikiResult<wikidata::EntityMessage>| {
-        let e = e.unwrap();
-        let mut relations = e.get_relations().unwrap();
+    try!(mapred::par_try_foreach(try!(wd.entity_iter_iter()),
+                                 mapred::ErrorPolicy::FailFast,
+                                 None,
+                                 &|e: WikiResult<wikidata::EntityMessage>| {
+        let e = try!(e);
+        let mut relations = try!(e.get_relations());
         if relations.any(|t| t == (EntityRef::P(39), EntityRef::Q(11696))) {
-            let mut relations = e.get_relations().unwrap();
+            let mut relations = try!(e.get_relations());
             let fiction = relations.any(|t| {
                                             t == (EntityRef::P(31), EntityRef::Q(95074)) ||
                                             t == (EntityRef::P(31), EntityRef::Q(15632617))
                                         });
             if fiction {
-                let work = e.get_relations()
-                    .unwrap()
+                let work = try!(e.get_relations())
                     .find(|t| t.0 == EntityRef::P(1441))
                     .and_then(|t| wd.get_label(&*t.1.get_id()));
                 println!("{} ({})",
-                         e.get_a_label().unwrap(),
+                         try!(e.get_a_label()),
                          &*work.unwrap_or("unknown work".to_string()));
             } else {
-                println!("{}, REAL", e.get_a_label().unwrap());
+                println!("{}, REAL", try!(e.get_a_label()));
             }
         }
-    });
+        Ok(())
+    }));
     Ok(())
 }
@@ -3,70 +3,143 @@ extern crate glob;
 extern crate bzip2;
 extern crate flate2;
 extern crate snappy_framed;
-extern crate simple_parallel;
-extern crate num_cpus;
+extern crate clap;
 
 use wolframite::WikiError;
-use wolframite::helpers;
+use wolframite::cli;
+use wolframite::store::{DataStore, Stage};
 use wolframite::capitanize_wikidata;
 use wolframite::capitanize_wiki;
+use wolframite::mapred;
+use wolframite::multistream;
 
 use std::fs;
+use std::io;
+use std::io::prelude::*;
 
 use std::process;
 
 use std::path;
 
+use clap::Arg;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let ref lang = args[1];
-    let date: String = if args.len() < 3 || args[2] == "latest" {
-        helpers::latest("download", lang).unwrap().unwrap()
-    } else {
-        args[2].to_string()
-    };
-    capitanize(lang, &*date).unwrap();
+    let matches = cli::app("capitanize", "Compile downloaded dumps to capnp")
+        .arg(cli::wiki_arg().required(true))
+        .arg(cli::date_arg())
+        .arg(Arg::with_name("history")
+                 .long("history")
+                 .help("Compile the full-history dump instead of the articles one"))
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let lang = matches.value_of("wiki").unwrap();
+        let date = try!(cli::date(&matches, &store, Stage::Download, lang));
+        capitanize(&store, lang, &*date, matches.is_present("history"))
+    });
+}
+
+/// Name of the compiled parts for a dump file: the file name without its
+/// compression and format extensions, keeping the page range of split dumps
+/// ("enwiki-20170101-pages-meta-history1.xml-p10p2289.bz2" gives
+/// "enwiki-20170101-pages-meta-history1-p10p2289").
+fn target_stem(file_name: &str) -> String {
+    file_name.trim_right_matches(".bz2")
+        .trim_right_matches(".json.gz")
+        .replace(".xml", "")
+}
+
+enum Source {
+    File(path::PathBuf),
+    Streams(multistream::StreamRange),
+}
+
+impl Source {
+    fn len(&self) -> u64 {
+        match self {
+            &Source::File(ref file) => file.metadata().map(|m| m.len()).unwrap_or(0),
+            &Source::Streams(ref range) => range.len(),
+        }
+    }
 }
 
-pub fn capitanize(lang: &str, date: &str) -> Result<(), WikiError> {
-    let source_root = helpers::data_dir_for("download", lang, date);
-    let target_root = helpers::data_dir_for("cap", lang, date);
-    let _ = fs::remove_dir_all(target_root.clone());
-    try!(fs::create_dir_all(target_root.clone()));
-    let extension = if lang == "wikidata" { "json.gz" } else { "bz2" };
-    let glob = source_root.clone() + "/*." + extension;
-    let jobs: Result<Vec<(path::PathBuf, path::PathBuf)>, WikiError> = try!(::glob::glob(&glob))
-        .map(|entry| {
-                 let entry: String = try!(entry).to_str().unwrap().to_string();
-                 let target = target_root.clone() +
-                              &entry[source_root.len()..entry.find(".").unwrap()];
-                 Ok((path::PathBuf::from(&*entry), path::PathBuf::from(&target)))
-             })
-        .collect();
-    let mut jobs = try!(jobs);
-    jobs.sort_by(|a, b| {
-                     b.0
-                         .metadata()
-                         .unwrap()
-                         .len()
-                         .cmp(&a.0.metadata().unwrap().len())
-                 });
-    let mut pool = simple_parallel::Pool::new(1 + num_cpus::get());
-    let task = |job: (path::PathBuf, path::PathBuf)| if lang != "wikidata" {
-        let input = bzip2::read::BzDecoder::new(try!(fs::File::open(&*job.0)));
-        capitanize_wiki::process(input, &*job.1)
+pub fn capitanize(store: &DataStore,
+                  lang: &str,
+                  date: &str,
+                  history: bool)
+                  -> Result<(), WikiError> {
+    let source_root = store.dir(Stage::Download, lang, date);
+    let stage = if history { Stage::History } else { Stage::Cap };
+    let staging = try!(store.staging(stage, lang, date));
+    let target_root = staging.dir().to_string();
+    let pattern = if lang == "wikidata" {
+        "*.json.gz"
+    } else if history {
+        "*pages-meta-history*.bz2"
+    } else {
+        "*pages-articles*.bz2"
+    };
+    let glob = source_root.clone() + "/" + pattern;
+    let found: Vec<path::PathBuf> = try!(try!(::glob::glob(&glob)).collect());
+    let dumps = multistream::one_flavour(found);
+    if dumps.len() == 0 {
+        Err(format!("no dump matching {}", glob))?
+    }
+    let mut jobs: Vec<(Source, path::PathBuf)> = vec![];
+    for entry in dumps {
+        let stem = target_stem(entry.file_name().unwrap().to_str().unwrap());
+        let target = path::Path::new(&target_root).join(stem);
+        if multistream::is_multistream(&*entry) {
+            // split big multistream dumps so that they get decompressed on all cores
+            let ranges = try!(multistream::split(&*entry, 4 * mapred::pool().current_num_threads()));
+            for (i, range) in ranges.into_iter().enumerate() {
+                let chunk_target = path::PathBuf::from(format!("{}-s{:04}",
+                                                               target.to_str().unwrap(),
+                                                               i));
+                jobs.push((Source::Streams(range), chunk_target));
+            }
+        } else {
+            jobs.push((Source::File(entry), target));
+        }
+    }
+    // largest first, so that no big job starts last and runs alone
+    jobs.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    let task = |job: (Source, path::PathBuf)| if lang != "wikidata" {
+        let input: Box<Read + Send> = match job.0 {
+            Source::File(ref file) => {
+                Box::new(bzip2::read::BzDecoder::new(try!(fs::File::open(file))))
+            }
+            Source::Streams(ref range) => range.reader(),
+        };
+        if history {
+            capitanize_wiki::process_history(input, &*job.1)
+        } else {
+            capitanize_wiki::process(input, &*job.1)
+        }
     } else {
+        let file = match job.0 {
+            Source::File(file) => file,
+            Source::Streams(_) => Err("multistream is not a wikidata format".to_string())?,
+        };
         let cmd = try!(process::Command::new("gzcat")
                            .arg("-d")
-                           .arg(&*job.0)
+                           .arg(&*file)
                            .stdout(process::Stdio::piped())
                            .spawn());
         try!(capitanize_wikidata::process(cmd.stdout.unwrap(), &*job.1));
-        Ok(())
+        Ok(capitanize_wiki::ModelCounts::new())
     };
-    let result: Result<Vec<()>, WikiError> = unsafe { pool.map(jobs, &task).collect() };
-    try!(result);
-    let _ = fs::File::create(format!("data/cap/{}/{}/ok", lang, &*date));
-    Ok(())
+    let counts: Vec<capitanize_wiki::ModelCounts> = try!(mapred::par_try_map_queued(jobs, task));
+    if lang != "wikidata" {
+        let models = try!(capitanize_wiki::write_model_report(&*counts,
+                                                              path::Path::new(&target_root)));
+        for (model, count) in models.iter() {
+            let flag = if capitanize_wiki::is_known_model(model) { "" } else { " (unknown)" };
+            try!(writeln!(io::stderr(), "model {}: {}{}", model, count, flag));
+        }
+        if history {
+            try!(capitanize_wiki::write_history_index(path::Path::new(&target_root)));
+        }
+    }
+    staging.commit()
 }
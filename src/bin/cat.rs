@@ -1,13 +1,21 @@
 extern crate wolframite;
 
+use wolframite::cli;
 use wolframite::helpers;
+use wolframite::store::Stage;
 use std::io;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let ref lang = args[1];
-    let ref date = args[2];
-
-    let mut source = helpers::bzcat(lang, date).unwrap();
-    io::copy(&mut source, &mut io::stdout()).unwrap();
+    let matches = cli::app("cat", "Decompress a downloaded wiki dump to stdout")
+        .arg(cli::wiki_arg().required(true))
+        .arg(cli::date_arg())
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let lang = matches.value_of("wiki").unwrap();
+        let date = try!(cli::date(&matches, &store, Stage::Download, lang));
+        let mut source = try!(helpers::bzcat(&store, lang, &*date));
+        try!(io::copy(&mut source, &mut io::stdout()));
+        Ok(())
+    });
 }
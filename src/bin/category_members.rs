@@ -0,0 +1,42 @@
+extern crate wolframite;
+extern crate clap;
+
+use clap::Arg;
+
+use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::store::DataStore;
+use wolframite::wiki::Wiki;
+use wolframite::categories::CategoryGraph;
+
+fn main() {
+    let matches = cli::app("category_members", "List the articles of a category")
+        .arg(cli::wiki_arg())
+        .arg(Arg::with_name("depth")
+                 .long("depth")
+                 .takes_value(true)
+                 .help("Subcategory depth (default 0)"))
+        .arg(Arg::with_name("category")
+                 .required(true)
+                 .help("Category name, without namespace prefix"))
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let wiki = matches.value_of("wiki").unwrap_or("enwiki");
+        let depth: usize = try!(matches
+                                    .value_of("depth")
+                                    .unwrap_or("0")
+                                    .parse()
+                                    .map_err(|_| "--depth expects a number"));
+        run(&store, wiki, matches.value_of("category").unwrap(), depth)
+    });
+}
+
+fn run(store: &DataStore, wiki: &str, category: &str, depth: usize) -> WikiResult<()> {
+    let wiki = try!(Wiki::latest_compiled(store, wiki));
+    let graph = try!(CategoryGraph::for_wiki(&wiki));
+    for article in graph.articles_under(category, depth) {
+        println!("{}", article);
+    }
+    Ok(())
+}
@@ -2,67 +2,100 @@ extern crate regex;
 extern crate reqwest;
 extern crate time;
 extern crate wolframite;
+extern crate clap;
 
 use std::io;
 use std::io::prelude::*;
 use std::fs;
 use std::path;
 
+use clap::Arg;
 use regex::Regex;
 
-use wolframite::helpers;
+use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::store::{DataStore, Stage};
 
 const PREFIX: &'static str = "http://dumps.wikimedia.org";
 
-fn latest_available(lang: &str, item: &str) -> Option<String> {
+fn get(url: &str) -> WikiResult<reqwest::Response> {
+    Ok(try!(reqwest::get(url).map_err(|e| format!("GET {}: {}", url, e))))
+}
+
+fn latest_available(lang: &str, item: &str) -> WikiResult<Option<String>> {
     let rss_url = format!("{}/{}/latest/{}-latest-{}-rss.xml",
                           PREFIX,
                           lang,
                           lang,
                           item);
-    let res = reqwest::get(&rss_url).unwrap();
+    let res = try!(get(&rss_url));
     let buffered = io::BufReader::new(res);
     let re = Regex::new(r#"<link>.*/(20\d+)</link>"#).unwrap();
     for line in buffered.lines() {
-        let line = line.unwrap();
+        let line = try!(line);
         if let Some(found) = re.captures(&line) {
-            return Some(found[1].to_string());
+            return Ok(Some(found[1].to_string()));
         }
     }
-    None
+    Ok(None)
 }
 
-fn latest_wikidata_available() -> Option<String> {
-    let res = reqwest::get("http://dumps.wikimedia.org/other/wikidata/").unwrap();
+fn latest_wikidata_available() -> WikiResult<Option<String>> {
+    let res = try!(get("http://dumps.wikimedia.org/other/wikidata/"));
     let buffered = io::BufReader::new(res);
     let re = Regex::new(r#"href="(\d*)\.json\.gz"#).unwrap();
-    buffered
-        .lines()
-        .flat_map(|line| {
-                      let line: String = line.unwrap();
-                      re.captures(&*line).map(|cap| cap[1].to_string())
-                  })
-        .last()
+    let mut found = None;
+    for line in buffered.lines() {
+        let line: String = try!(line);
+        if let Some(cap) = re.captures(&*line) {
+            found = Some(cap[1].to_string());
+        }
+    }
+    Ok(found)
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let ref lang = args[1];
-    let date = if args.len() >= 3 && args[2].to_string() != "latest" {
-        Some(args[2].to_string())
-    } else {
-        None
-    };
-    if lang == "wikidata" {
-        download_wikidata(date)
-    } else if lang == "pagecounts" {
-        download_pagecounts(date)
-    } else {
-        download_wiki(lang, date)
-    }
+    let matches = cli::app("download", "Download dumps from dumps.wikimedia.org")
+        .arg(cli::wiki_arg()
+                 .required(true)
+                 .help("Wiki name (enwiki, frwiki...), wikidata, or pagecounts"))
+        .arg(cli::date_arg())
+        .arg(Arg::with_name("history")
+                 .long("history")
+                 .help("Also fetch the full-history dump (pages-meta-history)"))
+        .arg(Arg::with_name("multistream")
+                 .long("multistream")
+                 .help("Fetch the multistream articles dump and its index"))
+        .arg(Arg::with_name("categorylinks")
+                 .long("categorylinks")
+                 .help("Also fetch the categorylinks table"))
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let lang = matches.value_of("wiki").unwrap();
+        let date = matches
+            .value_of("date")
+            .and_then(|d| if d == "latest" { None } else { Some(d.to_string()) });
+        let categorylinks = matches.is_present("categorylinks");
+        let mut items = vec![if matches.is_present("multistream") {
+                                 "pages-articles-multistream"
+                             } else {
+                                 "pages-articles"
+                             }];
+        if matches.is_present("history") {
+            items.push("pages-meta-history");
+        }
+        if lang == "wikidata" {
+            download_wikidata(&store, date)
+        } else if lang == "pagecounts" {
+            download_pagecounts(&store, date)
+        } else {
+            download_wiki(&store, lang, date, &*items, categorylinks)
+        }
+    });
 }
 
-fn download_pagecounts(date: Option<String>) {
+fn download_pagecounts(store: &DataStore, date: Option<String>) -> WikiResult<()> {
     let date: String = date.unwrap_or_else(|| {
                                                let yesterday = time::now_utc() -
                                                                time::Duration::days(1);
@@ -72,15 +105,16 @@ fn download_pagecounts(date: Option<String>) {
                             &date[0..4],
                             &date[0..4],
                             &date[4..6]);
-    let dir = helpers::data_dir_for("download", "pagecounts", &*date);
-    fs::create_dir_all(&*dir).unwrap();
-    let index = reqwest::get(&index_url).unwrap();
+    let _lock = try!(store.lock(Stage::Download, "pagecounts", &*date));
+    let dir = store.dir(Stage::Download, "pagecounts", &*date);
+    try!(fs::create_dir_all(&*dir));
+    let index = try!(get(&index_url));
     let buffered = io::BufReader::new(index);
     let expr = format!(r#"href="(pagecounts-{}-.*\.gz)""#, date);
     let re = Regex::new(&*expr).unwrap();
     let mut files: Vec<String> = vec![];
     for line in buffered.lines() {
-        let line = line.unwrap();
+        let line = try!(line);
         if let Some(cap) = re.captures(&*line) {
             files.push(cap[1].to_string());
         }
@@ -88,69 +122,124 @@ fn download_pagecounts(date: Option<String>) {
     for filename in files {
         let url = index_url.clone() + "/" + &*filename;
         let local_filename = dir.clone() + "/" + &*filename;
-        download_if_smaller(url, local_filename);
+        try!(download_if_smaller(url, local_filename));
     }
-    let _ = fs::File::create(format!("{}/ok", &*dir));
+    try!(store.mark_complete(Stage::Download, "pagecounts", &*date));
+    Ok(())
 }
 
-fn download_wikidata(date: Option<String>) {
-    let date: String = date.or_else(|| latest_wikidata_available()).unwrap();
+fn download_wikidata(store: &DataStore, date: Option<String>) -> WikiResult<()> {
+    let date: String = match date {
+        Some(date) => date,
+        None => {
+            try!(try!(latest_wikidata_available()).ok_or("no wikidata dump found online"))
+        }
+    };
     let url = format!("http://dumps.wikimedia.org/other/wikidata/{}.json.gz",
                       &*date);
-    let dir = helpers::data_dir_for("download", "wikidata", &*date);
-    fs::create_dir_all(&*dir).unwrap();
+    let _lock = try!(store.lock(Stage::Download, "wikidata", &*date));
+    let dir = store.dir(Stage::Download, "wikidata", &*date);
+    try!(fs::create_dir_all(&*dir));
     let filename = format!("{}/wikidata-{}.json.gz", &*dir, &*date);
-    download_if_smaller(url, filename);
-    let _ = fs::File::create(format!("{}/ok", &*dir));
+    try!(download_if_smaller(url, filename));
+    try!(store.mark_complete(Stage::Download, "wikidata", &*date));
+    Ok(())
 }
 
-fn download_wiki(lang: &String, optdate: Option<String>) {
-    let date: String = optdate
-        .or_else(|| latest_available(lang, "pages-articles1.xml.bz2"))
-        .or_else(|| latest_available(lang, "pages-articles.xml.bz2"))
-        .unwrap();
-    let dir = helpers::data_dir_for("download", &*lang, &*date);
-    fs::create_dir_all(&*dir).unwrap();
-
-    let summary_url = format!("{}/{}/{}/", PREFIX, lang, &*date);
-
-    let res = reqwest::get(&summary_url).unwrap();
-
-    let buffered = io::BufReader::new(res);
-    let expr = format!(r#"href="(/{}/{}/{}-{}-pages-articles\d[^\\"]*)""#,
+/// Dump files of one flavour listed on the summary page of a date.
+/// `item` is "pages-articles", "pages-articles-multistream" (listed along
+/// with its stream index), or "pages-meta-history" for full revision
+/// history.
+fn dump_files(lang: &str, date: &str, summary: &str, item: &str) -> Vec<String> {
+    let expr = format!(r#"href="(/{}/{}/{}-{}-{}\d[^\\"]*\.bz2)""#,
                        lang,
                        date,
                        lang,
-                       date);
+                       date,
+                       item);
     let re = Regex::new(&*expr).unwrap();
+    let multistream = item.ends_with("multistream");
+    let index_expr = format!(r#"href="(/{}/{}/{}-{}-{}-index\d[^\\"]*\.bz2)""#,
+                             lang,
+                             date,
+                             lang,
+                             date,
+                             item);
+    let index_re = Regex::new(&*index_expr).unwrap();
 
     let mut files = vec![];
-    for line in buffered.lines() {
-        let line = line.unwrap();
+    for line in summary.lines() {
         for cap in re.captures_iter(&*line) {
             files.push(cap[1].to_string());
         }
+        if multistream {
+            for cap in index_re.captures_iter(&*line) {
+                files.push(cap[1].to_string());
+            }
+        }
     }
 
     if files.len() == 0 {
-        files.push(format!("/{}/{}/{}-{}-pages-articles.xml.bz2",
-                           lang,
-                           date,
-                           lang,
-                           date));
+        files.push(format!("/{}/{}/{}-{}-{}.xml.bz2", lang, date, lang, date, item));
+        if multistream {
+            files.push(format!("/{}/{}/{}-{}-{}-index.txt.bz2", lang, date, lang, date, item));
+        }
+    }
+    files
+}
+
+/// `items` are the dump flavours to fetch (see `dump_files`).
+/// `categorylinks` also fetches the categorylinks table dump.
+fn download_wiki(store: &DataStore,
+                 lang: &str,
+                 optdate: Option<String>,
+                 items: &[&str],
+                 categorylinks: bool)
+                 -> WikiResult<()> {
+    let date: String = match optdate {
+        Some(date) => date,
+        None => {
+            let found = match try!(latest_available(lang, "pages-articles1.xml.bz2")) {
+                Some(date) => Some(date),
+                None => try!(latest_available(lang, "pages-articles.xml.bz2")),
+            };
+            try!(found.ok_or(format!("no dump found online for {}", lang)))
+        }
+    };
+    let _lock = try!(store.lock(Stage::Download, &*lang, &*date));
+    let dir = store.dir(Stage::Download, &*lang, &*date);
+    try!(fs::create_dir_all(&*dir));
+
+    let summary_url = format!("{}/{}/{}/", PREFIX, lang, &*date);
+
+    let mut summary = String::new();
+    try!(try!(get(&summary_url)).read_to_string(&mut summary));
+
+    let mut files = vec![];
+    for item in items {
+        files.extend(dump_files(lang, &*date, &*summary, item));
+    }
+    if categorylinks {
+        files.push(format!("/{}/{}/{}-{}-categorylinks.sql.gz", lang, date, lang, date));
     }
 
     for filename in files {
         let url = PREFIX.to_string() + "/" + &*filename;
-        let local_filename = "data/download".to_string() + &*filename;
-        download_if_smaller(url, local_filename);
+        let local_filename = format!("{}/{}{}", store.root().display(), Stage::Download, filename);
+        try!(download_if_smaller(url, local_filename));
     }
-    let _ = fs::File::create(format!("{}/ok", &*dir));
+    try!(store.mark_complete(Stage::Download, lang, &*date));
+    Ok(())
 }
 
-fn download_if_smaller(url: String, filename: String) {
+/// Files are fetched under a ".part" name and renamed once complete, so a
+/// partial download never passes for a dump file.
+fn download_if_smaller(url: String, filename: String) -> WikiResult<()> {
     let path = path::Path::new(&*filename);
-    let mut res = reqwest::get(&*url).unwrap();
+    let mut res = try!(get(&*url));
+    if !res.status().is_success() {
+        Err(format!("GET {}: {}", url, res.status()))?
+    }
     let size: Option<u64> = res.headers()
         .get::<reqwest::header::ContentLength>()
         .map(|x| **x);
@@ -158,7 +247,11 @@ fn download_if_smaller(url: String, filename: String) {
        path.metadata().map(|m| m.len()).unwrap_or(0) == size.unwrap() {
         println!("skip {} (size: {})", filename, size.unwrap());
     } else {
-        let mut file = fs::File::create(path).unwrap();
-        io::copy(&mut res, &mut file).unwrap();
+        let part = format!("{}.part", filename);
+        let mut file = try!(fs::File::create(&*part));
+        try!(io::copy(&mut res, &mut file));
+        try!(file.sync_all());
+        try!(fs::rename(&*part, path));
     }
+    Ok(())
 }
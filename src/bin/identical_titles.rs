@@ -1,22 +1,47 @@
 extern crate wolframite;
 
 use wolframite::WikiResult;
+use wolframite::checkpoint::Checkpoint;
+use wolframite::cli;
+use wolframite::store::DataStore;
 use wolframite::wikidata;
 use wolframite::wikidata::EntityHelpers;
-use wolframite::mapred::MapReduceOp;
+use wolframite::mapred::{BI, MapReduceOp};
 
 fn main() {
-    let wd = wikidata::Wikidata::latest_compiled().unwrap();
+    let matches = cli::app("identical_titles",
+                           "Count the entities with identical English and German labels")
+        .arg(cli::job_arg())
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let checkpoint = try!(cli::checkpoint(&matches, &store));
+        count(&store, checkpoint)
+    });
+}
+
+fn count(store: &DataStore, checkpoint: Option<Checkpoint>) -> WikiResult<()> {
+    let wd = try!(wikidata::Wikidata::latest_compiled(store));
 
-    let result = MapReduceOp::map_reduce(|e: WikiResult<wikidata::EntityMessage>| {
-                                             let e = e.unwrap();
-                                             let en = e.get_label("en").unwrap();
-                                             let de = e.get_label("de").unwrap();
-                                             let identical:bool = en.is_some() && de == en;
-                                             Box::new(Some((identical, 1)).into_iter())
-                                         },
-                                         |a, b| a + b,
-                                         wd.entity_iter_iter().unwrap());
+    let mro = MapReduceOp::new_map_reduce(|e: WikiResult<wikidata::EntityMessage>|
+                                           -> WikiResult<BI<(bool, usize)>> {
+                                              let e = try!(e);
+                                              let en = try!(e.get_label("en"));
+                                              let de = try!(e.get_label("de"));
+                                              let identical:bool = en.is_some() && de == en;
+                                              Ok(Box::new(Some((identical, 1)).into_iter()))
+                                          },
+                                          |a, b| a + b)
+        .with_progress("identical_titles");
+    let chunks = try!(wd.entity_iter_iter());
+    let (result, _) = match checkpoint {
+        Some(ref checkpoint) => try!(mro.run_checkpointed(chunks, checkpoint)),
+        None => try!(mro.run(chunks)),
+    };
 
     println!("results: {:?}", result);
+    if let Some(checkpoint) = checkpoint {
+        try!(checkpoint.clear());
+    }
+    Ok(())
 }
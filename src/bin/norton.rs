@@ -3,25 +3,34 @@
 extern crate wolframite;
 
 use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::store::DataStore;
 use wolframite::wikidata;
 use wolframite::wikidata::EntityHelpers;
 use wolframite::mapred;
 use wolframite::wikidata::EntityRef;
 
 fn main() {
-    count().unwrap()
+    let matches = cli::app("norton", "List the works with Edward Norton in the cast").get_matches();
+    cli::run(|| count(&try!(cli::setup(&matches))));
 }
 
-fn count() -> WikiResult<()> {
-    let wd = wikidata::Wikidata::latest_compiled().unwrap();
+fn count(store: &DataStore) -> WikiResult<()> {
+    let wd = try!(wikidata::Wikidata::latest_compiled(store));
 
-    mapred::par_foreach(wd.entity_iter_iter().unwrap(),
-                        &|e: WikiResult<wikidata::EntityMessage>| {
-                             let e = e.unwrap();
-                             let mut relations = e.get_relations().unwrap();
-                             if relations.any(|t| t == (EntityRef::P(161), EntityRef::Q(125904))) {
-                                 println!("{}", e.get_a_label().unwrap());
-                             }
-                         });
+    try!(mapred::par_try_foreach(try!(wd.entity_iter_iter()),
+                                 mapred::ErrorPolicy::FailFast,
+                                 None,
+                                 &|e: WikiResult<wikidata::EntityMessage>| {
+                                      let e = try!(e);
+                                      let mut relations = try!(e.get_relations());
+                                      if relations.any(|t| {
+                                                           t == (EntityRef::P(161),
+                                                                 EntityRef::Q(125904))
+                                                       }) {
+                                          println!("{}", try!(e.get_a_label()));
+                                      }
+                                      Ok(())
+                                  }));
     Ok(())
 }
@@ -0,0 +1,104 @@
+extern crate wolframite;
+extern crate clap;
+
+use std::io;
+
+use clap::{Arg, SubCommand};
+
+use wolframite::cli;
+use wolframite::gc;
+use wolframite::pipeline;
+use wolframite::pipeline::Pipeline;
+use wolframite::store::Stage;
+
+fn main() {
+    let matches = cli::app("pipeline", "Build, inspect and clean up the data tree")
+        .subcommand(SubCommand::with_name("run")
+                        .about("Run what is missing or stale for a wiki and a date")
+                        .arg(cli::wiki_arg().required(true))
+                        .arg(cli::date_arg().help("Dump date (YYYYMMDD), or \"latest\" \
+                                                   downloaded (the default; the latest \
+                                                   online on a fresh store)"))
+                        .arg(Arg::with_name("history")
+                                 .long("history")
+                                 .help("Also compile the full-history dump"))
+                        .arg(Arg::with_name("dry-run")
+                                 .short("n")
+                                 .long("dry-run")
+                                 .help("Only show what would run")))
+        .subcommand(SubCommand::with_name("status")
+                        .about("List the stages present for each wiki and date"))
+        .subcommand(SubCommand::with_name("gc")
+                        .about("Remove old dates, keeping the most recent complete ones")
+                        .arg(cli::wiki_arg().help("Only collect this wiki"))
+                        .arg(Arg::with_name("keep")
+                                 .short("k")
+                                 .long("keep")
+                                 .takes_value(true)
+                                 .help("Complete dates to keep per stage and wiki (default 2)"))
+                        .arg(Arg::with_name("dry-run")
+                                 .short("n")
+                                 .long("dry-run")
+                                 .help("Only show what would be removed")))
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        match matches.subcommand() {
+            ("run", Some(sub)) => {
+                let wiki = sub.value_of("wiki").unwrap();
+                let pipeline = Pipeline::new(&store, wiki).with_history(sub.is_present("history"));
+                let date = match sub.value_of("date") {
+                    Some(date) if date != "latest" => date.to_string(),
+                    _ if sub.is_present("dry-run") => {
+                        try!(store.latest_or_err(Stage::Download, wiki))
+                    }
+                    _ => try!(pipeline.latest_or_download()),
+                };
+                if sub.is_present("dry-run") {
+                    for (stage, status) in try!(pipeline.plan(&*date)) {
+                        println!("{} {} {}: {}", wiki, date, stage, status);
+                    }
+                    return Ok(());
+                }
+                for step in try!(pipeline.run(&*date)) {
+                    match step.ran_for {
+                        Some(duration) => {
+                            println!("{} {} {}: was {}, rebuilt in {}s",
+                                     wiki,
+                                     date,
+                                     step.stage,
+                                     step.before,
+                                     duration.as_secs())
+                        }
+                        None => println!("{} {} {}: up to date", wiki, date, step.stage),
+                    }
+                }
+                Ok(())
+            }
+            ("status", _) => pipeline::write_status(&store, &mut io::stdout()),
+            ("gc", Some(sub)) => {
+                let keep: usize = try!(sub.value_of("keep")
+                                           .unwrap_or("2")
+                                           .parse()
+                                           .map_err(|_| "--keep expects a number"));
+                if keep == 0 {
+                    Err("--keep must be at least 1")?
+                }
+                let removals = try!(gc::plan(&store, keep, sub.value_of("wiki")));
+                let mut total = 0;
+                for removal in &removals {
+                    println!("remove {} ({} MB)", removal.path, removal.bytes / 1_000_000);
+                    total += removal.bytes;
+                }
+                if sub.is_present("dry-run") {
+                    println!("would free {} MB", total / 1_000_000);
+                } else {
+                    try!(gc::apply(&*removals));
+                    println!("freed {} MB", total / 1_000_000);
+                }
+                Ok(())
+            }
+            _ => Err("expected a subcommand: run, status or gc".into()),
+        }
+    });
+}
@@ -0,0 +1,52 @@
+extern crate wolframite;
+extern crate clap;
+
+use clap::Arg;
+
+use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::store::DataStore;
+use wolframite::wikidata;
+use wolframite::wikidata::{EntityHelpers, EntityRef};
+use wolframite::aggregate;
+use wolframite::aggregate::Count;
+use wolframite::mapred::{BI, MapReduceOp};
+
+fn main() {
+    let matches = cli::app("properties", "Count the entity-valued claims of each property")
+        .arg(Arg::with_name("top")
+                 .short("n")
+                 .long("top")
+                 .takes_value(true)
+                 .help("Most used properties to show (default: 100)"))
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let top = try!(matches.value_of("top")
+                           .unwrap_or("100")
+                           .parse()
+                           .map_err(|_| "--top expects a number"));
+        count(&store, top)
+    });
+}
+
+fn count(store: &DataStore, top: usize) -> WikiResult<()> {
+    let wd = try!(wikidata::Wikidata::latest_compiled(store));
+
+    let mro = MapReduceOp::new_map_reduce(|e: WikiResult<wikidata::EntityMessage>|
+                                           -> WikiResult<BI<(EntityRef, Count)>> {
+        let e = try!(e);
+        Ok(Box::new(try!(e.get_relations()).map(|t| (t.0, Count(1)))))
+    },
+                                          aggregate::merge)
+        .with_progress("properties");
+    let (counts, _) = try!(mro.run(try!(wd.entity_iter_iter())));
+    for (property, count) in aggregate::top_k(counts, top) {
+        let id = property.get_id();
+        println!("{}\t{}\t{}",
+                 count.0,
+                 id,
+                 wd.get_label(&*id).unwrap_or("no label".to_string()));
+    }
+    Ok(())
+}
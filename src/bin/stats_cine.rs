@@ -1,33 +1,39 @@
 extern crate wolframite;
 
 use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::store::DataStore;
 use wolframite::wikidata;
 use wolframite::wikidata::EntityHelpers;
-use wolframite::mapred::MapReduceOp;
+use wolframite::aggregate;
+use wolframite::aggregate::Count;
+use wolframite::mapred::{BI, ErrorPolicy, MapReduceOp};
 use wolframite::wikidata::EntityRef;
 
 fn main() {
-    count().unwrap()
+    let matches = cli::app("stats_cine", "Count the films with a Rotten Tomatoes identifier").get_matches();
+    cli::run(|| count(&try!(cli::setup(&matches))));
 }
 
-#[allow(dead_code)]
-fn count() -> WikiResult<()> {
-    let wd = wikidata::Wikidata::latest_compiled().unwrap();
+fn count(store: &DataStore) -> WikiResult<()> {
+    let wd = try!(wikidata::Wikidata::latest_compiled(store));
 
-    let mro = MapReduceOp::new_map_reduce(|e: WikiResult<wikidata::EntityMessage>| {
-        let e = e.unwrap();
-        Box::new(vec![((),
-                       e.get_relations()
-                           .unwrap()
-                           .any(|t| {
-                                    (t.0 == EntityRef::P(31) && t.1 == EntityRef::Q(11424) &&
-                                     e.get_claim(EntityRef::P(1258)).unwrap().is_some())
-                                }) as usize)]
-                         .into_iter())
+    let mro = MapReduceOp::new_map_reduce(|e: WikiResult<wikidata::EntityMessage>|
+                                           -> WikiResult<BI<((), Count)>> {
+        let e = try!(e);
+        let film = try!(e.get_relations())
+            .any(|t| t.0 == EntityRef::P(31) && t.1 == EntityRef::Q(11424));
+        let rated = film && try!(e.get_claim(EntityRef::P(1258))).is_some();
+        Ok(Box::new(Some(((), Count(rated as u64))).into_iter()))
     },
-                                          |a: &usize, b: &usize| a + b);
+                                          aggregate::merge)
+        .with_policy(ErrorPolicy::Collect(100))
+        .with_progress("stats_cine");
     let itit = try!(wd.entity_iter_iter());
-    let r = mro.run(itit);
+    let (r, report) = try!(mro.run(itit));
     println!("results: {:?}", r);
+    for error in report.errors {
+        println!("failed: {:?}: {}", error.id, error.error);
+    }
     Ok(())
 }
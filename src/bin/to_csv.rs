@@ -3,44 +3,64 @@
 extern crate wolframite;
 extern crate itertools;
 extern crate pad;
-extern crate num_cpus;
-extern crate simple_parallel;
 
 use std::fs;
-use std::io::prelude::*;
+use std::io;
 
 use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::mapred::Progress;
+use wolframite::tabular::{Format, TableWriter};
 use wolframite::wikidata::Wikidata;
-use wolframite::helpers;
+use wolframite::store::{DataStore, Stage};
 use wolframite::wikidata::EntityHelpers;
 
-fn main() { run().unwrap() }
-
-fn run() -> WikiResult<()> {
-    let date:String = helpers::latest("cap", "wikidata").unwrap().unwrap();
-    let target_root = helpers::data_dir_for("csv", "wikidata", &*date);
-    let _ = fs::remove_dir_all(target_root.clone());
-    try!(fs::create_dir_all(target_root.clone()));
-    let node_filename = format!("{}/titles.csv", target_root);
-    let tuple_filename = format!("{}/tuples.csv", target_root);
-    let mut node = fs::File::create(node_filename).unwrap();
-    let mut tuples = fs::File::create(tuple_filename).unwrap();
-    writeln!(node, "id,label").unwrap();
-    writeln!(tuples, "source,prop,dest").unwrap();
-
-    let it = Wikidata::entity_iter_for_date(&*date).unwrap();
-
-    for (i, entity) in it.enumerate() {
-        let entity = entity.unwrap();
-        let label = entity.get_a_label().unwrap_or("/no label".to_string()).replace(",","").replace("\n","");
-        writeln!(node, "{},{}", entity.get_id().unwrap(), &*label).unwrap();
-        for tuple in entity.triplets().unwrap() {
-            writeln!(tuples, "{},{},{}", tuple.0.get_id(), tuple.1.get_id(), tuple.2.get_id()).unwrap();
-        }
-        if i % 100000 == 0 {
-            println!("done {}", i);
+fn main() {
+    let matches = cli::app("to_csv",
+                           "Export a compiled Wikidata as node and tuple tables (CSV, TSV or \
+                            JSON Lines)")
+        .arg(cli::date_arg())
+        .arg(cli::format_arg())
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let date = try!(cli::date(&matches, &store, Stage::Cap, "wikidata"));
+        let format = try!(cli::format(&matches, Format::Csv));
+        run(&store, &*date, format)
+    });
+}
+
+fn run(store: &DataStore, date: &str, format: Format) -> WikiResult<()> {
+    let staging = try!(store.staging(Stage::Csv, "wikidata", date));
+    let target_root = staging.dir().to_string();
+    let node_filename = format!("{}/titles.{}", target_root, format.extension());
+    let tuple_filename = format!("{}/tuples.{}", target_root, format.extension());
+    let mut node = try!(TableWriter::new(io::BufWriter::new(try!(fs::File::create(node_filename))),
+                                         format,
+                                         &["id", "label"]));
+    let mut tuples =
+        try!(TableWriter::new(io::BufWriter::new(try!(fs::File::create(tuple_filename))),
+                              format,
+                              &["source", "prop", "dest"]));
+
+    let chunks: Vec<_> = try!(Wikidata::entity_iter_iter_for_date(store, date)).collect();
+    let progress = Progress::new("to_csv", chunks.len());
+
+    for entity_iter in chunks {
+        for entity in entity_iter {
+            let entity = try!(entity);
+            let label = entity.get_a_label().unwrap_or("/no label".to_string());
+            try!(node.write_row(&[try!(entity.get_id()), &*label]));
+            for tuple in try!(entity.triplets()) {
+                try!(tuples.write_row(&[tuple.0.get_id(), tuple.1.get_id(), tuple.2.get_id()]));
+            }
+            progress.record();
         }
-    };
-    Ok( () )
+        progress.chunk_done();
+    }
+    progress.report();
+    try!(node.flush());
+    try!(tuples.flush());
+    staging.commit()
 }
 
@@ -0,0 +1,461 @@
+extern crate wolframite;
+extern crate clap;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path;
+
+use clap::Arg;
+
+use wolframite::WikiResult;
+use wolframite::aggregate;
+use wolframite::aggregate::Count;
+use wolframite::blocks;
+use wolframite::cli;
+use wolframite::mapred;
+use wolframite::mapred::{BI, MapReduceOp, Progress};
+use wolframite::rdf;
+use wolframite::store::{DataStore, Stage};
+use wolframite::tabular::{Format, TableWriter};
+use wolframite::wikidata::{ClaimValue, EntityHelpers, EntityMessage, EntityReader, EntityType,
+                           Statement, Wikidata};
+
+/// Between the values of array columns (and node labels): a control
+/// character, as any printable one may appear in the values.
+const ARRAY_DELIMITER: char = '\u{1f}';
+const NODE_COLUMNS: &'static [&'static str] = &["qid:ID", ":LABEL", "name", "description"];
+const RELATIONSHIP_COLUMNS: &'static [&'static str] = &[":START_ID",
+                                                         ":END_ID",
+                                                         ":TYPE",
+                                                         "property",
+                                                         "rank"];
+const EARTH: &'static str = "http://www.wikidata.org/entity/Q2";
+
+fn main() {
+    let matches = cli::app("to_neo4j",
+                           "Export a compiled Wikidata as CSV files for neo4j-admin import")
+        .arg(cli::date_arg())
+        .arg(Arg::with_name("properties")
+                 .long("properties")
+                 .takes_value(true)
+                 .help("Literal-valued properties exported as node properties: the most \
+                        used ones (default: 200)"))
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let date = try!(cli::date(&matches, &store, Stage::Labels, "wikidata"));
+        let properties = try!(matches.value_of("properties")
+                                  .unwrap_or("200")
+                                  .parse()
+                                  .map_err(|_| "--properties expects a number"));
+        run(&store, &*date, properties)
+    });
+}
+
+/// Neo4j type of a literal value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    String,
+    DateTime,
+    Double,
+    Point,
+}
+
+impl Kind {
+    fn of(value: &ClaimValue) -> Option<Kind> {
+        match *value {
+            ClaimValue::Entity(_) => None,
+            ClaimValue::String(_) |
+            ClaimValue::Text { .. } => Some(Kind::String),
+            ClaimValue::Time { .. } => Some(Kind::DateTime),
+            ClaimValue::Quantity { .. } => Some(Kind::Double),
+            ClaimValue::Coordinate { .. } => Some(Kind::Point),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Kind::String => "string",
+            Kind::DateTime => "datetime",
+            Kind::Double => "double",
+            Kind::Point => "point",
+        }
+    }
+
+    /// The value as neo4j-admin reads it, None if it can not: times out
+    /// of the years 0000 to 9999, and coordinates off Earth.
+    fn format(&self, value: &ClaimValue) -> Option<String> {
+        match (*self, value) {
+            (Kind::String, &ClaimValue::String(ref s)) |
+            (Kind::String, &ClaimValue::Text { text: ref s, .. }) => {
+                Some(s.replace(ARRAY_DELIMITER, " "))
+            }
+            (Kind::DateTime, &ClaimValue::Time { ref time, .. }) => {
+                let time = rdf::normalize_time(time);
+                let year = time.split('-').next().unwrap_or("");
+                if year.len() == 4 && year.chars().all(|c| c.is_digit(10)) {
+                    Some(time)
+                } else {
+                    None
+                }
+            }
+            (Kind::Double, &ClaimValue::Quantity { amount, .. }) => Some(format!("{}", amount)),
+            (Kind::Point, &ClaimValue::Coordinate { latitude, longitude, ref globe })
+                if globe.is_empty() || globe == EARTH => {
+                Some(format!("{{latitude: {}, longitude: {}}}", latitude, longitude))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A literal-valued property, as a node property.
+struct Column {
+    property: String,
+    kind: Kind,
+    header: String,
+}
+
+fn words(label: &str) -> Vec<String> {
+    label.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_string())
+        .collect()
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// "date of birth" as dateOfBirth.
+fn camel_case(label: &str) -> String {
+    words(label)
+        .iter()
+        .enumerate()
+        .map(|(i, w)| if i == 0 { w.to_lowercase() } else { capitalize(w) })
+        .collect()
+}
+
+/// "human" as Human, "film festival" as FilmFestival.
+fn pascal_case(label: &str) -> String {
+    words(label).iter().map(|w| capitalize(w)).collect()
+}
+
+/// "instance of" as INSTANCE_OF.
+fn upper_snake_case(label: &str) -> String {
+    words(label).iter().map(|w| w.to_uppercase()).collect::<Vec<String>>().join("_")
+}
+
+/// What the survey pass notes of an entity: a literal-valued property, a
+/// property linking it to other entities, or a class it is an instance of.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Seen {
+    Literal(String, Kind),
+    Relationship(String),
+    Class(String),
+}
+
+/// A name derived from a label, or the id when there is no label or it has
+/// nothing to derive from.
+fn name_or_id(label: Option<String>, id: &str, f: fn(&str) -> String) -> String {
+    let name = label.map(|l| f(&*l)).unwrap_or(String::new());
+    if name.is_empty() { id.to_string() } else { name }
+}
+
+/// Header name of a literal column: the camel cased label, or if it is
+/// taken, suffixed with the property id, then with the kind as well.
+fn column_name(name: &str, property: &str, kind: Kind, taken: &HashSet<String>) -> String {
+    let candidates = vec![name.to_string(),
+                          format!("{}_{}", name, property),
+                          format!("{}_{}_{}", name, property, kind.name())];
+    candidates.into_iter()
+        .find(|c| !c.starts_with('_') && !c.is_empty() && !taken.contains(c))
+        .unwrap_or(format!("{}_{}", property, kind.name()))
+}
+
+/// Node labels of the P31 classes and relationship types of the entity
+/// valued properties. Computed before the export, and only read by it.
+struct Names {
+    node_labels: HashMap<String, String>,
+    relationship_types: HashMap<String, String>,
+}
+
+impl Names {
+    fn node_label<'b>(&'b self, class: &'b str) -> &'b str {
+        self.node_labels.get(class).map(|s| &**s).unwrap_or(class)
+    }
+
+    fn relationship_type<'b>(&'b self, property: &'b str) -> &'b str {
+        self.relationship_types.get(property).map(|s| &**s).unwrap_or(property)
+    }
+}
+
+/// One pass over the entities: the `count` (property, kind) pairs found in
+/// the most entities, as columns, and the names of the classes and
+/// relationships.
+fn survey(store: &DataStore,
+          date: &str,
+          wd: &Wikidata,
+          count: usize)
+          -> WikiResult<(Vec<Column>, Names)> {
+    let mro = MapReduceOp::new_map_reduce(|e: WikiResult<EntityMessage>|
+                                           -> WikiResult<BI<(Seen, Count)>> {
+        let e = try!(e);
+        let mut seen = HashSet::new();
+        for statement in try!(e.get_statements()) {
+            match statement.value.value() {
+                Some(&ClaimValue::Entity(ref target)) => {
+                    if statement.property == "P31" {
+                        seen.insert(Seen::Class(target.get_id()));
+                    }
+                    seen.insert(Seen::Relationship(statement.property.clone()));
+                }
+                Some(value) => {
+                    if let Some(kind) = Kind::of(value) {
+                        seen.insert(Seen::Literal(statement.property.clone(), kind));
+                    }
+                }
+                None => (),
+            }
+        }
+        Ok(Box::new(seen.into_iter().map(|key| (key, Count(1)))))
+    },
+                                          aggregate::merge)
+        .with_progress("to_neo4j survey");
+    let (all, _) = try!(mro.run(try!(Wikidata::entity_iter_iter_for_date(store, date))));
+    let mut literals = vec![];
+    let mut names = Names {
+        node_labels: HashMap::new(),
+        relationship_types: HashMap::new(),
+    };
+    for (seen, n) in all {
+        match seen {
+            Seen::Literal(property, kind) => literals.push(((property, kind), n)),
+            Seen::Relationship(property) => {
+                let name = name_or_id(wd.get_label(&*property), &*property, upper_snake_case);
+                names.relationship_types.insert(property, name);
+            }
+            Seen::Class(class) => {
+                let name = name_or_id(wd.get_label(&*class), &*class, pascal_case);
+                names.node_labels.insert(class, name);
+            }
+        }
+    }
+    let mut taken: HashSet<String> = ["qid", "name", "description"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let mut columns = vec![];
+    for ((property, kind), _) in aggregate::top_k(literals, count) {
+        let label = wd.get_label(&*property).map(|l| camel_case(&*l)).unwrap_or(String::new());
+        let name = column_name(&*label, &*property, kind, &taken);
+        taken.insert(name.clone());
+        columns.push(Column {
+                         header: format!("{}:{}[]", name, kind.name()),
+                         property: property,
+                         kind: kind,
+                     });
+    }
+    Ok((columns, names))
+}
+
+fn node_row(entity: &EntityMessage,
+            statements: &[Statement],
+            names: &Names,
+            columns: &[Column])
+            -> WikiResult<Vec<String>> {
+    let delimiter = ARRAY_DELIMITER.to_string();
+    let mut labels = vec![match try!(try!(entity.as_entity_reader()).get_type()) {
+                              EntityType::Item => "Item".to_string(),
+                              EntityType::Property => "Property".to_string(),
+                          }];
+    for statement in statements {
+        if let (true, Some(&ClaimValue::Entity(ref class))) =
+            (statement.property == "P31", statement.value.value()) {
+            let label = names.node_label(&*class.get_id()).to_string();
+            if !labels.contains(&label) {
+                labels.push(label);
+            }
+        }
+    }
+    let mut row = vec![try!(entity.get_id()).to_string(),
+                       labels.join(&*delimiter),
+                       try!(entity.get_a_label()),
+                       try!(entity.get_description("en")).unwrap_or(String::new())];
+    for column in columns {
+        let values: Vec<String> = statements.iter()
+            .filter(|s| s.property == column.property && s.rank != "deprecated")
+            .filter_map(|s| s.value.value().and_then(|v| column.kind.format(v)))
+            .collect();
+        row.push(values.join(&*delimiter));
+    }
+    Ok(row)
+}
+
+/// Write the nodes and relationships of a part, without headers.
+fn export_part(part: &path::Path,
+               nodes: &path::Path,
+               relationships: &path::Path,
+               names: &Names,
+               columns: &[Column],
+               progress: &Progress)
+               -> WikiResult<()> {
+    let mut node_columns = NODE_COLUMNS.to_vec();
+    node_columns.extend(columns.iter().map(|c| &*c.header));
+    let mut nodes = TableWriter::headless(io::BufWriter::new(try!(fs::File::create(nodes))),
+                                          Format::Csv,
+                                          &*node_columns);
+    let mut relationships =
+        TableWriter::headless(io::BufWriter::new(try!(fs::File::create(relationships))),
+                              Format::Csv,
+                              RELATIONSHIP_COLUMNS);
+    let split = blocks::Split {
+        part: part.to_path_buf(),
+        offset: 0,
+        length: None,
+    };
+    for entity in EntityReader::for_reader(try!(split.open())) {
+        let entity = try!(entity);
+        let statements = try!(entity.get_statements());
+        let row = try!(node_row(&entity, &*statements, names, columns));
+        try!(nodes.write_row(&*row));
+        let id = try!(entity.get_id());
+        for statement in &statements {
+            if let Some(&ClaimValue::Entity(ref target)) = statement.value.value() {
+                let row = [id.to_string(),
+                           target.get_id(),
+                           names.relationship_type(&*statement.property).to_string(),
+                           statement.property.clone(),
+                           statement.rank.to_string()];
+                try!(relationships.write_row(&row[..]));
+            }
+        }
+        progress.record();
+    }
+    try!(nodes.flush());
+    try!(relationships.flush());
+    Ok(())
+}
+
+fn run(store: &DataStore, date: &str, properties: usize) -> WikiResult<()> {
+    let wd = try!(Wikidata::for_date(store, date));
+    let (columns, names) = try!(survey(store, date, &wd, properties));
+
+    let staging = try!(store.staging(Stage::Neo4j, "wikidata", date));
+    let root = path::Path::new(staging.dir()).to_path_buf();
+    try!(fs::create_dir_all(root.join("nodes")));
+    try!(fs::create_dir_all(root.join("relationships")));
+    let mut node_header = NODE_COLUMNS.to_vec();
+    node_header.extend(columns.iter().map(|c| &*c.header));
+    try!(TableWriter::new(try!(fs::File::create(root.join("nodes/header.csv"))),
+                          Format::Csv,
+                          &*node_header));
+    try!(TableWriter::new(try!(fs::File::create(root.join("relationships/header.csv"))),
+                          Format::Csv,
+                          RELATIONSHIP_COLUMNS));
+
+    let parts: Vec<path::PathBuf> = try!(try!(Wikidata::cap_files_for_date(store, date)).collect());
+    let progress = Progress::new("to_neo4j", parts.len());
+    try!(mapred::par_try_map(parts.into_iter().enumerate().collect(),
+                             |(i, part): (usize, path::PathBuf)| {
+        try!(export_part(&*part,
+                         &*root.join(format!("nodes/part{:04}.csv", i)),
+                         &*root.join(format!("relationships/part{:04}.csv", i)),
+                         &names,
+                         &*columns,
+                         &progress));
+        progress.chunk_done();
+        Ok(())
+    }));
+    progress.report();
+
+    let command = "neo4j-admin import --nodes \"nodes/header.csv,nodes/part.*\" \
+                   --relationships \"relationships/header.csv,relationships/part.*\" \
+                   --array-delimiter U+001F --multiline-fields=true \
+                   --ignore-missing-nodes=true";
+    let mut script = try!(fs::File::create(root.join("import.sh")));
+    try!(writeln!(script, "#!/bin/sh\ncd \"$(dirname \"$0\")\"\n{}", command));
+    println!("import from {} with: {}", root.display(), command);
+    staging.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cases() {
+        assert_eq!(camel_case("date of birth"), "dateOfBirth");
+        assert_eq!(camel_case("ISBN-13"), "isbn13");
+        assert_eq!(pascal_case("film festival"), "FilmFestival");
+        assert_eq!(pascal_case("human"), "Human");
+        assert_eq!(upper_snake_case("instance of"), "INSTANCE_OF");
+        assert_eq!(upper_snake_case("père ou mère"), "PÈRE_OU_MÈRE");
+        assert_eq!(upper_snake_case("--"), "");
+        assert_eq!(name_or_id(Some("--".to_string()), "P1", upper_snake_case), "P1");
+        assert_eq!(name_or_id(None, "Q5", pascal_case), "Q5");
+    }
+
+    #[test]
+    fn column_names_do_not_collide() {
+        let mut taken: HashSet<String> = ["qid", "name", "description"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(column_name("height", "P2048", Kind::Double, &taken), "height");
+        taken.insert("height".to_string());
+        assert_eq!(column_name("height", "P2048", Kind::String, &taken), "height_P2048");
+        taken.insert("height_P2048".to_string());
+        assert_eq!(column_name("height", "P2048", Kind::DateTime, &taken),
+                   "height_P2048_datetime");
+        taken.insert("height_P2048_datetime".to_string());
+        assert_eq!(column_name("height", "P2048", Kind::Point, &taken), "P2048_point");
+        assert_eq!(column_name("name", "P2561", Kind::String, &taken), "name_P2561");
+        assert_eq!(column_name("", "P9999", Kind::String, &taken), "P9999_string");
+    }
+
+    fn time(time: &str) -> ClaimValue {
+        ClaimValue::Time {
+            time: time.to_string(),
+            precision: 11,
+            calendar: String::new(),
+        }
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(Kind::DateTime.format(&time("+1952-03-11T00:00:00Z")),
+                   Some("1952-03-11T00:00:00Z".to_string()));
+        assert_eq!(Kind::DateTime.format(&time("+1952-00-00T00:00:00Z")),
+                   Some("1952-01-01T00:00:00Z".to_string()));
+        assert_eq!(Kind::DateTime.format(&time("-0500-01-01T00:00:00Z")), None);
+        assert_eq!(Kind::DateTime.format(&time("+13798000000-00-00T00:00:00Z")), None);
+        let point = |globe: &str| {
+            ClaimValue::Coordinate {
+                latitude: 51.5,
+                longitude: -0.125,
+                globe: globe.to_string(),
+            }
+        };
+        assert_eq!(Kind::Point.format(&point(EARTH)),
+                   Some("{latitude: 51.5, longitude: -0.125}".to_string()));
+        assert_eq!(Kind::Point.format(&point("")),
+                   Some("{latitude: 51.5, longitude: -0.125}".to_string()));
+        assert_eq!(Kind::Point.format(&point("http://www.wikidata.org/entity/Q111")),
+                   None);
+        assert_eq!(Kind::String.format(&ClaimValue::String("a\u{1f}b".to_string())),
+                   Some("a b".to_string()));
+        assert_eq!(Kind::Double.format(&ClaimValue::Quantity {
+                                           amount: 1.96,
+                                           unit: "1".to_string(),
+                                       }),
+                   Some("1.96".to_string()));
+        assert_eq!(Kind::Double.format(&time("+1952-03-11T00:00:00Z")), None);
+    }
+}
@@ -0,0 +1,43 @@
+extern crate wolframite;
+
+use std::fs;
+use std::path;
+
+use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::mapred;
+use wolframite::mapred::Progress;
+use wolframite::wikidata::Wikidata;
+use wolframite::wikidata_parquet;
+use wolframite::store::{DataStore, Stage};
+
+fn main() {
+    let matches = cli::app("to_parquet",
+                           "Export a compiled Wikidata as Parquet entities and statements")
+        .arg(cli::date_arg())
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let date = try!(cli::date(&matches, &store, Stage::Cap, "wikidata"));
+        run(&store, &*date)
+    });
+}
+
+fn run(store: &DataStore, date: &str) -> WikiResult<()> {
+    let staging = try!(store.staging(Stage::Parquet, "wikidata", date));
+    let entities = path::Path::new(staging.dir()).join("entities");
+    let statements = path::Path::new(staging.dir()).join("statements");
+    try!(fs::create_dir_all(&entities));
+    try!(fs::create_dir_all(&statements));
+
+    let parts: Vec<path::PathBuf> = try!(try!(Wikidata::cap_files_for_date(store, date)).collect());
+    let progress = Progress::new("to_parquet", parts.len());
+    let counts = try!(mapred::par_try_map(parts, |part| {
+        let count = try!(wikidata_parquet::export_part(&*part, &*entities, &*statements));
+        progress.chunk_done();
+        progress.report();
+        Ok(count)
+    }));
+    println!("{} entities exported", counts.iter().sum::<usize>());
+    staging.commit()
+}
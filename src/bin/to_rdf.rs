@@ -0,0 +1,151 @@
+extern crate wolframite;
+extern crate clap;
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io;
+use std::path;
+use std::sync::Mutex;
+
+use clap::{Arg, ArgMatches};
+
+use wolframite::{BoxedIter, WikiResult};
+use wolframite::cli;
+use wolframite::mapred;
+use wolframite::mapred::Progress;
+use wolframite::rdf;
+use wolframite::rdf::{EntityFilter, Mode, Syntax};
+use wolframite::store::{DataStore, Stage};
+use wolframite::wikidata::{EntityMessage, Wikidata};
+
+/// Triples of a chunk are written to the output by batches of this size.
+const BATCH_BYTES: usize = 1 << 20;
+
+fn main() {
+    let matches = cli::app("to_rdf",
+                           "Export a compiled Wikidata, or a subset of it, as N-Triples or \
+                            Turtle on stdout")
+        .arg(cli::date_arg())
+        .arg(Arg::with_name("syntax")
+                 .short("s")
+                 .long("syntax")
+                 .takes_value(true)
+                 .possible_values(&["nt", "ttl"])
+                 .help("N-Triples (the default) or Turtle"))
+        .arg(Arg::with_name("full")
+                 .long("full")
+                 .help("Reified statements with ranks and qualifiers, not only the truthy \
+                        wdt: triples"))
+        .arg(Arg::with_name("ids")
+                 .long("ids")
+                 .takes_value(true)
+                 .help("Only the entities listed (comma separated, or @file with one id \
+                        per line), read from the parts holding them according to the id \
+                        lookup table of wikidata_cdb"))
+        .arg(Arg::with_name("claim")
+                 .long("claim")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .help("Only the entities with this claim: P18, or P31=Q5 (repeatable, \
+                        all must match)"))
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let date = try!(cli::date(&matches, &store, Stage::Cap, "wikidata"));
+        let filter = try!(filter(&matches));
+        let syntax = match matches.value_of("syntax") {
+            Some("ttl") => Syntax::Turtle,
+            _ => Syntax::NTriples,
+        };
+        let mode = if matches.is_present("full") {
+            Mode::Full
+        } else {
+            Mode::Truthy
+        };
+        run(&store, &*date, &filter, syntax, mode)
+    });
+}
+
+fn filter(matches: &ArgMatches) -> WikiResult<EntityFilter> {
+    let mut filter = EntityFilter::new();
+    if let Some(ids) = matches.value_of("ids") {
+        let ids = if ids.starts_with('@') {
+            try!(rdf::read_ids(io::BufReader::new(try!(fs::File::open(&ids[1..])))))
+        } else {
+            ids.split(',').map(|id| id.trim().to_string()).filter(|id| !id.is_empty()).collect()
+        };
+        filter = filter.ids(ids);
+    }
+    if let Some(claims) = matches.values_of("claim") {
+        for claim in claims {
+            filter = try!(filter.claim(claim));
+        }
+    }
+    Ok(filter)
+}
+
+/// Entities of the parts holding the listed ids, found with the id lookup
+/// table. A part is only opened when its turn comes.
+fn listed_entities(store: &DataStore,
+                   date: &str,
+                   ids: &HashSet<String>)
+                   -> WikiResult<Vec<BoxedIter<WikiResult<EntityMessage>>>> {
+    let wd = try!(Wikidata::for_date(store, date));
+    let mut parts = BTreeSet::new();
+    for id in ids {
+        if let Some(part) = try!(wd.part_of(id)) {
+            parts.insert(part);
+        }
+    }
+    let dir = path::PathBuf::from(store.dir(Stage::Cap, "wikidata", date));
+    let mut chunks = vec![];
+    for part in parts {
+        let entities = Some(dir.join(part)).into_iter().flat_map(|file| {
+            let entities: BoxedIter<WikiResult<EntityMessage>> =
+                match Wikidata::entity_iter_for_file(file) {
+                    Ok(entities) => entities,
+                    Err(e) => Box::new(Some(Err(e)).into_iter()),
+                };
+            entities
+        });
+        chunks.push(Box::new(entities) as BoxedIter<WikiResult<EntityMessage>>);
+    }
+    Ok(chunks)
+}
+
+fn run(store: &DataStore,
+       date: &str,
+       filter: &EntityFilter,
+       syntax: Syntax,
+       mode: Mode)
+       -> WikiResult<()> {
+    let chunks: Vec<BoxedIter<WikiResult<EntityMessage>>> = match filter.listed_ids() {
+        Some(ids) => try!(listed_entities(store, date, ids)),
+        None => try!(Wikidata::entity_iter_iter_for_date(store, date)).collect(),
+    };
+    let out = Mutex::new(try!(rdf::stdout_writer(syntax, mode)));
+    let progress = Progress::new("to_rdf", chunks.len());
+    try!(mapred::par_try_map(chunks, |entities: BoxedIter<WikiResult<EntityMessage>>| {
+        // triples are formatted outside of the lock, and written by batches
+        let mut buffer = String::new();
+        for e in entities {
+            let e = try!(e);
+            if try!(filter.matches(&e)) {
+                buffer.push_str(&*rdf::format_triples(&*try!(rdf::entity_triples(&e, mode)),
+                                                      syntax));
+                if buffer.len() >= BATCH_BYTES {
+                    try!(out.lock().unwrap().write_chunk(&*buffer));
+                    buffer.clear();
+                }
+            }
+            progress.record();
+        }
+        try!(out.lock().unwrap().write_chunk(&*buffer));
+        progress.chunk_done();
+        Ok(())
+    }));
+    progress.report();
+    let mut out = out.into_inner().unwrap();
+    out.flush()
+}
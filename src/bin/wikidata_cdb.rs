@@ -1,42 +1,36 @@
 extern crate wolframite;
-extern crate tinycdb;
 
-use std::fs;
 use std::path;
 
-use tinycdb::Cdb;
-
-use wolframite::WikiError;
-use wolframite::helpers;
-use wolframite::wikidata::EntityHelpers;
+use wolframite::WikiResult;
+use wolframite::cli;
+use wolframite::capitanize_wikidata;
+use wolframite::mapred::Progress;
+use wolframite::store::{DataStore, Stage};
 use wolframite::wikidata::Wikidata;
 
-pub type WikiResult<T> = Result<T, WikiError>;
-
 fn main() {
-    run().unwrap();
+    let matches = cli::app("wikidata_cdb",
+                           "Build the label and id lookup tables of a compiled Wikidata")
+        .arg(cli::date_arg())
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let date = try!(cli::date(&matches, &store, Stage::Cap, "wikidata"));
+        run(&store, &*date)
+    });
 }
 
-fn run() -> WikiResult<()> {
-    let date: String = helpers::latest("cap", "wikidata").unwrap().unwrap();
-    let target_root = helpers::data_dir_for("labels", "wikidata", &*date);
-    try!(fs::create_dir_all(target_root.clone()));
-    let filename = target_root.clone() + "/labels";
-    let _ = fs::remove_file(target_root.clone() + "/labels");
-    Cdb::new(path::Path::new(&*filename), |creator| {
-        let it = Wikidata::entity_iter_for_date(&*date).unwrap();
-        for (i, message) in it.enumerate() {
-            let message = message.unwrap();
-            let label = message.get_a_label().unwrap();
-            creator
-                .add(&*message.get_id().unwrap().as_bytes(), label.as_bytes())
-                .unwrap();
-            if i % 100000 == 0 {
-                println!("done {}", i);
-            }
-        }
-    })
-            .unwrap();
-    let _ = fs::File::create(format!("{}/ok", target_root));
-    Ok(())
+fn run(store: &DataStore, date: &str) -> WikiResult<()> {
+    let staging = try!(store.staging(Stage::Labels, "wikidata", date));
+    let parts: Vec<path::PathBuf> =
+        try!(try!(Wikidata::cap_files_for_date(store, date)).collect());
+    let progress = Progress::new("wikidata_cdb", parts.len());
+    let entities = capitanize_wikidata::index_parts(parts).map(|entity| {
+                                                                   progress.record();
+                                                                   entity
+                                                               });
+    try!(capitanize_wikidata::write_indexes(path::Path::new(staging.dir()), entities));
+    progress.report();
+    staging.commit()
 }
@@ -0,0 +1,50 @@
+extern crate wolframite;
+extern crate clap;
+
+use std::path;
+
+use clap::Arg;
+
+use wolframite::cli;
+use wolframite::store::Stage;
+use wolframite::wikidata_incr;
+
+fn main() {
+    let matches = cli::app("wikidata_incr",
+                           "Compile a new Wikidata date from a compiled one and updates")
+        .arg(Arg::with_name("base")
+                 .short("b")
+                 .long("base")
+                 .takes_value(true)
+                 .help("Compiled date to update (default: latest with labels)"))
+        .arg(cli::date_arg()
+                 .required(true)
+                 .help("Date of the updated compilation (YYYYMMDD)"))
+        .arg(Arg::with_name("updates")
+                 .required(true)
+                 .multiple(true)
+                 .help("Update files, oldest first: JSON entity lines (.json[.gz]) or \
+                        incr XML dumps (.xml.bz2)"))
+        .get_matches();
+    cli::run(|| {
+        let store = try!(cli::setup(&matches));
+        let base = match matches.value_of("base") {
+            Some(base) => base.to_string(),
+            None => try!(store.latest_or_err(Stage::Labels, "wikidata")),
+        };
+        let date = matches.value_of("date").unwrap();
+        let updates: Vec<path::PathBuf> =
+            matches.values_of("updates").unwrap().map(path::PathBuf::from).collect();
+        let report = try!(wikidata_incr::apply(&store, &*base, date, &*updates));
+        println!("{} from {}: {} parts reused, {} rewritten; {} entities replaced, {} added, \
+                  {} deleted",
+                 date,
+                 base,
+                 report.reused,
+                 report.rewritten,
+                 report.replaced,
+                 report.added,
+                 report.deleted);
+        Ok(())
+    });
+}
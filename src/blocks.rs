@@ -0,0 +1,353 @@
+//! Block-compressed part files: messages are compressed by blocks of about
+//! `BLOCK_SIZE` bytes, each block being a complete gzip member (or snappy
+//! framed stream), so the file still reads as a whole, and any run of
+//! blocks reads on its own. Blocks are listed in a `{part}.idx` sidecar,
+//! one "offset length records" line per block.
+//!
+//! Scans use the index to cut parts into splits of about `SPLIT_BYTES`
+//! compressed bytes, whatever the size of the parts. Parts compiled before
+//! there was an index make one split each.
+
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path;
+
+use flate2;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use snappy_framed::read::SnappyFramedDecoder;
+use snappy_framed::read::CrcMode::Ignore;
+use snappy_framed::write::SnappyFramedEncoder;
+
+use capnp::message::{Allocator, Builder};
+use capnp::serialize_packed;
+
+use {BoxedIter, WikiResult};
+
+/// Uncompressed size of a block.
+pub const BLOCK_SIZE: usize = 4 << 20;
+/// Compressed size of a split.
+pub const SPLIT_BYTES: u64 = 32 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Snappy,
+}
+
+impl Codec {
+    fn of(part: &path::Path) -> WikiResult<Codec> {
+        match part.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Ok(Codec::Gzip),
+            Some("snap") => Ok(Codec::Snappy),
+            _ => Err(format!("unknown compression for {:?}", part))?,
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> WikiResult<Vec<u8>> {
+        match *self {
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], flate2::Compression::Default);
+                try!(encoder.write_all(data));
+                Ok(try!(encoder.finish()))
+            }
+            Codec::Snappy => {
+                let mut compressed = vec![];
+                {
+                    let mut encoder = try!(SnappyFramedEncoder::new(&mut compressed));
+                    try!(encoder.write_all(data));
+                    try!(encoder.flush());
+                }
+                Ok(compressed)
+            }
+        }
+    }
+}
+
+/// The index of a part file.
+pub fn index_path(part: &path::Path) -> path::PathBuf {
+    let mut name = part.as_os_str().to_owned();
+    name.push(".idx");
+    path::PathBuf::from(name)
+}
+
+/// Writes capnp messages to a block-compressed part and its index.
+pub struct BlockWriter {
+    codec: Codec,
+    out: fs::File,
+    index: io::BufWriter<fs::File>,
+    block: Vec<u8>,
+    records: u64,
+    offset: u64,
+    finished: bool,
+}
+
+impl BlockWriter {
+    /// Create a part, compressed according to its extension (.gz or .snap).
+    pub fn create(part: &path::Path) -> WikiResult<BlockWriter> {
+        Ok(BlockWriter {
+               codec: try!(Codec::of(part)),
+               out: try!(fs::File::create(part)),
+               index: io::BufWriter::new(try!(fs::File::create(index_path(part)))),
+               block: Vec::with_capacity(BLOCK_SIZE + BLOCK_SIZE / 4),
+               records: 0,
+               offset: 0,
+               finished: false,
+           })
+    }
+
+    pub fn write_message<A: Allocator>(&mut self, message: &mut Builder<A>) -> WikiResult<()> {
+        try!(serialize_packed::write_message(&mut self.block, message));
+        self.records += 1;
+        if self.block.len() >= BLOCK_SIZE {
+            try!(self.flush_block());
+        }
+        Ok(())
+    }
+
+    /// Offset in the part of the pending block, the one the next message
+    /// goes to: reading a split from there gets that message.
+    pub fn block_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Bytes written so far, counting the pending block uncompressed.
+    pub fn len(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn flush_block(&mut self) -> WikiResult<()> {
+        if self.records == 0 {
+            return Ok(());
+        }
+        let compressed = try!(self.codec.compress(&*self.block));
+        try!(self.out.write_all(&*compressed));
+        try!(writeln!(self.index, "{} {} {}", self.offset, compressed.len(), self.records));
+        self.offset += compressed.len() as u64;
+        self.block.clear();
+        self.records = 0;
+        Ok(())
+    }
+
+    /// Write the pending block. Dropping the writer does it too, but
+    /// ignores errors.
+    pub fn finish(&mut self) -> WikiResult<()> {
+        if !self.finished {
+            try!(self.flush_block());
+            try!(self.out.flush());
+            try!(self.index.flush());
+            self.finished = true;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BlockWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    pub offset: u64,
+    pub length: u64,
+    pub records: u64,
+}
+
+/// Blocks of a part, None if it has no index.
+pub fn read_index(part: &path::Path) -> WikiResult<Option<Vec<Block>>> {
+    let index = index_path(part);
+    if !index.exists() {
+        return Ok(None);
+    }
+    let mut blocks = vec![];
+    for line in io::BufReader::new(try!(fs::File::open(&index))).lines() {
+        let line = try!(line);
+        let fields: Vec<u64> = try!(line.split_whitespace()
+                                        .map(|f| f.parse())
+                                        .collect::<Result<Vec<u64>, _>>()
+                                        .map_err(|e| format!("{:?}: {}", index, e)));
+        if fields.len() != 3 {
+            Err(format!("{:?}: bad index line {:?}", index, line))?
+        }
+        blocks.push(Block {
+                        offset: fields[0],
+                        length: fields[1],
+                        records: fields[2],
+                    });
+    }
+    Ok(Some(blocks))
+}
+
+/// A run of consecutive blocks of a part, or the whole part.
+#[derive(Debug, Clone)]
+pub struct Split {
+    pub part: path::PathBuf,
+    pub offset: u64,
+    /// None for the whole part
+    pub length: Option<u64>,
+}
+
+impl Split {
+    /// Decompressed content of the split.
+    pub fn open(&self) -> WikiResult<Box<Read + Send>> {
+        let mut file = try!(fs::File::open(&self.part));
+        try!(file.seek(io::SeekFrom::Start(self.offset)));
+        let raw: Box<Read + Send> = match self.length {
+            Some(length) => Box::new(file.take(length)),
+            None => Box::new(file),
+        };
+        match try!(Codec::of(&*self.part)) {
+            Codec::Gzip => Ok(Box::new(try!(MultiGzDecoder::new(raw)))),
+            Codec::Snappy => Ok(Box::new(SnappyFramedDecoder::new(raw, Ignore))),
+        }
+    }
+}
+
+/// Cut parts into splits of about `target` compressed bytes. Splits never
+/// span parts.
+pub fn splits(parts: Vec<path::PathBuf>, target: u64) -> WikiResult<Vec<Split>> {
+    let mut splits = vec![];
+    for part in parts {
+        let blocks = match try!(read_index(&*part)) {
+            Some(blocks) => blocks,
+            None => {
+                splits.push(Split {
+                                part: part,
+                                offset: 0,
+                                length: None,
+                            });
+                continue;
+            }
+        };
+        let mut begin: Option<u64> = None;
+        let mut end = 0;
+        for block in blocks {
+            let first = *begin.get_or_insert(block.offset);
+            end = block.offset + block.length;
+            if end - first >= target {
+                splits.push(Split {
+                                part: part.clone(),
+                                offset: first,
+                                length: Some(end - first),
+                            });
+                begin = None;
+            }
+        }
+        if let Some(first) = begin {
+            splits.push(Split {
+                            part: part.clone(),
+                            offset: first,
+                            length: Some(end - first),
+                        });
+        }
+    }
+    Ok(splits)
+}
+
+/// Records of each split, read with `reader`. Splits are opened on first
+/// use, so handing out many of them does not hold a file each.
+pub fn split_iters<T, I>(splits: Vec<Split>,
+                         reader: fn(Box<Read + Send>) -> I)
+                         -> Vec<BoxedIter<WikiResult<T>>>
+    where T: Send + 'static,
+          I: Iterator<Item = WikiResult<T>> + Send + 'static
+{
+    splits.into_iter()
+        .map(|split| {
+            let records = Some(split).into_iter().flat_map(move |split| {
+                let records: BoxedIter<WikiResult<T>> = match split.open() {
+                    Ok(input) => Box::new(reader(input)),
+                    Err(e) => Box::new(Some(Err(e)).into_iter()),
+                };
+                records
+            });
+            Box::new(records) as BoxedIter<WikiResult<T>>
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path;
+    use std::process;
+
+    use super::*;
+    use wiki::{Page, PagesReader};
+
+    fn dir(test: &str) -> path::PathBuf {
+        let dir = env::temp_dir().join(format!("wolframite-blocks-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write pages `ids` with 4kB of text that does not compress too well.
+    fn write_part(part: &path::Path, ids: ::std::ops::Range<u64>) {
+        let mut out = BlockWriter::create(part).unwrap();
+        let mut seed = 42u64;
+        for id in ids {
+            let text: String = (0..4096)
+                .map(|_| {
+                         seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                         (b'a' + (seed >> 59) as u8) as char
+                     })
+                .collect();
+            let mut message = Builder::new_default();
+            {
+                let mut page = message.init_root::<Page::Builder>();
+                page.set_id(id);
+                page.set_title(&*format!("Page {}", id));
+                page.set_text(&*text);
+            }
+            out.write_message(&mut message).unwrap();
+        }
+        out.finish().unwrap();
+    }
+
+    fn read_ids(splits: Vec<Split>) -> Vec<u64> {
+        split_iters(splits, PagesReader::new)
+            .into_iter()
+            .flat_map(|pages| pages)
+            .map(|page| page.unwrap().as_page_reader().unwrap().get_id())
+            .collect()
+    }
+
+    #[test]
+    fn splits_read_every_message_once() {
+        let dir = dir("splits_read_every_message_once");
+        for ext in &["gz", "snap"] {
+            let big = dir.join(format!("big.cap.{}", ext));
+            let small = dir.join(format!("small.cap.{}", ext));
+            write_part(&big, 0..3000);
+            write_part(&small, 3000..3003);
+            let blocks = read_index(&big).unwrap().unwrap();
+            assert!(blocks.len() >= 3, "{} blocks", blocks.len());
+            assert_eq!(blocks.iter().map(|b| b.records).sum::<u64>(), 3000);
+            assert_eq!(read_index(&small).unwrap().unwrap().len(), 1);
+            let expected: Vec<u64> = (0..3003).collect();
+
+            // a split per block
+            let by_block = splits(vec![big.clone(), small.clone()], 1).unwrap();
+            assert_eq!(by_block.len(), blocks.len() + 1);
+            assert_eq!(read_ids(by_block), expected);
+
+            // a split per part: the small part is smaller than a split
+            let by_part = splits(vec![big.clone(), small.clone()], SPLIT_BYTES).unwrap();
+            assert_eq!(by_part.len(), 2);
+            assert_eq!(read_ids(by_part), expected);
+
+            // without its index, a part is a split of its own
+            fs::remove_file(index_path(&big)).unwrap();
+            let unindexed = splits(vec![big.clone(), small.clone()], 1).unwrap();
+            assert_eq!(unindexed.len(), 2);
+            assert_eq!(unindexed[0].length, None);
+            assert_eq!(read_ids(unindexed), expected);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
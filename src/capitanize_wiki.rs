@@ -1,60 +1,327 @@
 use xml::reader::{EventReader, Events, XmlEvent};
 
 use std::{io, fs, path};
-
-use snappy_framed::write::SnappyFramedEncoder;
+use std::io::prelude::*;
+use std::collections::HashMap;
 
 use capnp::serialize_packed;
-use capnp::message::Builder;
+use capnp::message::{Allocator, Builder, HeapAllocator};
+
+use tinycdb::Cdb;
 
-use WikiResult;
+use {WikiResult, WikiError};
+use blocks::BlockWriter;
 
 pub use capn_wiki::wiki_capnp::page as Page;
+pub use capn_wiki::wiki_capnp::site_info as SiteInfo;
+pub use capn_wiki::wiki_capnp::revision as Revision;
+pub use capn_wiki::wiki_capnp::contributor as Contributor;
+pub use capn_wiki::wiki_capnp::Case;
 
-pub fn process<R: io::Read>(input: R, output: &path::Path) -> WikiResult<()> {
+/// Rolls compiled output over block-compressed part files of roughly 250MB.
+struct Parts {
+    output: String,
+    extension: &'static str,
+    part_counter: usize,
+    part_name: String,
+    part: Option<BlockWriter>,
+}
+
+impl Parts {
+    fn new(output: &path::Path, extension: &'static str) -> Parts {
+        Parts {
+            output: output.to_str().unwrap().to_string(),
+            extension: extension,
+            part_counter: 0,
+            part_name: String::new(),
+            part: None,
+        }
+    }
+
+    /// Write a message, returning the name of the part file and the offset
+    /// of the block it went to.
+    fn write<A: Allocator>(&mut self, message: &mut Builder<A>) -> WikiResult<(String, u64)> {
+        if self.part.as_ref().map(|p| p.len() > 250_000_000).unwrap_or(true) {
+            try!(self.finish());
+            let path = path::PathBuf::from(format!("{}-part-{:05}.{}",
+                                                   self.output,
+                                                   self.part_counter,
+                                                   self.extension));
+            self.part_counter += 1;
+            self.part_name = path.file_name().unwrap().to_str().unwrap().to_string();
+            self.part = Some(try!(BlockWriter::create(&*path)));
+        }
+        let part = self.part.as_mut().unwrap();
+        let offset = part.block_offset();
+        try!(part.write_message(message));
+        Ok((self.part_name.clone(), offset))
+    }
+
+    fn finish(&mut self) -> WikiResult<()> {
+        if let Some(mut part) = self.part.take() {
+            try!(part.finish());
+        }
+        Ok(())
+    }
+}
+
+/// Content model occurences, by raw model name.
+pub type ModelCounts = HashMap<String, u64>;
+
+pub fn process<R: io::Read>(input: R, output: &path::Path) -> WikiResult<ModelCounts> {
     let mut parser = EventReader::new(input).into_iter();
-    let mut part_counter = 0;
-    let mut counter = 0u64;
-    let mut path = path::PathBuf::new();
-    let mut part: Option<SnappyFramedEncoder<_>> = None; //open_one(counter);
+    let mut parts = Parts::new(output, "cap.snap");
+    let mut models = ModelCounts::new();
     while let Some(ref e) = parser.next() {
         match e {
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "siteinfo" => {
+                try!(write_siteinfo(&mut parser, output));
+            }
             &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "page" => {
-                if part.is_none() ||
-                   (counter % 1000 == 0 && try!(fs::metadata(&path)).len() > 250_000_000) {
-                    path = path::PathBuf::from(format!("{}-part-{:05}.cap.snap",
-                                                       output.to_str().unwrap(),
-                                                       part_counter));
-                    part_counter += 1;
-                    part = Some(SnappyFramedEncoder::new(fs::File::create(path.as_os_str())
-                                                             .unwrap())
-                                        .unwrap());
-                }
                 let mut message = Builder::new_default();
                 {
                     let mut page = message.init_root::<Page::Builder>();
-                    try!(consume_page(&mut parser, &mut page));
+                    try!(consume_page(&mut parser, &mut page, &mut models));
                 }
-                counter += 1;
-                try!(serialize_packed::write_message(&mut part.as_mut().unwrap(), &mut message));
+                try!(parts.write(&mut message));
             }
             _ => (),
         }
     }
+    try!(parts.finish());
+    Ok(models)
+}
+
+/// Compile the page named `title` from an XML document, skipping the others.
+pub fn find_page<R: io::Read>(input: R, title: &str) -> WikiResult<Option<Builder<HeapAllocator>>> {
+    let mut parser = EventReader::new(input).into_iter();
+    let mut models = ModelCounts::new();
+    while let Some(ref e) = parser.next() {
+        match e {
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "page" => {
+                let mut message = Builder::new_default();
+                let found = {
+                    let mut page = message.init_root::<Page::Builder>();
+                    try!(consume_page(&mut parser, &mut page, &mut models));
+                    try!(page.as_reader().get_title()) == title
+                };
+                if found {
+                    return Ok(Some(message));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(None)
+}
+
+/// Compile a full-history (pages-meta-history) dump. Every revision is
+/// written as a Revision message of its own, carrying its page id and
+/// full text, in dump order (pages are contiguous, revisions chronological).
+/// Where each page starts is listed in a `{output}.pages` file, for
+/// `write_history_index`.
+pub fn process_history<R: io::Read>(input: R, output: &path::Path) -> WikiResult<ModelCounts> {
+    let mut parser = EventReader::new(input).into_iter();
+    let mut parts = Parts::new(output, "hist.snap");
+    let mut pages = io::BufWriter::new(try!(fs::File::create(format!("{}.pages",
+                                                                     output.display()))));
+    let mut models = ModelCounts::new();
+    while let Some(ref e) = parser.next() {
+        match e {
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "siteinfo" => {
+                try!(write_siteinfo(&mut parser, output));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "page" => {
+                try!(consume_page_history(&mut parser, &mut parts, &mut pages, &mut models));
+            }
+            _ => (),
+        }
+    }
+    try!(parts.finish());
+    try!(pages.flush());
+    Ok(models)
+}
+
+/// Gather the `.pages` lists left by `process_history` in `target` into a
+/// `pages` lookup table (page id to "part offset"), and remove them.
+pub fn write_history_index(target: &path::Path) -> WikiResult<()> {
+    let lists: Vec<path::PathBuf> = try!(try!(::glob::glob(&*format!("{}/*.pages",
+                                                                     target.display())))
+                                             .collect());
+    let mut result: WikiResult<()> = Ok(());
+    try!(Cdb::new(&*target.join("pages"), |pages| for list in &lists {
+        let added = fs::File::open(list).map_err(WikiError::from).and_then(|file| {
+            for line in io::BufReader::new(file).lines() {
+                let line = try!(line);
+                let mut fields = line.splitn(2, ' ');
+                let id = fields.next().unwrap_or("");
+                let location = try!(fields.next()
+                                        .ok_or(format!("{:?}: bad line {:?}", list, line)));
+                try!(pages.add(id.as_bytes(), location.as_bytes())
+                         .map_err(|e| format!("Cdb Error: {:?}", e)));
+            }
+            Ok(())
+        });
+        if let Err(e) = added {
+            result = Err(e);
+            return;
+        }
+    })
+                 .map_err(|e| format!("Cdb Error: {:?}", e)));
+    try!(result);
+    for list in &lists {
+        try!(fs::remove_file(list));
+    }
     Ok(())
 }
 
-fn consume_page<R: io::Read>(events: &mut Events<R>, page: &mut Page::Builder) -> io::Result<()> {
+/// Merge per-file model counts and write them as a `models` report (tab
+/// separated, most frequent first) in the compiled directory.
+pub fn write_model_report(counts: &[ModelCounts], target: &path::Path) -> WikiResult<ModelCounts> {
+    let mut total = ModelCounts::new();
+    for c in counts {
+        for (model, count) in c {
+            *total.entry(model.clone()).or_insert(0) += *count;
+        }
+    }
+    let mut sorted: Vec<(&String, &u64)> = total.iter().collect();
+    sorted.sort_by(|a, b| b.1.cmp(a.1));
+    let mut report = try!(fs::File::create(target.join("models")));
+    for &(model, count) in &sorted {
+        try!(writeln!(report, "{}\t{}", model, count));
+    }
+    Ok(total)
+}
+
+fn write_siteinfo<R: io::Read>(events: &mut Events<R>, output: &path::Path) -> WikiResult<()> {
+    let mut message = Builder::new_default();
+    {
+        let mut siteinfo = message.init_root::<SiteInfo::Builder>();
+        try!(consume_siteinfo(events, &mut siteinfo));
+    }
+    let mut file = try!(fs::File::create(format!("{}-siteinfo.cap", output.to_str().unwrap())));
+    try!(serialize_packed::write_message(&mut file, &mut message));
+    Ok(())
+}
+
+fn consume_siteinfo<R: io::Read>(events: &mut Events<R>,
+                                 siteinfo: &mut SiteInfo::Builder)
+                                 -> io::Result<()> {
+    let mut namespaces: Vec<(i16, Case, String)> = vec![];
+    while let Some(ref e) = events.next() {
+        match e {
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "sitename" => {
+                siteinfo.set_sitename(&*try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "dbname" => {
+                siteinfo.set_dbname(&*try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "base" => {
+                siteinfo.set_base(&*try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "generator" => {
+                siteinfo.set_generator(&*try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "case" => {
+                siteinfo.set_case(try!(parse_case(&*try!(consume_string(events)))));
+            }
+            &Ok(XmlEvent::StartElement { ref name, ref attributes, .. })
+                if name.local_name == "namespace" => {
+                let key = try!(attributes
+                                   .iter()
+                                   .find(|a| a.name.local_name == "key")
+                                   .and_then(|a| a.value.parse().ok())
+                                   .ok_or(io::Error::new(io::ErrorKind::Other,
+                                                         "can not parse int (namespace key)")));
+                let case = match attributes.iter().find(|a| a.name.local_name == "case") {
+                    Some(a) => try!(parse_case(&*a.value)),
+                    None => Case::FirstLetter,
+                };
+                namespaces.push((key, case, try!(consume_string(events))));
+            }
+            &Ok(XmlEvent::EndElement { ref name, .. }) if name.local_name == "siteinfo" => {
+                let mut list = siteinfo.borrow().init_namespaces(namespaces.len() as u32);
+                for (i, &(id, case, ref local)) in namespaces.iter().enumerate() {
+                    let mut ns = list.borrow().get(i as u32);
+                    ns.set_id(id);
+                    ns.set_case(case);
+                    ns.set_name(&*local);
+                    ns.set_canonical(canonical_namespace_name(id).unwrap_or(&*local));
+                }
+                return Ok(());
+            }
+            _ => (),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::Other, "eof?"))
+}
+
+fn parse_case(case: &str) -> io::Result<Case> {
+    match case {
+        "first-letter" => Ok(Case::FirstLetter),
+        "case-sensitive" => Ok(Case::CaseSensitive),
+        c => Err(io::Error::new(io::ErrorKind::Other, "invalid case : ".to_string() + c)),
+    }
+}
+
+/// MediaWiki canonical (english) names of the built-in namespaces. Dumps
+/// only carry the localized names, these are the ones that work everywhere.
+pub fn canonical_namespace_name(id: i16) -> Option<&'static str> {
+    match id {
+        -2 => Some("Media"),
+        -1 => Some("Special"),
+        0 => Some(""),
+        1 => Some("Talk"),
+        2 => Some("User"),
+        3 => Some("User talk"),
+        4 => Some("Project"),
+        5 => Some("Project talk"),
+        6 => Some("File"),
+        7 => Some("File talk"),
+        8 => Some("MediaWiki"),
+        9 => Some("MediaWiki talk"),
+        10 => Some("Template"),
+        11 => Some("Template talk"),
+        12 => Some("Help"),
+        13 => Some("Help talk"),
+        14 => Some("Category"),
+        15 => Some("Category talk"),
+        828 => Some("Module"),
+        829 => Some("Module talk"),
+        _ => None,
+    }
+}
+
+fn consume_page<R: io::Read>(events: &mut Events<R>,
+                             page: &mut Page::Builder,
+                             models: &mut ModelCounts)
+                             -> io::Result<()> {
+    // <redirect> comes before the revision text, and both share a union:
+    // set whichever applies at the end of the page
+    let mut redirect = None;
+    let mut text = None;
     while let Some(ref e) = events.next() {
         match e {
             &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "title" => {
                 page.set_title(&*try!(consume_string(events)));
             }
             &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "revision" => {
-                try!(consume_revision(events, page));
+                let (model, revision_text) = {
+                    let mut revision = page.borrow().init_revision();
+                    try!(consume_revision(events, &mut revision))
+                };
+                if let Some(model) = model {
+                    page.set_model(parse_model(&*model));
+                    page.set_model_name(&*model);
+                    *models.entry(model).or_insert(0) += 1;
+                }
+                text = revision_text;
             }
-            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "redirect" => {
-                page.set_redirect(&*try!(consume_string(events)));
+            &Ok(XmlEvent::StartElement { ref name, ref attributes, .. })
+                if name.local_name == "redirect" => {
+                redirect = attributes.iter()
+                    .find(|a| a.name.local_name == "title")
+                    .map(|a| a.value.clone());
             }
             &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "id" => {
                 page.set_id(try!(consume_string(events).and_then(|s| {
@@ -71,7 +338,12 @@ fn consume_page<R: io::Read>(events: &mut Events<R>, page: &mut Page::Builder) -
                                                                  })));
             }
             &Ok(XmlEvent::EndElement { ref name, .. }) if name.local_name == "page" => {
-                return Ok(())
+                if let Some(ref redirect) = redirect {
+                    page.set_redirect(&*redirect);
+                } else if let Some(ref text) = text {
+                    page.set_text(&*text);
+                }
+                return Ok(());
             }
             _ => (),
         }
@@ -79,33 +351,93 @@ fn consume_page<R: io::Read>(events: &mut Events<R>, page: &mut Page::Builder) -
     Err(io::Error::new(io::ErrorKind::Other, "eof?"))
 }
 
+fn consume_page_history<R: io::Read, W: io::Write>(events: &mut Events<R>,
+                                                   parts: &mut Parts,
+                                                   pages: &mut W,
+                                                   models: &mut ModelCounts)
+                                                   -> WikiResult<()> {
+    let mut page_id = 0u64;
+    let mut first = true;
+    while let Some(ref e) = events.next() {
+        match e {
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "id" => {
+                page_id = try!(consume_number(events, "id"));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "revision" => {
+                let mut message = Builder::new_default();
+                {
+                    let mut revision = message.init_root::<Revision::Builder>();
+                    revision.set_page_id(page_id);
+                    let (model, text) = try!(consume_revision(events, &mut revision));
+                    if let Some(model) = model {
+                        *models.entry(model).or_insert(0) += 1;
+                    }
+                    if let Some(text) = text {
+                        revision.set_text(&*text);
+                    }
+                }
+                let (part, offset) = try!(parts.write(&mut message));
+                if first {
+                    try!(writeln!(pages, "{} {} {}", page_id, part, offset));
+                    first = false;
+                }
+            }
+            &Ok(XmlEvent::EndElement { ref name, .. }) if name.local_name == "page" => {
+                return Ok(())
+            }
+            _ => (),
+        }
+    }
+    Err("eof?".to_string())?
+}
+
+/// Fills the revision metadata, and hands back the model and text for the
+/// caller to store where it sees fit.
 fn consume_revision<R: io::Read>(events: &mut Events<R>,
-                                 page: &mut Page::Builder)
-                                 -> io::Result<()> {
+                                 revision: &mut Revision::Builder)
+                                 -> io::Result<(Option<String>, Option<String>)> {
+    let mut model = None;
+    let mut text = None;
     while let Some(ref e) = events.next() {
         match e {
             &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "model" => {
-                page.set_model(match &*try!(consume_string(events)) {
-                                   "wikitext" => Page::Model::Wikitext,
-                                   "wikibase-item" => Page::Model::Wikibaseitem,
-                                   "css" => Page::Model::Css,
-                                   "json" => Page::Model::Json,
-                                   "flow-board" => Page::Model::Flowboard,
-                                   "javascript" => Page::Model::Javascript,
-                                   "Scribunto" => Page::Model::Scribunto,
-                                   "MassMessageListContent" => Page::Model::Massmessagelistcontent,
-                                   m => {
-                                       return Err(io::Error::new(io::ErrorKind::Other,
-                                                                 "invalid model : ".to_string() +
-                                                                 m))
-                                   }
-                               })
+                model = Some(try!(consume_string(events)));
             }
             &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "text" => {
-                page.set_text(&*try!(consume_string(events)));
+                text = Some(try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "id" => {
+                revision.set_id(try!(consume_number(events, "id")));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "parentid" => {
+                revision.set_parent_id(try!(consume_number(events, "parentid")));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "timestamp" => {
+                revision.set_timestamp(&*try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::StartElement { ref name, ref attributes, .. })
+                if name.local_name == "contributor" => {
+                let mut contributor = revision.borrow().init_contributor();
+                if attributes.iter().any(|a| a.name.local_name == "deleted") {
+                    contributor.set_deleted(());
+                } else {
+                    try!(consume_contributor(events, &mut contributor));
+                }
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "minor" => {
+                revision.set_minor(true);
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "comment" => {
+                revision.set_comment(&*try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "sha1" => {
+                revision.set_sha1(&*try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "format" => {
+                revision.set_format(&*try!(consume_string(events)));
             }
             &Ok(XmlEvent::EndElement { ref name, .. }) if name.local_name == "revision" => {
-                return Ok(())
+                return Ok((model, text))
             }
             _ => (),
         }
@@ -113,6 +445,68 @@ fn consume_revision<R: io::Read>(events: &mut Events<R>,
     Err(io::Error::new(io::ErrorKind::Other, "eof?"))
 }
 
+pub fn is_known_model(model: &str) -> bool {
+    match parse_model(model) {
+        Page::Model::Other => false,
+        _ => true,
+    }
+}
+
+fn parse_model(model: &str) -> Page::Model {
+    match model {
+        "wikitext" => Page::Model::Wikitext,
+        "wikibase-item" => Page::Model::Wikibaseitem,
+        "wikibase-property" => Page::Model::Wikibaseproperty,
+        "wikibase-lexeme" => Page::Model::Wikibaselexeme,
+        "css" => Page::Model::Css,
+        "sanitized-css" => Page::Model::Sanitizedcss,
+        "json" => Page::Model::Json,
+        "Json.JsonConfig" => Page::Model::Jsonconfig,
+        "flow-board" => Page::Model::Flowboard,
+        "javascript" => Page::Model::Javascript,
+        "Scribunto" => Page::Model::Scribunto,
+        "MassMessageListContent" => Page::Model::Massmessagelistcontent,
+        "proofread-page" => Page::Model::Proofreadpage,
+        _ => Page::Model::Other,
+    }
+}
+
+fn consume_contributor<R: io::Read>(events: &mut Events<R>,
+                                    contributor: &mut Contributor::Builder)
+                                    -> io::Result<()> {
+    let mut username = None;
+    let mut id = 0;
+    while let Some(ref e) = events.next() {
+        match e {
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "username" => {
+                username = Some(try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "id" => {
+                id = try!(consume_number(events, "id"));
+            }
+            &Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "ip" => {
+                contributor.set_ip(&*try!(consume_string(events)));
+            }
+            &Ok(XmlEvent::EndElement { ref name, .. }) if name.local_name == "contributor" => {
+                if let Some(username) = username {
+                    let mut user = contributor.borrow().init_user();
+                    user.set_username(&*username);
+                    user.set_id(id);
+                }
+                return Ok(());
+            }
+            _ => (),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::Other, "eof?"))
+}
+
+fn consume_number<R: io::Read>(events: &mut Events<R>, what: &str) -> io::Result<u64> {
+    try!(consume_string(events))
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, format!("can not parse int ({})", what)))
+}
+
 fn consume_string<R: io::Read>(events: &mut Events<R>) -> io::Result<String> {
     let mut text = String::new();
     while let Some(ref e) = events.next() {
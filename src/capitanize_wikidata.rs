@@ -1,14 +1,12 @@
-use std::{io, fs, path};
+use std::{io, path};
 use std::io::prelude::*;
 
 use serde_json;
 
-use flate2;
-use flate2::write::GzEncoder;
-
-use capnp::serialize_packed;
 use capnp::{traits, text};
-use capnp::message::{Allocator, Builder};
+use capnp::message::{Allocator, Builder, HeapAllocator};
+
+use tinycdb::Cdb;
 
 pub use capn_wiki::wiki_capnp::page as Page;
 pub use capn_wiki::wiki_capnp::entity as Entity;
@@ -25,7 +23,9 @@ pub use capn_wiki::wiki_capnp::quantity as Quantity;
 pub use capn_wiki::wiki_capnp::globe_coordinate as GlobeCoordinate;
 pub use capn_wiki::wiki_capnp::EntityType;
 
-use {WikiResult, WikiError};
+use {WikiResult, WikiError, BoxedIter};
+use blocks::BlockWriter;
+use wikidata::{EntityHelpers, EntityMessage, Wikidata};
 
 macro_rules! println_stderr(
     ($($arg:tt)*) => (
@@ -38,22 +38,20 @@ macro_rules! println_stderr(
 
 pub fn process<R: io::Read>(input: R, output: &path::Path) -> WikiResult<()> {
     let input = io::BufReader::new(input);
-    let mut path = path::PathBuf::new();
-    //    let mut part:Option<SnappyFramedEncoder<_>> = None; //open_one(counter);
-    let mut part: Option<GzEncoder<_>> = None;
+    let mut part: Option<BlockWriter> = None;
     let mut part_counter = 0;
     let mut counter = 0;
     for line in input.lines() {
         let mut line = try!(line);
-        if part.is_none() ||
-           (counter % 1000 == 0 && try!(fs::metadata(&path)).len() > 250_000_000) {
-            path = path::PathBuf::from(format!("{}-part-{:05}.cap.gz",
-                                               output.to_str().unwrap(),
-                                               part_counter));
+        if part.as_ref().map(|p| p.len() > 250_000_000).unwrap_or(true) {
+            if let Some(mut full) = part.take() {
+                try!(full.finish());
+            }
+            let path = path::PathBuf::from(format!("{}-part-{:05}.cap.gz",
+                                                   output.to_str().unwrap(),
+                                                   part_counter));
             part_counter += 1;
-            part =
-                //Some(SnappyFramedEncoder::new(fs::File::create(path.as_os_str()).unwrap()).unwrap());
-                Some(GzEncoder::new(fs::File::create(path.as_os_str()).unwrap(), flate2::Compression::Default));
+            part = Some(try!(BlockWriter::create(&*path)));
         }
         if line == "[" || line == "]" {
         } else {
@@ -85,12 +83,84 @@ pub fn process<R: io::Read>(input: R, output: &path::Path) -> WikiResult<()> {
             try!(json::ser::to_writer_pretty(&mut io::stderr(),&value));
             return Err(WikiError::Other("blah".to_string()));
 */
-            try!(serialize_packed::write_message(&mut part.as_mut().unwrap(), &mut message));
+            try!(part.as_mut().unwrap().write_message(&mut message));
         }
     }
+    if let Some(mut last) = part {
+        try!(last.finish());
+    }
     Ok(())
 }
 
+/// Compile an entity, as found on a line of the JSON dump.
+pub fn entity_message(value: &serde_json::value::Value) -> WikiResult<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    try!(consume_item(value, &mut message));
+    Ok(message)
+}
+
+/// What the lookup tables know of an entity.
+#[derive(Debug, Clone)]
+pub struct IndexedEntity {
+    pub id: String,
+    pub label: Option<String>,
+    /// name of the part file holding the entity
+    pub part: String,
+}
+
+impl IndexedEntity {
+    pub fn of(entity: &EntityMessage, part: &str) -> WikiResult<IndexedEntity> {
+        Ok(IndexedEntity {
+               id: try!(entity.get_id()).to_string(),
+               label: entity.get_a_label().ok(),
+               part: part.to_string(),
+           })
+    }
+}
+
+/// Entities of compiled part files, for `write_indexes`.
+pub fn index_parts(parts: Vec<path::PathBuf>) -> BoxedIter<WikiResult<IndexedEntity>> {
+    Box::new(parts.into_iter().flat_map(|part| -> BoxedIter<WikiResult<IndexedEntity>> {
+        let name = part.file_name().unwrap().to_str().unwrap().to_string();
+        match Wikidata::entity_iter_for_file(part) {
+            Ok(it) => Box::new(it.map(move |e| e.and_then(|e| IndexedEntity::of(&e, &*name)))),
+            Err(e) => Box::new(Some(Err(e)).into_iter()),
+        }
+    }))
+}
+
+/// Write the `labels` (id to label) and `ids` (id to part file name) lookup
+/// tables in `target`.
+pub fn write_indexes<I>(target: &path::Path, entities: I) -> WikiResult<()>
+    where I: Iterator<Item = WikiResult<IndexedEntity>>
+{
+    let mut entities = entities;
+    let mut result: WikiResult<()> = Ok(());
+    try!(Cdb::new(&*target.join("labels"), |labels| {
+        let created = Cdb::new(&*target.join("ids"), |ids| for entity in &mut entities {
+            let added = entity.and_then(|entity| {
+                if let Some(ref label) = entity.label {
+                    try!(labels
+                             .add(entity.id.as_bytes(), label.as_bytes())
+                             .map_err(|e| format!("Cdb Error: {:?}", e)));
+                }
+                try!(ids.add(entity.id.as_bytes(), entity.part.as_bytes())
+                         .map_err(|e| format!("Cdb Error: {:?}", e)));
+                Ok(())
+            });
+            if let Err(e) = added {
+                result = Err(e);
+                return;
+            }
+        });
+        if let Err(e) = created {
+            result = Err(format!("Cdb Error: {:?}", e).into());
+        }
+    })
+                 .map_err(|e| format!("Cdb Error: {:?}", e)));
+    result
+}
+
 fn consume_item<T: Allocator>(value: &serde_json::value::Value,
                               message: &mut Builder<T>)
                               -> WikiResult<()> {
@@ -206,6 +276,12 @@ fn build_sitelink(json: &serde_json::value::Value,
     json.pointer("/title")
         .and_then(|v| v.as_str())
         .map(|v| builder.set_title(v));
+    if let Some(badges) = json.pointer("/badges").and_then(|v| v.as_array()) {
+        let mut list = builder.init_badges(badges.len() as u32);
+        for (i, badge) in badges.iter().enumerate() {
+            list.set(i as u32, try!(badge.as_str().ok_or("badge is expected to be a string")));
+        }
+    }
     Ok(())
 }
 
@@ -228,7 +304,32 @@ fn build_claim(json: &serde_json::value::Value, mut builder: Claim::Builder) ->
     }
     let snak = try!(json.pointer("/mainsnak")
                         .ok_or("I do expect a mainsnak."));
-    try!(build_snak(snak, builder.init_mainsnak()));
+    try!(build_snak(snak, builder.borrow().init_mainsnak()));
+    if let Some(qualifiers) = json.pointer("/qualifiers") {
+        try!(build_qualifiers(qualifiers, builder.init_qualifiers()));
+    }
+    Ok(())
+}
+
+/// Qualifiers come as lists of snaks by property: the map gets one entry
+/// per snak, a property repeating when it has several values.
+fn build_qualifiers(json: &serde_json::value::Value,
+                    mut map: Map::Builder<text::Owned, Snak::Owned>)
+                    -> WikiResult<()> {
+    let by_property = try!(json.as_object().ok_or("qualifiers are expected as json object"));
+    let mut snaks = vec![];
+    for (property, values) in by_property.iter() {
+        for snak in try!(values.as_array().ok_or("qualifiers are expected as arrays")) {
+            snaks.push((property, snak));
+        }
+    }
+    map.borrow().init_entries(snaks.len() as u32);
+    let mut entries = try!(map.borrow().get_entries());
+    for (i, (property, snak)) in snaks.into_iter().enumerate() {
+        let mut entry = entries.borrow().get(i as u32);
+        try!(entry.borrow().set_key(&**property));
+        try!(build_snak(snak, try!(entry.get_value())));
+    }
     Ok(())
 }
 
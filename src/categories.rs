@@ -0,0 +1,274 @@
+use std::io;
+use std::io::prelude::*;
+use std::fs;
+use std::path;
+use std::sync::Mutex;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use flate2::read::GzDecoder;
+use regex::Regex;
+
+use WikiResult;
+use mapred;
+use wiki::{Wiki, SiteInfo, TitleCase, MessageAndPage};
+use wiki::Page::Which::{Text, Redirect};
+
+const CATEGORY_NS: i16 = 14;
+
+/// Finds `[[Category:...]]` memberships in wikitext, using every name the
+/// wiki knows for the category namespace ("Category", "Catégorie", ...).
+pub struct CategoryExtractor {
+    regex: Regex,
+    case: TitleCase,
+}
+
+impl CategoryExtractor {
+    pub fn new(siteinfo: &SiteInfo) -> WikiResult<CategoryExtractor> {
+        let ns = try!(siteinfo
+                          .namespace_by_id(CATEGORY_NS)
+                          .ok_or("no category namespace in siteinfo"));
+        let mut names = vec![::regex::escape(&*ns.canonical)];
+        if ns.name != ns.canonical {
+            names.push(::regex::escape(&*ns.name));
+        }
+        let expr = format!(r"(?i)\[\[\s*(?:{})\s*:\s*([^\]\|\n]+?)\s*(?:\|[^\]]*)?\]\]",
+                           names.join("|"));
+        Ok(CategoryExtractor {
+               regex: try!(Regex::new(&*expr).map_err(|e| format!("{}", e))),
+               case: ns.case,
+           })
+    }
+
+    pub fn categories(&self, text: &str) -> Vec<String> {
+        self.regex
+            .captures_iter(text)
+            .map(|cap| normalize_title(&cap[1], self.case))
+            .collect()
+    }
+}
+
+/// Titles as used for category names: spaces instead of underscores, and
+/// an upper case first letter on first-letter wikis.
+pub fn normalize_title(title: &str, case: TitleCase) -> String {
+    let title = title.trim().replace("_", " ");
+    match case {
+        TitleCase::CaseSensitive => title,
+        TitleCase::FirstLetter => {
+            let mut chars = title.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => title.clone(),
+            }
+        }
+    }
+}
+
+/// Strips the namespace prefix from a page title ("Category:Foo" -> "Foo").
+fn strip_namespace(title: &str, ns: u16) -> &str {
+    if ns == 0 {
+        title
+    } else {
+        title.splitn(2, ':').nth(1).unwrap_or(title)
+    }
+}
+
+/// Category membership graph: member pages (namespace and title, category
+/// pages aside) per category, and subcategories per category.
+#[derive(Debug, Default)]
+pub struct CategoryGraph {
+    members: HashMap<String, Vec<(u16, String)>>,
+    subcategories: HashMap<String, Vec<String>>,
+}
+
+impl CategoryGraph {
+    pub fn new() -> CategoryGraph {
+        CategoryGraph::default()
+    }
+
+    /// `page` is the full page title, `category` the bare category name.
+    pub fn add(&mut self, page: &str, ns: u16, category: String) {
+        if ns as i16 == CATEGORY_NS {
+            self.subcategories
+                .entry(category)
+                .or_insert(vec![])
+                .push(strip_namespace(page, ns).to_string());
+        } else {
+            self.members.entry(category).or_insert(vec![]).push((ns, page.to_string()));
+        }
+    }
+
+    /// Build the graph from the `[[Category:...]]` links in the compiled
+    /// pages text.
+    pub fn from_wiki(wiki: &Wiki) -> WikiResult<CategoryGraph> {
+        let extractor = try!(CategoryExtractor::new(&try!(wiki.siteinfo())));
+        let graph = Mutex::new(CategoryGraph::new());
+        try!(mapred::par_try_foreach(try!(wiki.page_iter_iter()),
+                                     mapred::ErrorPolicy::FailFast,
+                                     None,
+                                     &|page: WikiResult<MessageAndPage>| {
+            let page = try!(page);
+            let reader = try!(page.as_page_reader());
+            if let Text(text) = try!(reader.which()) {
+                let categories = extractor.categories(try!(text));
+                if categories.len() > 0 {
+                    let title = try!(reader.get_title());
+                    let mut graph = graph.lock().unwrap();
+                    for category in categories {
+                        graph.add(title, reader.get_ns(), category);
+                    }
+                }
+            }
+            Ok(())
+        }));
+        Ok(graph.into_inner().unwrap())
+    }
+
+    /// Build the graph from a `categorylinks.sql.gz` table dump. Page ids
+    /// are resolved against the compiled pages.
+    pub fn from_categorylinks(wiki: &Wiki, sql: &path::Path) -> WikiResult<CategoryGraph> {
+        let case = try!(wiki.siteinfo()).case;
+        let mut pages: HashMap<u64, (u16, String)> = HashMap::new();
+        for page in try!(wiki.page_iter()) {
+            let page = try!(page);
+            let reader = try!(page.as_page_reader());
+            if let Redirect(_) = try!(reader.which()) {
+                continue;
+            }
+            pages.insert(reader.get_id(),
+                         (reader.get_ns(), try!(reader.get_title()).to_string()));
+        }
+        let mut graph = CategoryGraph::new();
+        let input = io::BufReader::new(try!(GzDecoder::new(try!(fs::File::open(sql)))));
+        for line in input.split(b'\n') {
+            let line = try!(line);
+            if !line.starts_with(b"INSERT INTO") {
+                continue;
+            }
+            for row in try!(sql_values(&*line)) {
+                if row.len() < 2 {
+                    continue;
+                }
+                let from: u64 = try!(row[0].parse().map_err(|_| "can not parse int (cl_from)"));
+                if let Some(&(ns, ref title)) = pages.get(&from) {
+                    graph.add(&*title, ns, normalize_title(&*row[1], case));
+                }
+            }
+        }
+        Ok(graph)
+    }
+
+    /// Build the graph from `categorylinks.sql.gz` if it was downloaded along
+    /// with the dump, from the wikitext otherwise.
+    pub fn for_wiki(wiki: &Wiki) -> WikiResult<CategoryGraph> {
+        let glob = format!("{}/*-categorylinks.sql.gz", wiki.download_dir());
+        match try!(::glob::glob(&*glob)).next() {
+            Some(sql) => CategoryGraph::from_categorylinks(wiki, &*try!(sql)),
+            None => CategoryGraph::from_wiki(wiki),
+        }
+    }
+
+    /// Member pages of a category, as namespace and full title.
+    pub fn members(&self, category: &str) -> &[(u16, String)] {
+        self.members.get(category).map(|v| &**v).unwrap_or(&[])
+    }
+
+    pub fn subcategories(&self, category: &str) -> &[String] {
+        self.subcategories.get(category).map(|v| &**v).unwrap_or(&[])
+    }
+
+    /// Categories reachable from `category` following at most `depth`
+    /// subcategory links, `category` included. Cycles are harmless.
+    pub fn categories_under(&self, category: &str, depth: usize) -> BTreeSet<String> {
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut frontier: Vec<&str> = vec![category];
+        seen.insert(category);
+        for _ in 0..depth {
+            let mut next = vec![];
+            for cat in frontier {
+                for sub in self.subcategories(cat) {
+                    if seen.insert(&**sub) {
+                        next.push(&**sub);
+                    }
+                }
+            }
+            if next.len() == 0 {
+                break;
+            }
+            frontier = next;
+        }
+        seen.into_iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Pages of namespace `ns` in `category` or any of its subcategories
+    /// down to `depth`.
+    pub fn pages_under(&self, category: &str, depth: usize, ns: u16) -> BTreeSet<String> {
+        let mut result = BTreeSet::new();
+        for cat in self.categories_under(category, depth) {
+            for &(page_ns, ref page) in self.members(&*cat) {
+                if page_ns == ns {
+                    result.insert(page.clone());
+                }
+            }
+        }
+        result
+    }
+
+    /// Articles (main namespace pages) in `category` or any of its
+    /// subcategories down to `depth`. Files, templates, talk pages and the
+    /// like are left out.
+    pub fn articles_under(&self, category: &str, depth: usize) -> BTreeSet<String> {
+        self.pages_under(category, depth, 0)
+    }
+}
+
+/// Splits the VALUES of a mysqldump INSERT statement into rows of unquoted
+/// fields.
+fn sql_values(line: &[u8]) -> WikiResult<Vec<Vec<String>>> {
+    let start = try!(line.iter()
+                         .position(|&c| c == b'(')
+                         .ok_or("no VALUES in INSERT statement"));
+    let mut rows = vec![];
+    let mut row: Vec<String> = vec![];
+    let mut field: Vec<u8> = vec![];
+    let mut in_string = false;
+    let mut escaped = false;
+    for &c in &line[start..] {
+        if in_string {
+            if escaped {
+                field.push(match c {
+                               b'n' => b'\n',
+                               b't' => b'\t',
+                               b'0' => 0,
+                               c => c,
+                           });
+                escaped = false;
+            } else if c == b'\\' {
+                escaped = true;
+            } else if c == b'\'' {
+                in_string = false;
+            } else {
+                field.push(c);
+            }
+            continue;
+        }
+        match c {
+            b'\'' => in_string = true,
+            b'(' => {
+                row.clear();
+                field.clear();
+            }
+            b',' => {
+                row.push(String::from_utf8_lossy(&*field).into_owned());
+                field.clear();
+            }
+            b')' => {
+                row.push(String::from_utf8_lossy(&*field).into_owned());
+                field.clear();
+                rows.push(row.clone());
+            }
+            b';' => break,
+            c => field.push(c),
+        }
+    }
+    Ok(rows)
+}
@@ -0,0 +1,217 @@
+//! Per-chunk results of a long scan, saved under
+//! `{root}/checkpoints/{job}/`, so that a rerun of the same job skips the
+//! chunks (part files) already done.
+//!
+//! Chunks are identified by their index, so a job id must only be reused
+//! for the same input: including the dump date in it is a good idea.
+
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path;
+
+use bincode;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use WikiResult;
+use store::DataStore;
+
+pub struct Checkpoint {
+    job: String,
+    dir: path::PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new(store: &DataStore, job: &str) -> WikiResult<Checkpoint> {
+        if job.is_empty() || job.contains('/') || job.starts_with('.') {
+            Err(format!("invalid job id: {:?}", job))?
+        }
+        let dir = store.root().join("checkpoints").join(job);
+        try!(fs::create_dir_all(&dir));
+        Ok(Checkpoint {
+               job: job.to_string(),
+               dir: dir,
+           })
+    }
+
+    pub fn job(&self) -> &str {
+        &*self.job
+    }
+
+    /// Record the chunk count of the job on its first run, and check it on
+    /// the next ones: a different count means a different input.
+    pub fn expect_chunks(&self, chunks: usize) -> WikiResult<()> {
+        let file = self.dir.join("chunks");
+        if file.exists() {
+            let mut recorded = String::new();
+            try!(try!(fs::File::open(&file)).read_to_string(&mut recorded));
+            if recorded.trim() != chunks.to_string() {
+                Err(format!("job {} was started on {} chunks, not {}: use another job id",
+                            self.job,
+                            recorded.trim(),
+                            chunks))?
+            }
+        } else {
+            try!(writeln!(try!(fs::File::create(&file)), "{}", chunks));
+        }
+        Ok(())
+    }
+
+    fn chunk_file(&self, chunk: usize) -> path::PathBuf {
+        self.dir.join(format!("{:05}.bin", chunk))
+    }
+
+    pub fn is_done(&self, chunk: usize) -> bool {
+        self.chunk_file(chunk).exists()
+    }
+
+    pub fn load<T: DeserializeOwned>(&self, chunk: usize) -> WikiResult<T> {
+        let file = self.chunk_file(chunk);
+        let mut input = io::BufReader::new(try!(fs::File::open(&file)));
+        Ok(try!(bincode::deserialize_from(&mut input)
+                    .map_err(|e| format!("reading checkpoint {:?}: {}", file, e))))
+    }
+
+    /// Save the result of a chunk, marking it done. The file appears
+    /// complete or not at all.
+    pub fn save<T: Serialize>(&self, chunk: usize, result: &T) -> WikiResult<()> {
+        let file = self.chunk_file(chunk);
+        let tmp = self.dir.join(format!(".{:05}.tmp", chunk));
+        {
+            let mut out = io::BufWriter::new(try!(fs::File::create(&tmp)));
+            try!(bincode::serialize_into(&mut out, result));
+            try!(out.flush());
+            // on disk before the rename, or a crash could leave the chunk
+            // marked done with an empty file
+            try!(out.get_ref().sync_all());
+        }
+        try!(fs::rename(&tmp, &file));
+        Ok(())
+    }
+
+    /// Remove the checkpoints, once the job output is safe.
+    pub fn clear(self) -> WikiResult<()> {
+        try!(fs::remove_dir_all(&self.dir));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use aggregate;
+    use aggregate::Count;
+    use mapred;
+    use mapred::{BI, ErrorPolicy, MapReduceOp, Record};
+
+    struct Rec(usize, u64);
+
+    impl Record for Rec {
+        fn record_id(&self) -> Option<String> {
+            Some(format!("{}/{}", self.0, self.1))
+        }
+    }
+
+    fn store(test: &str) -> DataStore {
+        let root = env::temp_dir().join(format!("wolframite-checkpoint-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        DataStore::new(root)
+    }
+
+    /// Three chunks of four records, the chunk number in each record.
+    fn chunks() -> BI<'static, BI<'static, Rec>> {
+        Box::new((0..3).map(|chunk| Box::new((1..5).map(move |n| Rec(chunk, n))) as BI<Rec>))
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let store = store("saves_and_loads");
+        let checkpoint = Checkpoint::new(&store, "job").unwrap();
+        assert!(!checkpoint.is_done(3));
+        let result: HashMap<String, u64> = vec![("a".to_string(), 1), ("b".to_string(), 2)]
+            .into_iter()
+            .collect();
+        checkpoint.save(3, &result).unwrap();
+        assert!(checkpoint.is_done(3));
+        assert!(!checkpoint.is_done(2));
+        let loaded: HashMap<String, u64> = checkpoint.load(3).unwrap();
+        assert_eq!(loaded, result);
+        checkpoint.clear().unwrap();
+        let _ = fs::remove_dir_all(store.root());
+    }
+
+    #[test]
+    fn rejects_another_chunk_count() {
+        let store = store("rejects_another_chunk_count");
+        assert!(Checkpoint::new(&store, "../job").is_err());
+        let checkpoint = Checkpoint::new(&store, "job").unwrap();
+        checkpoint.expect_chunks(12).unwrap();
+        checkpoint.expect_chunks(12).unwrap();
+        assert!(checkpoint.expect_chunks(13).is_err());
+        let _ = fs::remove_dir_all(store.root());
+    }
+
+    #[test]
+    fn map_reduce_skips_completed_chunks() {
+        let store = store("map_reduce_skips_completed_chunks");
+        let checkpoint = Checkpoint::new(&store, "job").unwrap();
+        checkpoint.expect_chunks(3).unwrap();
+        let saved: HashMap<String, Count> = vec![("sum".to_string(), Count(100))]
+            .into_iter()
+            .collect();
+        checkpoint.save(1, &saved).unwrap();
+        // records of chunk 1 fail: reading it again would fail the run
+        let mro = MapReduceOp::new_map_reduce(|r: Rec| -> WikiResult<BI<(String, Count)>> {
+                                                  if r.0 == 1 {
+                                                      Err("chunk 1 was done")?
+                                                  }
+                                                  Ok(Box::new(Some(("sum".to_string(), Count(r.1)))
+                                                                  .into_iter()))
+                                              },
+                                              aggregate::merge);
+        let (result, _) = mro.run_checkpointed(chunks(), &checkpoint).unwrap();
+        assert_eq!(result["sum"], Count(10 + 100 + 10));
+        assert!((0..3).all(|chunk| checkpoint.is_done(chunk)));
+        let again: HashMap<String, Count> = checkpoint.load(2).unwrap();
+        assert_eq!(again["sum"], Count(10));
+        let _ = fs::remove_dir_all(store.root());
+    }
+
+    #[test]
+    fn foreach_skips_completed_chunks() {
+        let store = store("foreach_skips_completed_chunks");
+        let checkpoint = Checkpoint::new(&store, "job").unwrap();
+        checkpoint.expect_chunks(3).unwrap();
+        checkpoint.save(0, &()).unwrap();
+        let seen = AtomicUsize::new(0);
+        mapred::par_try_foreach_checkpointed(chunks(),
+                                             ErrorPolicy::FailFast,
+                                             None,
+                                             &checkpoint,
+                                             &|r: Rec| {
+                                                  assert!(r.0 != 0);
+                                                  seen.fetch_add(1, Ordering::SeqCst);
+                                                  Ok(())
+                                              })
+            .unwrap();
+        assert_eq!(seen.load(Ordering::SeqCst), 8);
+        assert!((0..3).all(|chunk| checkpoint.is_done(chunk)));
+        // a rerun has nothing left to do
+        mapred::par_try_foreach_checkpointed(chunks(),
+                                             ErrorPolicy::FailFast,
+                                             None,
+                                             &checkpoint,
+                                             &|_: Rec| -> WikiResult<()> {
+                                                  Err("all chunks were done".into())
+                                              })
+            .unwrap();
+        let _ = fs::remove_dir_all(store.root());
+    }
+}
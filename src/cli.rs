@@ -0,0 +1,114 @@
+use std::io;
+use std::io::prelude::*;
+use std::process;
+
+use clap::{App, Arg, ArgMatches};
+
+use WikiResult;
+use checkpoint::Checkpoint;
+use mapred;
+use store::{DataStore, Stage};
+use tabular;
+
+/// Base clap App for the binaries, with the options they all understand.
+pub fn app<'a, 'b>(name: &str, about: &'b str) -> App<'a, 'b> {
+    App::new(name)
+        .about(about)
+        .arg(Arg::with_name("data-dir")
+                 .long("data-dir")
+                 .takes_value(true)
+                 .help("Root of the data tree (default: $WOLFRAMITE_DATA, the config \
+                        file data_dir, or ./data)"))
+        .arg(Arg::with_name("threads")
+                 .long("threads")
+                 .takes_value(true)
+                 .help("Worker threads (default: $WOLFRAMITE_THREADS, or one per core)"))
+}
+
+pub fn wiki_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("wiki")
+        .short("w")
+        .long("wiki")
+        .takes_value(true)
+        .help("Wiki name (enwiki, frwiki, wikidata...)")
+}
+
+pub fn date_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("date")
+        .short("d")
+        .long("date")
+        .takes_value(true)
+        .help("Dump date (YYYYMMDD), or \"latest\" (the default)")
+}
+
+pub fn job_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("job")
+        .long("job")
+        .takes_value(true)
+        .help("Checkpoint the scan under this job id: a rerun with the same id \
+               skips the parts already done")
+}
+
+pub fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .short("f")
+        .long("format")
+        .takes_value(true)
+        .possible_values(tabular::FORMATS)
+        .help("Output format")
+}
+
+/// The --format value, or `default`.
+pub fn format(matches: &ArgMatches, default: tabular::Format) -> WikiResult<tabular::Format> {
+    match matches.value_of("format") {
+        Some(name) => tabular::Format::from_name(name),
+        None => Ok(default),
+    }
+}
+
+/// The checkpoint of the --job, if any.
+pub fn checkpoint(matches: &ArgMatches, store: &DataStore) -> WikiResult<Option<Checkpoint>> {
+    match matches.value_of("job") {
+        Some(job) => Ok(Some(try!(Checkpoint::new(store, job)))),
+        None => Ok(None),
+    }
+}
+
+/// Apply the global options, and return the data store picked by
+/// --data-dir, or the default one.
+pub fn setup(matches: &ArgMatches) -> WikiResult<DataStore> {
+    if let Some(threads) = matches.value_of("threads") {
+        let threads = try!(threads.parse().map_err(|_| "--threads expects a number"));
+        mapred::set_threads(threads);
+    }
+    match matches.value_of("data-dir") {
+        Some(dir) => Ok(DataStore::new(dir)),
+        None => DataStore::from_env(),
+    }
+}
+
+/// The --date value, or the latest complete date for `stage`.
+pub fn date(matches: &ArgMatches,
+            store: &DataStore,
+            stage: Stage,
+            wiki: &str)
+            -> WikiResult<String> {
+    match matches.value_of("date") {
+        Some(date) if date != "latest" => Ok(date.to_string()),
+        _ => store.latest_or_err(stage, wiki),
+    }
+}
+
+/// Run a binary main function: errors are reported on stderr with their
+/// causes, and turned into a non-zero exit code.
+pub fn run<F: FnOnce() -> WikiResult<()>>(f: F) {
+    if let Err(e) = f() {
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        let _ = writeln!(stderr, "error: {}", e);
+        for cause in e.iter().skip(1) {
+            let _ = writeln!(stderr, "caused by: {}", cause);
+        }
+        process::exit(1);
+    }
+}
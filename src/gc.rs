@@ -0,0 +1,199 @@
+//! Retention: removes old date directories, keeping the most recent
+//! complete ones of each stage and wiki, and whatever they were built from.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path;
+
+use WikiResult;
+use pipeline;
+use store::{DataStore, Stage, STAGES};
+
+#[derive(Debug, Clone)]
+pub struct Removal {
+    pub stage: Stage,
+    pub wiki: String,
+    pub date: String,
+    pub path: String,
+    pub bytes: u64,
+}
+
+/// Directories to remove so that each stage and wiki keeps its `keep` most
+/// recent complete dates. Incomplete dates newer than the oldest kept one
+/// may be in progress and are left alone, as are locked ones. A date is
+/// never removed from a stage while a kept date of a downstream stage
+/// depends on it. Staging directories of crashed runs are removed too.
+pub fn plan(store: &DataStore, keep: usize, wiki: Option<&str>) -> WikiResult<Vec<Removal>> {
+    let mut present: HashMap<(Stage, String), Vec<String>> = HashMap::new();
+    let mut kept: HashSet<(Stage, String, String)> = HashSet::new();
+    for stage in STAGES {
+        for w in try!(store.wikis(*stage)) {
+            if wiki.map(|only| only != w).unwrap_or(false) {
+                continue;
+            }
+            let dates = try!(store.dates(*stage, &*w));
+            let complete = try!(store.complete_dates(*stage, &*w));
+            let newest: Vec<&String> = complete.iter().rev().take(keep).collect();
+            let oldest_kept = newest.last().map(|d| d.to_string());
+            for date in &dates {
+                let in_progress = store.is_locked(*stage, &*w, date) ||
+                                  !store.is_complete(*stage, &*w, date) &&
+                                  oldest_kept.as_ref().map(|o| date > o).unwrap_or(true);
+                if newest.contains(&date) || in_progress {
+                    kept.insert((*stage, w.clone(), date.clone()));
+                }
+            }
+            present.insert((*stage, w), dates);
+        }
+    }
+    // keep the inputs of what is kept, up the dependency chain
+    loop {
+        let mut more = vec![];
+        for &(stage, ref w, ref date) in &kept {
+            for input in pipeline::inputs(stage) {
+                let key = (*input, w.clone(), date.clone());
+                let exists = present
+                    .get(&(*input, w.clone()))
+                    .map(|dates| dates.contains(date))
+                    .unwrap_or(false);
+                if exists && !kept.contains(&key) {
+                    more.push(key);
+                }
+            }
+        }
+        if more.len() == 0 {
+            break;
+        }
+        kept.extend(more);
+    }
+    let mut removals = vec![];
+    for stage in STAGES {
+        let mut wikis: Vec<&String> = present
+            .keys()
+            .filter(|k| k.0 == *stage)
+            .map(|k| &k.1)
+            .collect();
+        wikis.sort();
+        for w in wikis {
+            for date in &present[&(*stage, w.clone())] {
+                if !kept.contains(&(*stage, w.clone(), date.clone())) {
+                    let dir = store.dir(*stage, w, date);
+                    removals.push(Removal {
+                                      stage: *stage,
+                                      wiki: w.clone(),
+                                      date: date.clone(),
+                                      bytes: try!(disk_usage(path::Path::new(&*dir))),
+                                      path: dir,
+                                  });
+                }
+            }
+            for (date, dir) in try!(store.leftovers(*stage, w)) {
+                removals.push(Removal {
+                                  stage: *stage,
+                                  wiki: w.clone(),
+                                  date: date,
+                                  bytes: try!(disk_usage(path::Path::new(&*dir))),
+                                  path: dir,
+                              });
+            }
+        }
+    }
+    Ok(removals)
+}
+
+/// Remove the planned directories. Downstream stages go first, so an
+/// interrupted run never leaves a kept stage without its inputs.
+pub fn apply(removals: &[Removal]) -> WikiResult<()> {
+    for removal in removals.iter().rev() {
+        try!(fs::remove_dir_all(&*removal.path)
+                 .map_err(|e| format!("removing {}: {}", removal.path, e)));
+    }
+    Ok(())
+}
+
+fn disk_usage(path: &path::Path) -> WikiResult<u64> {
+    let meta = try!(fs::symlink_metadata(path));
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    let mut total = 0;
+    for entry in try!(fs::read_dir(path)) {
+        total += try!(disk_usage(&*try!(entry).path()));
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::prelude::*;
+    use std::path;
+    use std::process;
+
+    use super::*;
+
+    fn store(test: &str) -> DataStore {
+        let root = env::temp_dir().join(format!("wolframite-gc-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        DataStore::new(root)
+    }
+
+    /// A date directory holding a 10 bytes file.
+    fn date(store: &DataStore, stage: Stage, wiki: &str, date: &str, complete: bool) {
+        let dir = store.dir(stage, wiki, date);
+        fs::create_dir_all(&*dir).unwrap();
+        let mut file = fs::File::create(path::Path::new(&*dir).join("data")).unwrap();
+        file.write_all(b"0123456789").unwrap();
+        if complete {
+            store.mark_complete(stage, wiki, date).unwrap();
+        }
+    }
+
+    fn planned(removals: &[Removal]) -> Vec<(Stage, &str, &str)> {
+        removals.iter().map(|r| (r.stage, &*r.wiki, &*r.date)).collect()
+    }
+
+    #[test]
+    fn keeps_recent_locked_and_needed_dates() {
+        let store = store("keeps_recent_locked_and_needed_dates");
+        for d in &["20161101", "20161201", "20170101", "20170201", "20170301"] {
+            date(&store, Stage::Download, "enwiki", d, true);
+        }
+        // locked: being built
+        date(&store, Stage::Cap, "enwiki", "20161101", false);
+        let _lock = store.lock(Stage::Cap, "enwiki", "20161101").unwrap();
+        // incomplete, older than the kept dates: a failed run
+        date(&store, Stage::Cap, "enwiki", "20161001", false);
+        for d in &["20161201", "20170101", "20170201"] {
+            date(&store, Stage::Cap, "enwiki", d, true);
+        }
+        // the only complete cdb keeps its cap and download inputs
+        date(&store, Stage::Cdb, "enwiki", "20170101", true);
+        // incomplete, newer than the kept date: maybe in progress
+        date(&store, Stage::Cdb, "enwiki", "20170301", false);
+        // leftover of a crashed run
+        fs::create_dir_all(store.root().join("cdb/enwiki/.20161225.tmp")).unwrap();
+        // other wikis are left alone when asked
+        date(&store, Stage::Download, "frwiki", "20161201", true);
+        date(&store, Stage::Download, "frwiki", "20170101", true);
+
+        let removals = plan(&store, 1, Some("enwiki")).unwrap();
+        assert_eq!(planned(&*removals),
+                   vec![(Stage::Download, "enwiki", "20161201"),
+                        (Stage::Cap, "enwiki", "20161001"),
+                        (Stage::Cap, "enwiki", "20161201"),
+                        (Stage::Cdb, "enwiki", "20161225")]);
+        assert_eq!(removals[0].bytes, 10);
+
+        apply(&*removals).unwrap();
+        assert!(!store.dates(Stage::Cap, "enwiki").unwrap().contains(&"20161201".to_string()));
+        assert!(store.is_complete(Stage::Download, "enwiki", "20170101"));
+        assert!(store.is_complete(Stage::Cap, "enwiki", "20170101"));
+        assert!(path::Path::new(&*store.dir(Stage::Cap, "enwiki", "20161101")).exists());
+        assert!(plan(&store, 1, Some("enwiki")).unwrap().is_empty());
+        assert_eq!(planned(&*plan(&store, 1, None).unwrap()),
+                   vec![(Stage::Download, "frwiki", "20161201")]);
+        let _ = fs::remove_dir_all(store.root());
+    }
+}
@@ -1,105 +1,287 @@
 use std::io;
 use std::io::prelude::*;
 use std::fs;
+use std::path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use bzip2::read::BzDecoder;
+use bzip2::read::MultiBzDecoder;
 use snappy_framed::read::SnappyFramedDecoder;
 use snappy_framed::read::CrcMode;
 
+use xml::common::Position;
 use xml::reader::EventReader;
-use xml::reader::Events;
 use xml::reader::XmlEvent;
 
 use WikiError;
+use multistream;
+use store::{DataStore, Stage};
 
-pub fn latest(state: &str, lang: &str) -> Result<Option<String>, WikiError> {
-    let glob = format!("data/{}/{}/*/ok", state, lang);
-    let mut dirs: Vec<_> = try!(try!(::glob::glob(&glob)).collect());
-    dirs.sort();
-    Ok(dirs.last()
-           .and_then(|p| p.iter().nth(3))
-           .and_then(|t| t.to_str())
-           .map(|s| s.to_string()))
+/// SHA-1 digest in base 36, zero-padded to 31 digits, as found in the
+/// `<sha1>` element of the XML dumps.
+pub fn sha1_base36(data: &[u8]) -> String {
+    let mut hasher = ::sha1::Sha1::new();
+    hasher.update(data);
+    let mut number: Vec<u8> = hasher.digest().bytes().to_vec();
+    let mut digits = vec![];
+    while number.iter().any(|&b| b != 0) {
+        let mut remainder = 0u32;
+        for byte in number.iter_mut() {
+            let acc = (remainder << 8) | *byte as u32;
+            *byte = (acc / 36) as u8;
+            remainder = acc % 36;
+        }
+        digits.push(b"0123456789abcdefghijklmnopqrstuvwxyz"[remainder as usize]);
+    }
+    while digits.len() < 31 {
+        digits.push(b'0');
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
 }
 
-pub fn data_dir_for(state: &str, lang: &str, date: &str) -> String {
-    format!("data/{}/{}/{}", state, lang, date)
+pub type WikiResult<R> = Result<R, WikiError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Contributor {
+    User { id: u64, username: String },
+    Ip(String),
+    Deleted,
 }
 
-pub type WikiResult<R> = Result<R, WikiError>;
+#[derive(Debug, Clone, Default)]
+pub struct Revision {
+    pub id: u64,
+    pub parent_id: Option<u64>,
+    pub timestamp: String,
+    pub contributor: Option<Contributor>,
+    pub minor: bool,
+    pub comment: Option<String>,
+    pub model: Option<String>,
+    pub format: Option<String>,
+    pub sha1: Option<String>,
+    pub text: Option<String>,
+}
 
-#[derive(Debug)]
+/// A page as found in an XML dump. Articles dumps have exactly one
+/// revision per page, history dumps all of them, oldest first.
+#[derive(Debug, Clone, Default)]
 pub struct Page {
-    title: String,
-    text: String,
+    pub id: u64,
+    pub ns: i32,
+    pub title: String,
+    pub redirect: Option<String>,
+    pub revisions: Vec<Revision>,
+}
+
+impl Page {
+    pub fn latest_revision(&self) -> Option<&Revision> {
+        self.revisions.last()
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        self.latest_revision().and_then(|r| r.text.as_ref()).map(|t| &**t)
+    }
+}
+
+/// Counts bytes going through, so errors can point somewhere in the
+/// (uncompressed) dump.
+struct CountingRead<R: Read> {
+    inner: R,
+    count: Arc<AtomicUsize>,
+}
+
+impl<R: Read> Read for CountingRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let read = try!(self.inner.read(buf));
+        self.count.fetch_add(read, Ordering::Relaxed);
+        Ok(read)
+    }
 }
 
+/// Streaming reader over the pages of an XML dump, as produced by `bzcat`.
+/// After an error is yielded, the iteration stops.
 pub struct PagesFromXml<R: Read> {
-    parser: Events<R>,
+    parser: EventReader<CountingRead<R>>,
+    offset: Arc<AtomicUsize>,
+    done: bool,
 }
 
-impl<R: Read> Iterator for PagesFromXml<R> {
-    type Item = WikiResult<Page>;
+impl<R: Read> PagesFromXml<R> {
+    /// Bytes consumed from the input so far.
+    pub fn offset(&self) -> usize {
+        self.offset.load(Ordering::Relaxed)
+    }
 
-    fn next(&mut self) -> Option<WikiResult<Page>> {
-        #[derive(PartialEq)]
-        enum State {
-            Nowhere,
-            InTitle,
-            InText,
-        };
-        let mut state: State = State::Nowhere;
-        let mut page = Page {
-            title: String::new(),
-            text: String::new(),
-        };
-        while let Some(ref e) = self.parser.next() {
-            match e {
-                &Ok(XmlEvent::StartElement { ref name, .. }) if state == State::Nowhere &&
-                                                                name.local_name == "title" => {
-                    state = State::InTitle;
-                }
-                &Ok(XmlEvent::StartElement { ref name, .. }) if state == State::Nowhere &&
-                                                                name.local_name == "text" => {
-                    state = State::InText;
-                }
-                &Ok(XmlEvent::EndElement { ref name }) if state == State::InTitle &&
-                                                          name.local_name == "title" => {
-                    state = State::Nowhere;
+    fn event(&mut self) -> WikiResult<XmlEvent> {
+        match self.parser.next() {
+            Ok(XmlEvent::EndDocument) => {
+                Err(format!("unexpected end of document at byte {}", self.offset()))?
+            }
+            Ok(e) => Ok(e),
+            Err(e) => {
+                Err(format!("XML error at byte {} (line {}): {}",
+                            self.offset(),
+                            self.parser.position().row + 1,
+                            e))?
+            }
+        }
+    }
+
+    fn string(&mut self) -> WikiResult<String> {
+        let mut text = String::new();
+        loop {
+            match try!(self.event()) {
+                XmlEvent::Characters(content) => text.push_str(&*content),
+                XmlEvent::Whitespace(content) => text.push_str(&*content),
+                XmlEvent::CData(content) => text.push_str(&*content),
+                XmlEvent::EndElement { .. } => return Ok(text),
+                _ => (),
+            }
+        }
+    }
+
+    fn number<N: ::std::str::FromStr>(&mut self, what: &str) -> WikiResult<N> {
+        let s = try!(self.string());
+        s.parse()
+            .map_err(|_| format!("can not parse int ({}) at byte {}", what, self.offset()).into())
+    }
+
+    fn page(&mut self) -> WikiResult<Page> {
+        let mut page = Page::default();
+        loop {
+            match try!(self.event()) {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    match &*name.local_name {
+                        "title" => page.title = try!(self.string()),
+                        "ns" => page.ns = try!(self.number("ns")),
+                        "id" => page.id = try!(self.number("id")),
+                        "redirect" => {
+                            page.redirect = attributes
+                                .into_iter()
+                                .find(|a| a.name.local_name == "title")
+                                .map(|a| a.value)
+                        }
+                        "revision" => page.revisions.push(try!(self.revision())),
+                        _ => (),
+                    }
                 }
-                &Ok(XmlEvent::EndElement { ref name }) if state == State::InText &&
-                                                          name.local_name == "text" => {
-                    state = State::Nowhere;
+                XmlEvent::EndElement { ref name } if name.local_name == "page" => return Ok(page),
+                _ => (),
+            }
+        }
+    }
+
+    fn revision(&mut self) -> WikiResult<Revision> {
+        let mut revision = Revision::default();
+        loop {
+            match try!(self.event()) {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    match &*name.local_name {
+                        "id" => revision.id = try!(self.number("revision id")),
+                        "parentid" => revision.parent_id = Some(try!(self.number("parentid"))),
+                        "timestamp" => revision.timestamp = try!(self.string()),
+                        "contributor" => {
+                            revision.contributor = if attributes
+                                   .iter()
+                                   .any(|a| a.name.local_name == "deleted") {
+                                Some(Contributor::Deleted)
+                            } else {
+                                try!(self.contributor())
+                            }
+                        }
+                        "minor" => revision.minor = true,
+                        "comment" => revision.comment = Some(try!(self.string())),
+                        "model" => revision.model = Some(try!(self.string())),
+                        "format" => revision.format = Some(try!(self.string())),
+                        "sha1" => revision.sha1 = Some(try!(self.string())),
+                        "text" => revision.text = Some(try!(self.string())),
+                        _ => (),
+                    }
                 }
-                &Ok(XmlEvent::EndElement { ref name }) if name.local_name == "page" => {
-                    return Some(Ok(page))
+                XmlEvent::EndElement { ref name } if name.local_name == "revision" => {
+                    return Ok(revision)
                 }
-                &Ok(XmlEvent::Characters(ref content)) if state == State::InTitle => {
-                    page.title.push_str(&*content)
+                _ => (),
+            }
+        }
+    }
+
+    fn contributor(&mut self) -> WikiResult<Option<Contributor>> {
+        let mut username = None;
+        let mut id = 0;
+        let mut ip = None;
+        loop {
+            match try!(self.event()) {
+                XmlEvent::StartElement { name, .. } => {
+                    match &*name.local_name {
+                        "username" => username = Some(try!(self.string())),
+                        "id" => id = try!(self.number("contributor id")),
+                        "ip" => ip = Some(try!(self.string())),
+                        _ => (),
+                    }
                 }
-                &Ok(XmlEvent::Characters(ref content)) if state == State::InText => {
-                    page.text.push_str(&*content)
+                XmlEvent::EndElement { ref name } if name.local_name == "contributor" => {
+                    return Ok(match (username, ip) {
+                                  (Some(username), _) => {
+                                      Some(Contributor::User {
+                                               id: id,
+                                               username: username,
+                                           })
+                                  }
+                                  (None, Some(ip)) => Some(Contributor::Ip(ip)),
+                                  (None, None) => None,
+                              })
                 }
-                &Ok(XmlEvent::Whitespace(ref content)) if state == State::InTitle => {
-                    page.title.push_str(&*content)
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for PagesFromXml<R> {
+    type Item = WikiResult<Page>;
+
+    fn next(&mut self) -> Option<WikiResult<Page>> {
+        if self.done {
+            return None;
+        }
+        loop {
+            match self.parser.next() {
+                Ok(XmlEvent::StartElement { ref name, .. }) if name.local_name == "page" => {
+                    let page = self.page();
+                    self.done = page.is_err();
+                    return Some(page);
                 }
-                &Ok(XmlEvent::Whitespace(ref content)) if state == State::InText => {
-                    page.text.push_str(&*content)
+                Ok(XmlEvent::EndDocument) => {
+                    self.done = true;
+                    return None;
                 }
-                &Err(ref e) => {
-                    println!("Error: {}", e);
-                    break;
+                Ok(_) => (),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(format!("XML error at byte {} (line {}): {}",
+                                            self.offset(),
+                                            self.parser.position().row + 1,
+                                            e)
+                                            .into()));
                 }
-                _ => {}
             }
         }
-        None
     }
 }
 
 pub fn pages_from_xml<R: Read>(read: R) -> WikiResult<PagesFromXml<R>> {
-    let parser = EventReader::new(read);
-    Ok(PagesFromXml { parser: parser.into_iter() })
+    let offset = Arc::new(AtomicUsize::new(0));
+    let counting = CountingRead {
+        inner: read,
+        count: offset.clone(),
+    };
+    Ok(PagesFromXml {
+           parser: EventReader::new(counting),
+           offset: offset,
+           done: false,
+       })
 }
 
 pub struct ReadChain<T: Read> {
@@ -129,22 +311,28 @@ impl<T: Read> Read for ReadChain<T> {
     }
 }
 
-pub fn bzcat(lang: &str, date: &str) -> Result<ReadChain<BzDecoder<fs::File>>, WikiError> {
-    let glob = data_dir_for("download", lang, date) + "/*.bz2";
-    let decompressors: Result<Vec<BzDecoder<fs::File>>, WikiError> = try!(::glob::glob(&glob))
-        .map(|entry| {
-                 let file = try!(fs::File::open(try!(entry)));
-                 Ok(BzDecoder::new(file))
-             })
-        .collect();
-    let decompressors = try!(decompressors);
+/// The downloaded articles dump, decompressed. Of the plain and multistream
+/// flavours, only one is read (see `multistream::one_flavour`); other dumps
+/// of the date (multistream index, full history) are left out.
+pub fn bzcat(store: &DataStore,
+             lang: &str,
+             date: &str)
+             -> Result<ReadChain<MultiBzDecoder<fs::File>>, WikiError> {
+    let glob = store.dir(Stage::Download, lang, date) + "/*pages-articles*.xml*.bz2";
+    let found: Vec<path::PathBuf> = try!(try!(::glob::glob(&glob)).collect());
+    let mut decompressors = vec![];
+    for dump in multistream::one_flavour(found) {
+        // multistream dumps are many bzip2 streams in a row
+        decompressors.push(MultiBzDecoder::new(try!(fs::File::open(dump))));
+    }
     Ok(ReadChain::new(decompressors))
 }
 
-pub fn snappycat(lang: &str,
+pub fn snappycat(store: &DataStore,
+                 lang: &str,
                  date: &str)
                  -> Result<ReadChain<SnappyFramedDecoder<fs::File>>, WikiError> {
-    let glob = data_dir_for("snappy", lang, date) + "/*.sz";
+    let glob = store.dir(Stage::Snappy, lang, date) + "/*.sz";
     let decompressors: Result<Vec<SnappyFramedDecoder<fs::File>>, WikiError> =
         try!(::glob::glob(&glob))
             .map(|entry| {
@@ -155,3 +343,19 @@ pub fn snappycat(lang: &str,
     let decompressors = try!(decompressors);
     Ok(ReadChain::new(decompressors))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha1_in_base36() {
+        assert_eq!(sha1_base36(b""), "phoiac9h4m842xq45sp7s6u21eteeq1");
+        // zero-padded to 31 digits
+        assert_eq!(sha1_base36(b"17"), "0tt80woaa11w8brcde626s7nrqra0yk");
+        assert_eq!(sha1_base36(b"#REDIRECT [[Albert Einstein]]"),
+                   "2909q6owlhq2w7vmk1lfqbur72n8361");
+        assert_eq!(sha1_base36("'''Zürich''' is the largest city in Switzerland.".as_bytes()),
+                   "mj88i4c2o0cd8mwjfhryke7ewfduc1z");
+    }
+}
@@ -1,4 +1,5 @@
 extern crate glob;
+extern crate regex;
 extern crate bzip2;
 #[macro_use]
 extern crate error_chain;
@@ -6,9 +7,15 @@ extern crate flate2;
 extern crate xml;
 extern crate snappy_framed;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate bincode;
 extern crate serde_json;
 extern crate num_cpus;
-extern crate simple_parallel;
+extern crate rayon;
+#[macro_use]
+extern crate lazy_static;
+extern crate clap;
 
 extern crate capnp;
 
@@ -17,6 +24,9 @@ extern crate url_aggregator;
 
 extern crate tinycdb;
 extern crate itertools;
+extern crate sha1;
+#[cfg(feature = "parquet")]
+extern crate parquet;
 
 pub mod helpers;
 pub mod wiki;
@@ -24,6 +34,21 @@ pub mod wikidata;
 pub mod capitanize_wikidata;
 pub mod capitanize_wiki;
 pub mod mapred;
+pub mod aggregate;
+pub mod shuffle;
+pub mod checkpoint;
+pub mod categories;
+pub mod multistream;
+pub mod blocks;
+pub mod tabular;
+pub mod rdf;
+pub mod cli;
+pub mod store;
+pub mod pipeline;
+pub mod gc;
+pub mod wikidata_incr;
+#[cfg(feature = "parquet")]
+pub mod wikidata_parquet;
 
 error_chain! {
     types { WikiError, WikiErrorKind, WikiErrorExt, WikiResult; }
@@ -34,6 +59,9 @@ error_chain! {
         Capnp(::capnp::Error);
         CapnpNotInSchema(::capnp::NotInSchema);
         Serde(::serde_json::Error);
+        Bincode(::bincode::Error);
+        #[cfg(feature = "parquet")]
+        Parquet(::parquet::errors::ParquetError);
     }
 }
 
@@ -1,80 +1,468 @@
+use std::env;
+use std::io;
+use std::io::prelude::*;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
+use std::hash::Hash;
+use std::path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
 
-use simple_parallel::pool::Pool;
+use rayon;
+use rayon::prelude::*;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use {WikiError, WikiErrorExt, WikiResult};
+use aggregate;
+use checkpoint::Checkpoint;
+use shuffle;
 
 pub type BI<'a, A> = Box<Iterator<Item = A> + Send + 'a>;
 
+/// Environment variable setting the worker thread count.
+pub const THREADS_VAR: &'static str = "WOLFRAMITE_THREADS";
+
+static THREADS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    static ref POOL: rayon::ThreadPool = {
+        let threads = match THREADS.load(Ordering::SeqCst) {
+            0 => {
+                env::var(THREADS_VAR)
+                    .ok()
+                    .and_then(|t| t.parse().ok())
+                    .unwrap_or(::num_cpus::get())
+            }
+            n => n,
+        };
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("mapred-{}", i))
+            .build()
+            .expect("failed to start the worker threads")
+    };
+}
+
+/// Set the worker thread count (default: `$WOLFRAMITE_THREADS`, or one per
+/// core). The pool is started on first use, so this must come before.
+pub fn set_threads(threads: usize) {
+    THREADS.store(threads, Ordering::SeqCst);
+}
+
+/// The worker pool, shared by all the operations of the process.
+pub fn pool() -> &'static rayon::ThreadPool {
+    &*POOL
+}
+
+/// Run `each` on every chunk, chunks being spread over the pool.
+fn for_chunks<'a, 'b, A, T, F>(chunks: BI<'a, BI<'b, A>>, each: F) -> Vec<T>
+    where A: Send,
+          T: Send,
+          F: Sync + Send + Fn(BI<'b, A>) -> T
+{
+    let chunks: Vec<BI<'b, A>> = chunks.collect();
+    pool().install(|| chunks.into_par_iter().map(each).collect())
+}
+
+/// What to do when a record fails.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPolicy {
+    /// stop at the first error, and return it
+    FailFast,
+    /// ignore failed records, only counting them
+    Skip,
+    /// keep going, reporting the first `n` errors; more is a failure
+    Collect(usize),
+}
+
+/// Records that can tell which entity or page they are, for error reports.
+pub trait Record {
+    fn record_id(&self) -> Option<String>;
+}
+
+#[derive(Debug)]
+pub struct RecordError {
+    pub id: Option<String>,
+    pub error: WikiError,
+}
+
+/// Outcome of a fallible parallel run.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub records: usize,
+    pub failed: usize,
+    /// kept with `ErrorPolicy::Collect`
+    pub errors: Vec<RecordError>,
+}
+
+/// Periodic records/s and chunks done report on stderr.
+pub struct Progress {
+    label: String,
+    chunks: usize,
+    chunks_done: AtomicUsize,
+    records: AtomicUsize,
+    start: Instant,
+    last: Mutex<Instant>,
+}
+
+impl Progress {
+    pub fn new(label: &str, chunks: usize) -> Progress {
+        Progress {
+            label: label.to_string(),
+            chunks: chunks,
+            chunks_done: AtomicUsize::new(0),
+            records: AtomicUsize::new(0),
+            start: Instant::now(),
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn record(&self) {
+        let records = self.records.fetch_add(1, Ordering::Relaxed) + 1;
+        if records % 1000 == 0 {
+            if let Ok(mut last) = self.last.try_lock() {
+                if last.elapsed() >= Duration::from_secs(10) {
+                    *last = Instant::now();
+                    self.report();
+                }
+            }
+        }
+    }
+
+    pub fn chunk_done(&self) {
+        self.chunks_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) {
+        let records = self.records.load(Ordering::Relaxed);
+        let elapsed = self.start.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let _ = writeln!(io::stderr(),
+                         "{}: {} records ({:.0}/s), {}/{} files",
+                         self.label,
+                         records,
+                         records as f64 / secs.max(0.001),
+                         self.chunks_done.load(Ordering::Relaxed),
+                         self.chunks);
+    }
+}
+
+/// Chunks numbered in their input order.
+fn indexed<'a, 'b, A>(chunks: BI<'a, BI<'b, A>>) -> Vec<(usize, BI<'b, A>)> {
+    chunks.enumerate().collect()
+}
+
+/// Run `step` on every record, spread over the pool by chunk, each chunk
+/// folding into its own state. At the end of a chunk, `finish` gets its
+/// number, its state, and whether it went through (the run was not
+/// aborted).
+fn for_records<'b, A, S, T, I, F, E>(chunks: Vec<(usize, BI<'b, A>)>,
+                                     policy: ErrorPolicy,
+                                     label: Option<&str>,
+                                     init: I,
+                                     step: F,
+                                     finish: E)
+                                     -> WikiResult<(Vec<T>, Report)>
+    where A: Send + Record,
+          T: Send,
+          I: Sync + Send + Fn() -> S,
+          F: Sync + Send + Fn(&mut S, A) -> WikiResult<()>,
+          E: Sync + Send + Fn(usize, S, bool) -> T
+{
+    let progress = label.map(|l| Progress::new(l, chunks.len()));
+    let abort = AtomicBool::new(false);
+    let records = AtomicUsize::new(0);
+    let report = Mutex::new(Report::default());
+    let each = |(chunk, it): (usize, BI<'b, A>)| -> T {
+        let mut state = init();
+        for record in it {
+            if abort.load(Ordering::Relaxed) {
+                break;
+            }
+            let id = if policy == ErrorPolicy::Skip { None } else { record.record_id() };
+            let done = step(&mut state, record);
+            records.fetch_add(1, Ordering::Relaxed);
+            if let Some(ref progress) = progress {
+                progress.record();
+            }
+            if let Err(e) = done {
+                let mut report = report.lock().unwrap();
+                report.failed += 1;
+                match policy {
+                    ErrorPolicy::Skip => (),
+                    ErrorPolicy::Collect(n) if report.errors.len() < n => {
+                        report.errors.push(RecordError { id: id, error: e })
+                    }
+                    _ => {
+                        if !abort.swap(true, Ordering::SeqCst) {
+                            report.errors.push(RecordError { id: id, error: e });
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(ref progress) = progress {
+            progress.chunk_done();
+        }
+        finish(chunk, state, !abort.load(Ordering::SeqCst))
+    };
+    let states = pool().install(|| chunks.into_par_iter().map(each).collect());
+    if let Some(ref progress) = progress {
+        progress.report();
+    }
+    let mut report = report.into_inner().unwrap();
+    report.records = records.into_inner();
+    if abort.into_inner() {
+        let RecordError { id, error } = report.errors.pop().unwrap();
+        let what = match policy {
+            ErrorPolicy::Collect(n) => format!("more than {} failed records, the last one", n),
+            _ => "record".to_string(),
+        };
+        return Err(error).chain_err(|| match id {
+                                              Some(id) => format!("{} {} failed", what, id),
+                                              None => format!("{} failed", what),
+                                          });
+    }
+    Ok((states, report))
+}
+
+/// Fold a (key, value) pair into aggregates.
+fn combine<K, V, R>(aggregates: &mut HashMap<K, V>, k: K, v: V, reducer: &R)
+    where K: Eq + Hash,
+          R: Fn(&V, &V) -> V
+{
+    match aggregates.entry(k) {
+        Entry::Occupied(prev) => {
+            let next = reducer(prev.get(), &v);
+            *(prev.into_mut()) = next;
+        }
+        Entry::Vacant(vac) => {
+            vac.insert(v);
+        }
+    };
+}
+
 pub struct MapReduceOp<'a, M, R, A, K, V>
-    where M: Sync + Fn(A) -> BI<'a, (K, V)>,
+    where M: Sync + Fn(A) -> WikiResult<BI<'a, (K, V)>>,
           R: Sync + Fn(&V, &V) -> V,
-          A: Send,
+          A: Send + Record,
           K: Send + Eq + ::std::hash::Hash + Clone,
           V: Clone + Send
 {
     mapper: M,
     reducer: R,
+    policy: ErrorPolicy,
+    progress: Option<String>,
+    partitions: usize,
+    spill_keys: usize,
     _phantom: ::std::marker::PhantomData<A>,
     _phantom_2: ::std::marker::PhantomData<&'a usize>,
 }
 
 impl<'a, M, R, A, K, V> MapReduceOp<'a, M, R, A, K, V>
-    where M: Sync + Fn(A) -> BI<'a, (K, V)>,
+    where M: Sync + Fn(A) -> WikiResult<BI<'a, (K, V)>>,
           R: Sync + Fn(&V, &V) -> V,
-          A: Send,
-          K: Send + Eq + ::std::hash::Hash + Clone,
-          V: Clone + Send
+          A: Send + Record,
+          K: Send + Sync + Eq + ::std::hash::Hash + Clone,
+          V: Clone + Send + Sync
 {
-    pub fn run(&self, chunks: BI<BI<A>>) -> HashMap<K, V> {
+    /// Map and reduce each chunk, handing its aggregates to `finish`.
+    fn fold<'b, T, E>(&self,
+                      chunks: Vec<(usize, BI<'b, A>)>,
+                      finish: E)
+                      -> WikiResult<(Vec<T>, Report)>
+        where T: Send,
+              E: Sync + Send + Fn(usize, HashMap<K, V>, bool) -> T
+    {
         let reducer = &self.reducer;
         let mapper = &self.mapper;
-        let each = |it: BI<A>| -> HashMap<K, V> {
-            let mut aggregates: HashMap<K, V> = HashMap::new();
-            for (k, v) in it.flat_map(|e| mapper(e)) {
-                let val = aggregates.entry(k.clone());
-                match val {
-                    Entry::Occupied(prev) => {
-                        let next = reducer(prev.get(), &v);
-                        *(prev.into_mut()) = next;
-                    }
-                    Entry::Vacant(vac) => {
-                        vac.insert(v);
-                    }
-                };
+        let step = |aggregates: &mut HashMap<K, V>, record: A| -> WikiResult<()> {
+            for (k, v) in try!(mapper(record)) {
+                combine(aggregates, k, v, reducer);
             }
-            aggregates
+            Ok(())
         };
-        let mut pool = Pool::new(1 + ::num_cpus::get());
-        let halfway: Vec<HashMap<K, V>> = unsafe { pool.map(chunks, &each).collect() };
+        for_records(chunks,
+                    self.policy,
+                    self.progress.as_ref().map(|s| &**s),
+                    HashMap::new,
+                    step,
+                    finish)
+    }
+
+    /// Reduce the aggregates of the chunks together.
+    fn merge_all(&self, halfway: Vec<HashMap<K, V>>) -> HashMap<K, V> {
         let mut result: HashMap<K, V> = HashMap::new();
-        for h in halfway.iter() {
-            for (k, v) in h.iter() {
-                let val = result.entry(k.clone());
-                match val {
-                    Entry::Occupied(prev) => {
-                        let next = reducer(prev.get(), v);
-                        *(prev.into_mut()) = next.clone();
-                    }
-                    Entry::Vacant(vac) => {
-                        vac.insert(v.clone());
-                    }
-                };
+        for h in halfway {
+            for (k, v) in h {
+                combine(&mut result, k, v, &self.reducer);
             }
         }
         result
     }
 
+    pub fn run(&self, chunks: BI<BI<A>>) -> WikiResult<(HashMap<K, V>, Report)> {
+        let (halfway, report) = try!(self.fold(indexed(chunks), |_, state, _| state));
+        Ok((self.merge_all(halfway), report))
+    }
+
+    /// `run`, with the result sorted by key.
+    pub fn run_sorted(&self, chunks: BI<BI<A>>) -> WikiResult<(Vec<(K, V)>, Report)>
+        where K: Ord
+    {
+        let (result, report) = try!(self.run(chunks));
+        let mut sorted: Vec<(K, V)> = result.into_iter().collect();
+        sorted.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok((sorted, report))
+    }
+
+    /// `run`, keeping only the `k` keys of highest value, highest first.
+    pub fn run_top(&self, chunks: BI<BI<A>>, k: usize) -> WikiResult<(Vec<(K, V)>, Report)>
+        where V: Ord
+    {
+        let (result, report) = try!(self.run(chunks));
+        Ok((aggregate::top_k(result, k), report))
+    }
+
     pub fn new_map_reduce(map: M, reduce: R) -> MapReduceOp<'a, M, R, A, K, V> {
         MapReduceOp {
             mapper: map,
             reducer: reduce,
+            policy: ErrorPolicy::FailFast,
+            progress: None,
+            partitions: shuffle::PARTITIONS,
+            spill_keys: shuffle::SPILL_KEYS,
             _phantom: ::std::marker::PhantomData,
             _phantom_2: ::std::marker::PhantomData,
         }
     }
 
-    pub fn map_reduce(map: M, reduce: R, chunks: BI<BI<A>>) -> HashMap<K, V> {
-        MapReduceOp::new_map_reduce(map, reduce).run(chunks)
+    pub fn with_policy(mut self, policy: ErrorPolicy) -> MapReduceOp<'a, M, R, A, K, V> {
+        self.policy = policy;
+        self
+    }
+
+    /// Report progress on stderr, lines starting with `label`.
+    pub fn with_progress(mut self, label: &str) -> MapReduceOp<'a, M, R, A, K, V> {
+        self.progress = Some(label.to_string());
+        self
+    }
+
+    /// Partition count and keys aggregated in memory by each chunk before
+    /// spilling, for `run_external`.
+    pub fn with_spill(mut self,
+                      partitions: usize,
+                      spill_keys: usize)
+                      -> MapReduceOp<'a, M, R, A, K, V> {
+        self.partitions = partitions;
+        self.spill_keys = spill_keys;
+        self
+    }
+
+    pub fn map_reduce(map: M, reduce: R, chunks: BI<BI<A>>) -> WikiResult<HashMap<K, V>> {
+        Ok(try!(MapReduceOp::new_map_reduce(map, reduce).run(chunks)).0)
+    }
+}
+
+impl<'a, M, R, A, K, V> MapReduceOp<'a, M, R, A, K, V>
+    where M: Sync + Fn(A) -> WikiResult<BI<'a, (K, V)>>,
+          R: Sync + Fn(&V, &V) -> V,
+          A: Send + Record,
+          K: Send + Sync + Eq + Hash + Clone + Serialize + DeserializeOwned,
+          V: Clone + Send + Sync + Serialize + DeserializeOwned
+{
+    /// `run`, saving the aggregates of each chunk in `checkpoint`, and
+    /// reusing the ones saved by a previous run of the job instead of
+    /// processing their chunks again. A chunk having failed records under
+    /// the `Skip` or `Collect` policies is saved too, and the report only
+    /// covers the chunks processed by this run.
+    pub fn run_checkpointed(&self,
+                            chunks: BI<BI<A>>,
+                            checkpoint: &Checkpoint)
+                            -> WikiResult<(HashMap<K, V>, Report)> {
+        let chunks = indexed(chunks);
+        try!(checkpoint.expect_chunks(chunks.len()));
+        let (done, todo): (Vec<_>, Vec<_>) =
+            chunks.into_iter().partition(|&(chunk, _)| checkpoint.is_done(chunk));
+        let mut halfway: Vec<HashMap<K, V>> = vec![];
+        for (chunk, _) in done {
+            halfway.push(try!(checkpoint.load(chunk)));
+        }
+        let save = |chunk: usize,
+                    state: HashMap<K, V>,
+                    complete: bool|
+                    -> WikiResult<HashMap<K, V>> {
+            if complete {
+                try!(checkpoint.save(chunk, &state));
+            }
+            Ok(state)
+        };
+        let (states, report) = try!(self.fold(todo, save));
+        for state in states {
+            halfway.push(try!(state));
+        }
+        Ok((self.merge_all(halfway), report))
+    }
+}
+
+impl<'a, M, R, A, K, V> MapReduceOp<'a, M, R, A, K, V>
+    where M: Sync + Fn(A) -> WikiResult<BI<'a, (K, V)>>,
+          R: Sync + Fn(&V, &V) -> V,
+          A: Send + Record,
+          K: Send + Sync + Ord + Hash + Clone + Serialize + DeserializeOwned,
+          V: Clone + Send + Sync + Serialize + DeserializeOwned
+{
+    /// `run` for key spaces larger than memory. Chunks spill their
+    /// aggregates to sorted runs in a directory under `dir`, then the result
+    /// streams from a merge of the runs: sorted by key within each
+    /// partition, partitions coming one after the other. The runs are
+    /// removed when the result is dropped.
+    pub fn run_external(&self,
+                        chunks: BI<BI<A>>,
+                        dir: &path::Path)
+                        -> WikiResult<(shuffle::Merged<K, V, R>, Report)> {
+        let spill = try!(shuffle::Spill::new(dir, self.partitions));
+        let (failures, report) = {
+            let reducer = &self.reducer;
+            let mapper = &self.mapper;
+            let spill_keys = self.spill_keys;
+            // a failed spill stops its chunk, but is not a record failure
+            let step = |state: &mut (HashMap<K, V>, Option<WikiError>),
+                        record: A|
+                        -> WikiResult<()> {
+                if state.1.is_some() {
+                    return Ok(());
+                }
+                for (k, v) in try!(mapper(record)) {
+                    combine(&mut state.0, k, v, reducer);
+                }
+                if state.0.len() >= spill_keys {
+                    if let Err(e) = spill.spill(&mut state.0) {
+                        state.1 = Some(e);
+                    }
+                }
+                Ok(())
+            };
+            let finish = |_: usize,
+                          state: (HashMap<K, V>, Option<WikiError>),
+                          _: bool|
+                          -> Option<WikiError> {
+                let (mut aggregates, failed) = state;
+                failed.or_else(|| spill.spill(&mut aggregates).err())
+            };
+            try!(for_records(indexed(chunks),
+                             self.policy,
+                             self.progress.as_ref().map(|s| &**s),
+                             || (HashMap::new(), None),
+                             step,
+                             finish))
+        };
+        if let Some(e) = failures.into_iter().filter_map(|f| f).next() {
+            return Err(e).chain_err(|| format!("spilling to {:?} failed", dir));
+        }
+        Ok((spill.merge(&self.reducer), report))
     }
 }
 
@@ -82,9 +470,97 @@ pub fn par_foreach<A, F>(chunks: BI<BI<A>>, func: &F)
     where A: Send,
           F: Sync + Fn(A) -> ()
 {
+    let each = |it: BI<A>| -> () { it.map(|e| func(e)).count(); };
+    for_chunks(chunks, each);
+}
+
+/// `par_foreach` for fallible functions.
+pub fn par_try_foreach<A, F>(chunks: BI<BI<A>>,
+                             policy: ErrorPolicy,
+                             progress: Option<&str>,
+                             func: &F)
+                             -> WikiResult<Report>
+    where A: Send + Record,
+          F: Sync + Fn(A) -> WikiResult<()>
+{
+    let step = |_: &mut (), record: A| func(record);
+    Ok(try!(for_records(indexed(chunks), policy, progress, || (), step, |_, _, _| ())).1)
+}
+
+/// `par_try_foreach`, marking each chunk done in `checkpoint`, and skipping
+/// the chunks a previous run of the job went through. A chunk interrupted
+/// by a crash is processed again from its start, so `func` should cope with
+/// seeing records twice (writing one output file per chunk, for instance).
+pub fn par_try_foreach_checkpointed<A, F>(chunks: BI<BI<A>>,
+                                          policy: ErrorPolicy,
+                                          progress: Option<&str>,
+                                          checkpoint: &Checkpoint,
+                                          func: &F)
+                                          -> WikiResult<Report>
+    where A: Send + Record,
+          F: Sync + Fn(A) -> WikiResult<()>
+{
+    let chunks = indexed(chunks);
+    try!(checkpoint.expect_chunks(chunks.len()));
+    let todo = chunks.into_iter().filter(|&(chunk, _)| !checkpoint.is_done(chunk)).collect();
+    let step = |_: &mut (), record: A| func(record);
+    let done = |chunk: usize, _: (), complete: bool| -> WikiResult<()> {
+        if complete {
+            try!(checkpoint.save(chunk, &()));
+        }
+        Ok(())
+    };
+    let (saved, report) = try!(for_records(todo, policy, progress, || (), step, done));
+    for result in saved {
+        try!(result);
+    }
+    Ok(report)
+}
+
+/// Run fallible jobs on the pool, stopping at the first error.
+pub fn par_try_map<J, T, F>(jobs: Vec<J>, func: F) -> WikiResult<Vec<T>>
+    where J: Send,
+          T: Send,
+          F: Sync + Send + Fn(J) -> WikiResult<T>
+{
+    pool().install(|| jobs.into_par_iter().map(func).collect())
+}
 
-    let mapper = &func;
-    let each = |it: BI<A>| -> () { it.map(|e| mapper(e)).count(); };
-    let mut pool = Pool::new(1 + ::num_cpus::get());
-    let _: Vec<()> = unsafe { pool.map(chunks, &each).collect() };
+/// `par_try_map`, starting the jobs in their order: the workers take them
+/// one at a time from a shared queue, so that jobs sorted largest first do
+/// start first. Results come in the order of the jobs.
+pub fn par_try_map_queued<J, T, F>(jobs: Vec<J>, func: F) -> WikiResult<Vec<T>>
+    where J: Send,
+          T: Send,
+          F: Sync + Send + Fn(J) -> WikiResult<T>
+{
+    let workers = ::std::cmp::min(pool().current_num_threads(), jobs.len());
+    let queue = Mutex::new(jobs.into_iter().enumerate());
+    let failed = AtomicBool::new(false);
+    let work = |_| -> WikiResult<Vec<(usize, T)>> {
+        let mut done = vec![];
+        while !failed.load(Ordering::Relaxed) {
+            let next = queue.lock().unwrap().next();
+            let (i, job) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            match func(job) {
+                Ok(result) => done.push((i, result)),
+                Err(e) => {
+                    failed.store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(done)
+    };
+    let per_worker: Vec<WikiResult<Vec<(usize, T)>>> =
+        pool().install(|| (0..workers).into_par_iter().map(work).collect());
+    let mut results = vec![];
+    for done in per_worker {
+        results.extend(try!(done));
+    }
+    results.sort_by_key(|&(i, _)| i);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
 }
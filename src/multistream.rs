@@ -0,0 +1,339 @@
+//! Multistream dumps (`pages-articles-multistream.xml.bz2`) are a
+//! concatenation of independent bzip2 streams of about 100 pages each. The
+//! companion index (`pages-articles-multistream-index.txt.bz2`) gives, for
+//! every page, the offset of the stream holding it: `offset:page_id:title`.
+//! The first stream is the dump header (siteinfo), the last one the footer.
+
+use std::io;
+use std::io::prelude::*;
+use std::fs;
+use std::path;
+use std::sync::Mutex;
+
+use bzip2::read::BzDecoder;
+use tinycdb::{Cdb, CdbCreator};
+
+use {WikiResult, WikiError};
+use store::{DataStore, Stage};
+use helpers::ReadChain;
+use capitanize_wiki;
+use wiki::MessageAndPage;
+
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub offset: u64,
+    pub page_id: u64,
+    pub title: String,
+}
+
+/// Index file path for a multistream dump file
+/// ("...-multistream1.xml-p1p41242.bz2" -> "...-multistream-index1.txt-p1p41242.bz2").
+pub fn index_path_for(dump: &path::Path) -> path::PathBuf {
+    let name = dump.file_name().unwrap().to_str().unwrap();
+    let name = name.replace("multistream", "multistream-index").replace(".xml", ".txt");
+    dump.with_file_name(name)
+}
+
+pub fn is_multistream(dump: &path::Path) -> bool {
+    dump.to_str().map(|s| s.contains("multistream")).unwrap_or(false)
+}
+
+pub fn is_index(path: &path::Path) -> bool {
+    path.to_str().map(|s| s.contains("multistream-index")).unwrap_or(false)
+}
+
+/// The articles dumps to read among the downloaded files, stream indexes
+/// left out. The multistream and plain dumps hold the same pages: when both
+/// were downloaded, only the multistream ones are kept.
+pub fn one_flavour(files: Vec<path::PathBuf>) -> Vec<path::PathBuf> {
+    let mut dumps: Vec<path::PathBuf> = files.into_iter().filter(|f| !is_index(&*f)).collect();
+    if dumps.iter().any(|d| is_multistream(&*d)) {
+        dumps.retain(|d| is_multistream(&*d));
+    }
+    dumps
+}
+
+fn parse_index_line(index: &path::Path, line: &str) -> WikiResult<IndexEntry> {
+    let mut tokens = line.splitn(3, ':');
+    let offset = tokens.next().and_then(|s| s.parse().ok());
+    let page_id = tokens.next().and_then(|s| s.parse().ok());
+    match (offset, page_id, tokens.next()) {
+        (Some(offset), Some(page_id), Some(title)) => {
+            Ok(IndexEntry {
+                   offset: offset,
+                   page_id: page_id,
+                   title: title.to_string(),
+               })
+        }
+        _ => Err(format!("invalid index line in {:?}: {}", index, line))?,
+    }
+}
+
+/// Sorted, deduplicated stream offsets of an index, streamed: the titles
+/// are dropped as soon as read. The index lists the pages in the order of
+/// the streams, about a hundred pages per stream.
+pub fn stream_offsets(index: &path::Path) -> WikiResult<Vec<u64>> {
+    let input = io::BufReader::new(BzDecoder::new(try!(fs::File::open(index))));
+    let mut offsets: Vec<u64> = vec![];
+    for line in input.lines() {
+        let line = try!(line);
+        let offset = try!(parse_index_line(index, &*line)).offset;
+        if offsets.last() != Some(&offset) {
+            offsets.push(offset);
+        }
+    }
+    offsets.sort();
+    offsets.dedup();
+    Ok(offsets)
+}
+
+/// A run of consecutive bzip2 streams of a multistream dump, readable as a
+/// standalone XML document.
+#[derive(Debug, Clone)]
+pub struct StreamRange {
+    pub path: path::PathBuf,
+    /// (start, end) byte offsets of each stream.
+    pub streams: Vec<(u64, u64)>,
+    /// The header stream opens the `<mediawiki>` element by itself.
+    pub header: bool,
+    /// The last range ends with the footer stream, which closes it.
+    pub footer: bool,
+}
+
+impl StreamRange {
+    /// Compressed size of the range.
+    pub fn len(&self) -> u64 {
+        self.streams.iter().map(|&(start, end)| end - start).sum()
+    }
+
+    pub fn reader(&self) -> BoxedRead {
+        let mut parts: Vec<BoxedRead> = vec![];
+        if !self.header {
+            parts.push(Box::new(io::Cursor::new(&b"<mediawiki>"[..])));
+        }
+        parts.push(Box::new(StreamsReader::new(self.path.clone(), self.streams.clone())));
+        if !self.footer {
+            parts.push(Box::new(io::Cursor::new(&b"</mediawiki>"[..])));
+        }
+        Box::new(ReadChain::new(parts))
+    }
+}
+
+pub type BoxedRead = Box<Read + Send>;
+
+/// Split a multistream dump in `chunks` ranges of roughly the same
+/// compressed size, plus the header range.
+pub fn split(dump: &path::Path, chunks: usize) -> WikiResult<Vec<StreamRange>> {
+    let offsets = try!(stream_offsets(&*index_path_for(dump)));
+    if offsets.len() == 0 {
+        Err(format!("empty multistream index for {:?}", dump))?
+    }
+    let file_len = try!(fs::metadata(dump)).len();
+    let target = file_len / chunks as u64 + 1;
+    let mut ranges = vec![StreamRange {
+                              path: dump.to_path_buf(),
+                              streams: vec![(0, offsets[0])],
+                              header: true,
+                              footer: false,
+                          }];
+    let mut current = vec![];
+    let mut start = offsets[0];
+    for (i, &offset) in offsets.iter().enumerate() {
+        let end = offsets.get(i + 1).cloned().unwrap_or(file_len);
+        current.push((offset, end));
+        if end - start >= target {
+            ranges.push(StreamRange {
+                            path: dump.to_path_buf(),
+                            streams: current,
+                            header: false,
+                            footer: end == file_len,
+                        });
+            current = vec![];
+            start = end;
+        }
+    }
+    if current.len() > 0 {
+        ranges.push(StreamRange {
+                        path: dump.to_path_buf(),
+                        streams: current,
+                        header: false,
+                        footer: true,
+                    });
+    }
+    Ok(ranges)
+}
+
+/// Decompresses a list of bzip2 streams of a file, one after the other,
+/// opening them lazily.
+pub struct StreamsReader {
+    path: path::PathBuf,
+    streams: Vec<(u64, u64)>,
+    next: usize,
+    current: Option<BzDecoder<io::Take<fs::File>>>,
+}
+
+impl StreamsReader {
+    pub fn new(path: path::PathBuf, streams: Vec<(u64, u64)>) -> StreamsReader {
+        StreamsReader {
+            path: path,
+            streams: streams,
+            next: 0,
+            current: None,
+        }
+    }
+}
+
+impl Read for StreamsReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        loop {
+            if self.current.is_none() {
+                if self.next == self.streams.len() {
+                    return Ok(0);
+                }
+                let (start, end) = self.streams[self.next];
+                let mut file = try!(fs::File::open(&self.path));
+                try!(file.seek(io::SeekFrom::Start(start)));
+                self.current = Some(BzDecoder::new(file.take(end - start)));
+                self.next += 1;
+            }
+            let read = try!(self.current.as_mut().unwrap().read(buf));
+            if read > 0 {
+                return Ok(read);
+            }
+            self.current = None;
+        }
+    }
+}
+
+/// Path of the title lookup table built from a multistream index
+/// ("...-multistream-index1.txt-p1p41242.bz2" -> "...-multistream-index1.txt-p1p41242.cdb").
+pub fn titles_path_for(index: &path::Path) -> path::PathBuf {
+    index.with_extension("cdb")
+}
+
+/// Add the titles of a stream to a title lookup table.
+fn add_stream(titles: &mut CdbCreator,
+              stream: &mut Vec<String>,
+              start: u64,
+              end: u64)
+              -> WikiResult<()> {
+    let location = format!("{} {}", start, end);
+    for title in stream.drain(..) {
+        try!(titles.add(title.as_bytes(), location.as_bytes())
+                 .map_err(|e| format!("Cdb Error: {:?}", e)));
+    }
+    Ok(())
+}
+
+/// Write the title lookup table of a multistream dump: title to the
+/// "start end" byte offsets of the stream holding the page. The index is
+/// read line by line, in its order, which is the order of the streams.
+pub fn write_titles(dump: &path::Path) -> WikiResult<()> {
+    let index = index_path_for(dump);
+    let target = titles_path_for(&*index);
+    let tmp = target.with_extension("cdb.tmp");
+    let file_len = try!(fs::metadata(dump)).len();
+    let mut input = io::BufReader::new(BzDecoder::new(try!(fs::File::open(&*index))));
+    let mut result: WikiResult<()> = Ok(());
+    try!(Cdb::new(&*tmp, |titles| {
+        // titles of the current stream, added once the next stream tells
+        // where it ends
+        let mut stream: Vec<String> = vec![];
+        let mut start = 0u64;
+        for line in (&mut input).lines() {
+            let added = line.map_err(WikiError::from).and_then(|line| {
+                let entry = try!(parse_index_line(&*index, &*line));
+                if entry.offset != start {
+                    try!(add_stream(titles, &mut stream, start, entry.offset));
+                    start = entry.offset;
+                }
+                stream.push(entry.title);
+                Ok(())
+            });
+            if let Err(e) = added {
+                result = Err(e);
+                return;
+            }
+        }
+        if let Err(e) = add_stream(titles, &mut stream, start, file_len) {
+            result = Err(e);
+        }
+    })
+             .map_err(|e| format!("Cdb Error: {:?}", e)));
+    try!(result);
+    try!(fs::rename(&*tmp, &*target));
+    Ok(())
+}
+
+/// Random access to the pages of a downloaded multistream dump, without
+/// compiling it: a title lookup table, built from the index on first use
+/// and kept next to the download, tells which bzip2 stream to decompress
+/// and parse.
+pub struct MultistreamWiki {
+    /// dump files, with their title lookup tables
+    files: Vec<(path::PathBuf, Mutex<Box<Cdb>>)>,
+}
+
+impl MultistreamWiki {
+    pub fn for_date(store: &DataStore, wiki: &str, date: &str) -> WikiResult<MultistreamWiki> {
+        let glob = store.dir(Stage::Download, wiki, date) + "/*multistream*.bz2";
+        let mut files = vec![];
+        for dump in try!(::glob::glob(&*glob)) {
+            let dump = try!(dump);
+            if is_index(&*dump) {
+                continue;
+            }
+            let titles = titles_path_for(&*index_path_for(&*dump));
+            if !titles.exists() {
+                try!(write_titles(&*dump));
+            }
+            let titles = try!(Cdb::open(&*titles).map_err(|e| format!("Cdb Error: {:?}", e)));
+            files.push((dump, Mutex::new(titles)));
+        }
+        if files.len() == 0 {
+            Err(format!("no multistream dump for {} {}", wiki, date))?
+        }
+        Ok(MultistreamWiki { files: files })
+    }
+
+    pub fn latest(store: &DataStore, wiki: &str) -> WikiResult<MultistreamWiki> {
+        let date = try!(store.latest_or_err(Stage::Download, wiki));
+        MultistreamWiki::for_date(store, wiki, &*date)
+    }
+
+    /// The file and stream (start, end) offsets holding a page.
+    fn locate(&self, title: &str) -> WikiResult<Option<(&path::Path, u64, u64)>> {
+        for &(ref dump, ref titles) in &self.files {
+            let mut lock = titles.lock().unwrap();
+            let location = match (*lock).find(title.as_bytes()) {
+                Some(location) => {
+                    try!(::std::str::from_utf8(location).map_err(|e| e.to_string())).to_string()
+                }
+                None => continue,
+            };
+            let mut offsets = location.split(' ').map(|o| o.parse::<u64>().ok());
+            match (offsets.next(), offsets.next()) {
+                (Some(Some(start)), Some(Some(end))) => return Ok(Some((&**dump, start, end))),
+                _ => Err(format!("bad stream location {:?} for {:?}", location, title))?,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decompress and parse the stream holding the page.
+    pub fn get_page(&self, title: &str) -> WikiResult<Option<MessageAndPage>> {
+        let (dump, start, end) = match try!(self.locate(title)) {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        let range = StreamRange {
+            path: dump.to_path_buf(),
+            streams: vec![(start, end)],
+            header: false,
+        };
+        match try!(capitanize_wiki::find_page(range.reader(), title)) {
+            Some(builder) => Ok(Some(try!(MessageAndPage::from_builder(&builder)))),
+            None => Ok(None),
+        }
+    }
+}
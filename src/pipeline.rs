@@ -0,0 +1,310 @@
+//! Chains the binaries (download, capitanize, wikidata_cdb, wiki_cdb) for a
+//! wiki and a date, running only the stages that are missing or older than
+//! their inputs.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path;
+use std::process;
+use std::time::{Duration, Instant, SystemTime};
+
+use WikiResult;
+use store::{DataStore, Stage, STAGES};
+
+/// Stages a stage is computed from.
+pub fn inputs(stage: Stage) -> &'static [Stage] {
+    match stage {
+        Stage::Download => &[],
+        Stage::Snappy | Stage::Cap | Stage::History => &[Stage::Download],
+        Stage::Labels | Stage::Cdb | Stage::Csv | Stage::Parquet => &[Stage::Cap],
+        Stage::Neo4j => &[Stage::Cap, Stage::Labels],
+    }
+}
+
+/// Stages the pipeline maintains for a wiki, in dependency order.
+pub fn stages_for(wiki: &str, history: bool) -> Vec<Stage> {
+    let mut stages = vec![Stage::Download, Stage::Cap];
+    if wiki == "wikidata" {
+        stages.push(Stage::Labels);
+    } else {
+        stages.push(Stage::Cdb);
+        if history {
+            stages.push(Stage::History);
+        }
+    }
+    stages
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// no directory for the date
+    Missing,
+    /// being built by a run holding the lock
+    Running,
+    /// directory present, but no `ok` marker: an interrupted run
+    Partial,
+    /// complete, but an input is incomplete or was completed after it
+    Stale,
+    UpToDate,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Status::Missing => "-",
+            Status::Running => "running",
+            Status::Partial => "partial",
+            Status::Stale => "stale",
+            Status::UpToDate => "ok",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn completed_at(store: &DataStore,
+                stage: Stage,
+                wiki: &str,
+                date: &str)
+                -> WikiResult<Option<SystemTime>> {
+    let ok = path::Path::new(&*store.dir(stage, wiki, date)).join("ok");
+    if !ok.exists() {
+        return Ok(None);
+    }
+    Ok(Some(try!(try!(fs::metadata(ok)).modified())))
+}
+
+pub fn status(store: &DataStore, stage: Stage, wiki: &str, date: &str) -> WikiResult<Status> {
+    if store.is_locked(stage, wiki, date) {
+        return Ok(Status::Running);
+    }
+    let done = match try!(completed_at(store, stage, wiki, date)) {
+        Some(done) => done,
+        None => {
+            return Ok(if path::Path::new(&*store.dir(stage, wiki, date)).exists() {
+                          Status::Partial
+                      } else {
+                          Status::Missing
+                      })
+        }
+    };
+    for input in inputs(stage) {
+        match try!(completed_at(store, *input, wiki, date)) {
+            Some(input_done) if input_done <= done => (),
+            _ => return Ok(Status::Stale),
+        }
+    }
+    Ok(Status::UpToDate)
+}
+
+/// Whether the full-history dump of a date was downloaded.
+fn has_history_dump(store: &DataStore, wiki: &str, date: &str) -> WikiResult<bool> {
+    let glob = store.dir(Stage::Download, wiki, date) + "/*pages-meta-history*.bz2";
+    Ok(try!(::glob::glob(&*glob)).next().is_some())
+}
+
+/// Binary name and arguments building a stage. `date` is None to let the
+/// binary pick the latest one available.
+fn command(store: &DataStore,
+           stage: Stage,
+           wiki: &str,
+           date: Option<&str>,
+           history: bool)
+           -> (&'static str, Vec<String>) {
+    let mut args: Vec<String> = vec!["--data-dir".to_string(),
+                                     format!("{}", store.root().display())];
+    let binary = match stage {
+        Stage::Download => {
+            if history {
+                args.push("--history".to_string());
+            }
+            "download"
+        }
+        Stage::Cap => "capitanize",
+        Stage::History => {
+            args.push("--history".to_string());
+            "capitanize"
+        }
+        Stage::Labels => "wikidata_cdb",
+        Stage::Cdb => "wiki_cdb",
+        Stage::Csv => "to_csv",
+        Stage::Snappy | Stage::Parquet | Stage::Neo4j => unreachable!(),
+    };
+    if binary != "wikidata_cdb" && binary != "to_csv" {
+        args.push("--wiki".to_string());
+        args.push(wiki.to_string());
+    }
+    if let Some(date) = date {
+        args.push("--date".to_string());
+        args.push(date.to_string());
+    }
+    (binary, args)
+}
+
+/// The binaries are expected next to the running one.
+fn binary_path(name: &str) -> WikiResult<path::PathBuf> {
+    let exe = try!(env::current_exe());
+    let path = exe.with_file_name(name);
+    if !path.exists() {
+        Err(format!("can not find {} next to {:?}", name, exe))?
+    }
+    Ok(path)
+}
+
+#[derive(Debug, Clone)]
+pub struct StepReport {
+    pub stage: Stage,
+    pub before: Status,
+    /// None if the stage was up to date
+    pub ran_for: Option<Duration>,
+}
+
+pub struct Pipeline {
+    store: DataStore,
+    wiki: String,
+    history: bool,
+}
+
+impl Pipeline {
+    pub fn new(store: &DataStore, wiki: &str) -> Pipeline {
+        Pipeline {
+            store: store.clone(),
+            wiki: wiki.to_string(),
+            history: false,
+        }
+    }
+
+    /// Also maintain the full-history compilation.
+    pub fn with_history(mut self, history: bool) -> Pipeline {
+        self.history = history;
+        self
+    }
+
+    pub fn stages(&self) -> Vec<Stage> {
+        stages_for(&*self.wiki, self.history)
+    }
+
+    fn history(&self) -> bool {
+        self.history && self.wiki != "wikidata"
+    }
+
+    /// `status`, with a download lacking the full-history dump counted as
+    /// stale when the pipeline compiles it.
+    fn status(&self, stage: Stage, date: &str) -> WikiResult<Status> {
+        let status = try!(status(&self.store, stage, &*self.wiki, date));
+        if stage == Stage::Download && status == Status::UpToDate && self.history() &&
+           !try!(has_history_dump(&self.store, &*self.wiki, date)) {
+            return Ok(Status::Stale);
+        }
+        Ok(status)
+    }
+
+    /// Status of each stage for the date.
+    pub fn plan(&self, date: &str) -> WikiResult<Vec<(Stage, Status)>> {
+        let mut plan = vec![];
+        for stage in self.stages() {
+            plan.push((stage, try!(self.status(stage, date))));
+        }
+        Ok(plan)
+    }
+
+    /// The latest date downloaded. On a store with none yet, the latest dump
+    /// available online is downloaded first.
+    pub fn latest_or_download(&self) -> WikiResult<String> {
+        if let Some(date) = try!(self.store.latest(Stage::Download, &*self.wiki)) {
+            return Ok(date);
+        }
+        let (binary, args) =
+            command(&self.store, Stage::Download, &*self.wiki, None, self.history());
+        try!(writeln!(io::stderr(),
+                      "{}: nothing downloaded, running {} {}",
+                      self.wiki,
+                      binary,
+                      args.join(" ")));
+        try!(self.execute(binary, &*args, "latest"));
+        self.store.latest_or_err(Stage::Download, &*self.wiki)
+    }
+
+    fn execute(&self, binary: &str, args: &[String], date: &str) -> WikiResult<()> {
+        let exit = try!(process::Command::new(try!(binary_path(binary)))
+                            .args(args)
+                            .status());
+        if !exit.success() {
+            Err(format!("{} failed for {} {} ({})", binary, self.wiki, date, exit))?
+        }
+        Ok(())
+    }
+
+    /// Build what is not up to date, in dependency order. Stages are
+    /// re-examined after each run, as rebuilding one makes the next ones
+    /// stale.
+    pub fn run(&self, date: &str) -> WikiResult<Vec<StepReport>> {
+        let mut reports = vec![];
+        for stage in self.stages() {
+            let before = try!(self.status(stage, date));
+            if before == Status::UpToDate {
+                reports.push(StepReport {
+                                 stage: stage,
+                                 before: before,
+                                 ran_for: None,
+                             });
+                continue;
+            }
+            let (binary, args) =
+                command(&self.store, stage, &*self.wiki, Some(date), self.history());
+            try!(writeln!(io::stderr(),
+                          "{} {}: {} is {}, running {} {}",
+                          self.wiki,
+                          date,
+                          stage,
+                          before,
+                          binary,
+                          args.join(" ")));
+            let start = Instant::now();
+            try!(self.execute(binary, &*args, date));
+            if !self.store.is_complete(stage, &*self.wiki, date) {
+                Err(format!("{} exited without completing {} for {} {}",
+                            binary,
+                            stage,
+                            self.wiki,
+                            date))?
+            }
+            reports.push(StepReport {
+                             stage: stage,
+                             before: before,
+                             ran_for: Some(start.elapsed()),
+                         });
+        }
+        Ok(reports)
+    }
+}
+
+/// One line per wiki and date found in the store, one column per stage.
+pub fn write_status<W: Write>(store: &DataStore, out: &mut W) -> WikiResult<()> {
+    let mut wikis: Vec<String> = vec![];
+    for stage in STAGES {
+        wikis.extend(try!(store.wikis(*stage)));
+    }
+    wikis.sort();
+    wikis.dedup();
+    let names: Vec<&str> = STAGES.iter().map(|s| s.name()).collect();
+    try!(writeln!(out, "wiki\tdate\t{}", names.join("\t")));
+    for wiki in wikis {
+        let mut dates: Vec<String> = vec![];
+        for stage in STAGES {
+            dates.extend(try!(store.dates(*stage, &*wiki)));
+        }
+        dates.sort();
+        dates.dedup();
+        for date in dates {
+            let mut cells = vec![];
+            for stage in STAGES {
+                cells.push(format!("{}", try!(status(store, *stage, &*wiki, &*date))));
+            }
+            try!(writeln!(out, "{}\t{}\t{}", wiki, date, cells.join("\t")));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,703 @@
+//! RDF export of compiled Wikidata entities, after the Wikidata RDF mapping
+//! (https://www.mediawiki.org/wiki/Wikibase/Indexing/RDF_Dump_Format):
+//!
+//! * labels and descriptions as `rdfs:label` and `schema:description`
+//!   language-tagged literals,
+//! * "truthy" statements as `wd:Q42 wdt:P31 wd:Q5`: the value snaks of
+//!   the best rank of each property (preferred if any, else normal),
+//! * in full mode, statements are also reified as
+//!   `wd:Q42 p:P31 wds:Q42-...`, the statement node holding its rank,
+//!   `ps:P31` value and `pq:` qualifier values.
+//!
+//! Times are `xsd:dateTime`, quantities `xsd:decimal` (units are not
+//! exported), coordinates `geo:wktLiteral` points, and urls IRIs.
+//! Unknown and absent values ("somevalue", "novalue"), sitelinks, and
+//! references are not exported. Qualifier values are typed after their
+//! value only, so url qualifiers come out as plain strings.
+
+use std::collections::HashSet;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::prelude::*;
+
+use capnp::text;
+
+use WikiResult;
+use wikidata::{ClaimValue, EntityHelpers, EntityType, Map, MonolingualText, SnakValue,
+               Statement};
+
+pub const WD: &'static str = "http://www.wikidata.org/entity/";
+pub const WDT: &'static str = "http://www.wikidata.org/prop/direct/";
+pub const P: &'static str = "http://www.wikidata.org/prop/";
+pub const PS: &'static str = "http://www.wikidata.org/prop/statement/";
+pub const PQ: &'static str = "http://www.wikidata.org/prop/qualifier/";
+pub const WDS: &'static str = "http://www.wikidata.org/entity/statement/";
+pub const WIKIBASE: &'static str = "http://wikiba.se/ontology#";
+pub const RDF: &'static str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+pub const RDFS: &'static str = "http://www.w3.org/2000/01/rdf-schema#";
+pub const SCHEMA: &'static str = "http://schema.org/";
+pub const XSD: &'static str = "http://www.w3.org/2001/XMLSchema#";
+pub const GEO: &'static str = "http://www.opengis.net/ont/geosparql#";
+
+/// Turtle prefixes. Longest namespaces first, so that an IRI gets the most
+/// specific one.
+const PREFIXES: &'static [(&'static str, &'static str)] = &[("wds", WDS),
+                                                            ("wdt", WDT),
+                                                            ("ps", PS),
+                                                            ("pq", PQ),
+                                                            ("p", P),
+                                                            ("wd", WD),
+                                                            ("wikibase", WIKIBASE),
+                                                            ("rdf", RDF),
+                                                            ("rdfs", RDFS),
+                                                            ("schema", SCHEMA),
+                                                            ("xsd", XSD),
+                                                            ("geo", GEO)];
+
+const EARTH: &'static str = "http://www.wikidata.org/entity/Q2";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    NTriples,
+    Turtle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// best rank values as direct `wdt:` triples only
+    Truthy,
+    /// truthy triples, plus reified statements with ranks and qualifiers
+    Full,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Iri(String),
+    Literal {
+        value: String,
+        datatype: Option<String>,
+        language: Option<String>,
+    },
+}
+
+impl Term {
+    pub fn iri(namespace: &str, local: &str) -> Term {
+        Term::Iri(format!("{}{}", namespace, local))
+    }
+
+    pub fn string(value: &str) -> Term {
+        Term::Literal {
+            value: value.to_string(),
+            datatype: None,
+            language: None,
+        }
+    }
+
+    pub fn typed(value: String, datatype: &str) -> Term {
+        Term::Literal {
+            value: value,
+            datatype: Some(datatype.to_string()),
+            language: None,
+        }
+    }
+
+    pub fn tagged(value: &str, language: &str) -> Term {
+        Term::Literal {
+            value: value.to_string(),
+            datatype: None,
+            language: Some(language.to_string()),
+        }
+    }
+
+    /// The term in N-Triples, or in Turtle with prefixed names when they
+    /// are valid.
+    pub fn write(&self, syntax: Syntax, out: &mut String) {
+        match *self {
+            Term::Iri(ref iri) => write_iri(iri, syntax, out),
+            Term::Literal { ref value, ref datatype, ref language } => {
+                out.push('"');
+                escape_literal(value, out);
+                out.push('"');
+                if let Some(ref language) = *language {
+                    out.push('@');
+                    out.push_str(language);
+                } else if let Some(ref datatype) = *datatype {
+                    out.push_str("^^");
+                    write_iri(datatype, syntax, out);
+                }
+            }
+        }
+    }
+}
+
+pub type Triple = (Term, Term, Term);
+
+fn escape_literal(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                let _ = write!(out, "\\u{:04X}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+fn write_iri(iri: &str, syntax: Syntax, out: &mut String) {
+    if syntax == Syntax::Turtle {
+        for &(prefix, namespace) in PREFIXES {
+            if iri.starts_with(namespace) {
+                let local = &iri[namespace.len()..];
+                if is_local_name(local) {
+                    out.push_str(prefix);
+                    out.push(':');
+                    out.push_str(local);
+                    return;
+                }
+            }
+        }
+    }
+    out.push('<');
+    for c in iri.chars() {
+        match c {
+            // not allowed in IRIREF
+            '\u{0}'...' ' | '<' | '>' | '"' | '{' | '}' | '|' | '^' | '`' | '\\' => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    let _ = write!(out, "%{:02X}", b);
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('>');
+}
+
+/// A conservative subset of Turtle PN_LOCAL: ASCII letters, digits, '_'
+/// and '-', not starting with '-'.
+fn is_local_name(local: &str) -> bool {
+    !local.is_empty() && !local.starts_with('-') &&
+    local.chars().all(|c| match c {
+                          'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => true,
+                          _ => false,
+                      })
+}
+
+/// Turtle @prefix lines.
+pub fn turtle_prefixes() -> String {
+    let mut out = String::new();
+    for &(prefix, namespace) in PREFIXES {
+        let _ = writeln!(out, "@prefix {}: <{}> .", prefix, namespace);
+    }
+    out
+}
+
+/// Wikidata RDF form of a time: no leading '+', and unknown months and
+/// days (of low precision dates) as 01, to stay a valid xsd:dateTime.
+pub fn normalize_time(time: &str) -> String {
+    let time = time.trim_left_matches('+');
+    let mut parts: Vec<String> = time.splitn(2, 'T').map(|s| s.to_string()).collect();
+    {
+        let date = &mut parts[0];
+        // the year may be negative: work from the end
+        if date.ends_with("-00-00") {
+            let len = date.len();
+            date.truncate(len - 6);
+            date.push_str("-01-01");
+        } else if date.ends_with("-00") {
+            let len = date.len();
+            date.truncate(len - 3);
+            date.push_str("-01");
+        }
+    }
+    parts.join("T")
+}
+
+/// Term of a value. `datatype` is the property datatype, when known.
+pub fn value_term(value: &ClaimValue, datatype: Option<&str>) -> Term {
+    match *value {
+        ClaimValue::Entity(ref e) => Term::iri(WD, &*e.get_id()),
+        ClaimValue::String(ref s) => {
+            match datatype {
+                Some("url") => Term::Iri(s.to_string()),
+                Some("commonsMedia") => {
+                    Term::Iri(format!("http://commons.wikimedia.org/wiki/Special:FilePath/{}",
+                                      s.replace(' ', "_")))
+                }
+                _ => Term::string(s),
+            }
+        }
+        ClaimValue::Time { ref time, .. } => {
+            Term::typed(normalize_time(time), &*format!("{}dateTime", XSD))
+        }
+        ClaimValue::Quantity { amount, .. } => {
+            Term::typed(format!("{}", amount), &*format!("{}decimal", XSD))
+        }
+        ClaimValue::Coordinate { latitude, longitude, ref globe } => {
+            let point = if globe.is_empty() || globe == EARTH {
+                format!("Point({} {})", longitude, latitude)
+            } else {
+                format!("<{}> Point({} {})", globe, longitude, latitude)
+            };
+            Term::typed(point, &*format!("{}wktLiteral", GEO))
+        }
+        ClaimValue::Text { ref language, ref text } => Term::tagged(text, language),
+    }
+}
+
+/// Statement node id: the claim GUID, '$' replaced by '-'.
+pub fn statement_node(statement: &Statement) -> Term {
+    Term::iri(WDS, &*statement.id.replace('$', "-"))
+}
+
+/// Ranks that make the truthy statements of each property.
+fn best_ranks(statements: &[Statement]) -> HashSet<(String, &'static str)> {
+    let preferred: HashSet<&str> = statements.iter()
+        .filter(|s| s.rank == "preferred")
+        .map(|s| &*s.property)
+        .collect();
+    statements.iter()
+        .map(|s| {
+                 let best = if preferred.contains(&*s.property) {
+                     "preferred"
+                 } else {
+                     "normal"
+                 };
+                 (s.property.clone(), best)
+             })
+        .collect()
+}
+
+fn monolingual_triples(subject: &Term,
+                        predicate: &Term,
+                        map: Map::Reader<text::Owned, MonolingualText::Owned>,
+                        triples: &mut Vec<Triple>)
+                        -> WikiResult<()> {
+    for entry in try!(map.get_entries()).iter() {
+        if let MonolingualText::Value(text) = try!(try!(entry.get_value()).which()) {
+            triples.push((subject.clone(),
+                          predicate.clone(),
+                          Term::tagged(try!(text), try!(entry.get_key()))));
+        }
+    }
+    Ok(())
+}
+
+/// Triples of an entity.
+pub fn entity_triples<E: EntityHelpers>(entity: &E, mode: Mode) -> WikiResult<Vec<Triple>> {
+    let reader = try!(entity.as_entity_reader());
+    let id = try!(reader.get_id());
+    let subject = Term::iri(WD, id);
+    let mut triples = vec![];
+    if mode == Mode::Full {
+        let kind = match try!(reader.get_type()) {
+            EntityType::Item => "Item",
+            EntityType::Property => "Property",
+        };
+        triples.push((subject.clone(),
+                      Term::iri(RDF, "type"),
+                      Term::iri(WIKIBASE, kind)));
+    }
+    try!(monolingual_triples(&subject,
+                             &Term::iri(RDFS, "label"),
+                             try!(reader.get_labels()),
+                             &mut triples));
+    try!(monolingual_triples(&subject,
+                             &Term::iri(SCHEMA, "description"),
+                             try!(reader.get_descriptions()),
+                             &mut triples));
+    let statements = try!(entity.get_statements());
+    let best = best_ranks(&*statements);
+    for statement in &statements {
+        let datatype = if statement.datatype.is_empty() {
+            None
+        } else {
+            Some(&*statement.datatype)
+        };
+        let truthy = best.contains(&(statement.property.clone(), statement.rank));
+        if let (true, Some(value)) = (truthy, statement.value.value()) {
+            triples.push((subject.clone(),
+                          Term::iri(WDT, &*statement.property),
+                          value_term(value, datatype)));
+        }
+        if mode == Mode::Truthy {
+            continue;
+        }
+        let node = statement_node(statement);
+        triples.push((subject.clone(), Term::iri(P, &*statement.property), node.clone()));
+        triples.push((node.clone(), Term::iri(RDF, "type"), Term::iri(WIKIBASE, "Statement")));
+        if truthy {
+            triples.push((node.clone(), Term::iri(RDF, "type"), Term::iri(WIKIBASE, "BestRank")));
+        }
+        let rank = match statement.rank {
+            "preferred" => "PreferredRank",
+            "deprecated" => "DeprecatedRank",
+            _ => "NormalRank",
+        };
+        triples.push((node.clone(), Term::iri(WIKIBASE, "rank"), Term::iri(WIKIBASE, rank)));
+        if let Some(value) = statement.value.value() {
+            triples.push((node.clone(),
+                          Term::iri(PS, &*statement.property),
+                          value_term(value, datatype)));
+        }
+        for &(ref property, ref value) in &statement.qualifiers {
+            if let SnakValue::Value(ref value) = *value {
+                triples.push((node.clone(), Term::iri(PQ, property), value_term(value, None)));
+            }
+        }
+    }
+    Ok(triples)
+}
+
+/// Triples in `syntax`. Turtle groups consecutive triples of a subject
+/// (but needs the `turtle_prefixes` first).
+pub fn format_triples(triples: &[Triple], syntax: Syntax) -> String {
+    let mut out = String::new();
+    let mut previous: Option<&Term> = None;
+    for triple in triples {
+        match syntax {
+            Syntax::NTriples => {
+                triple.0.write(syntax, &mut out);
+                out.push(' ');
+            }
+            Syntax::Turtle => {
+                if previous == Some(&triple.0) {
+                    out.push_str(" ;\n    ");
+                } else {
+                    if previous.is_some() {
+                        out.push_str(" .\n");
+                    }
+                    triple.0.write(syntax, &mut out);
+                    out.push_str("\n    ");
+                }
+            }
+        }
+        triple.1.write(syntax, &mut out);
+        out.push(' ');
+        triple.2.write(syntax, &mut out);
+        match syntax {
+            Syntax::NTriples => out.push_str(" .\n"),
+            Syntax::Turtle => previous = Some(&triple.0),
+        }
+    }
+    if previous.is_some() {
+        out.push_str(" .\n");
+    }
+    out
+}
+
+/// Streams entities as RDF.
+pub struct RdfWriter<W: Write> {
+    out: W,
+    syntax: Syntax,
+    mode: Mode,
+}
+
+impl<W: Write> RdfWriter<W> {
+    /// A writer on `out`, with the Turtle prefixes written already.
+    pub fn new(mut out: W, syntax: Syntax, mode: Mode) -> WikiResult<RdfWriter<W>> {
+        if syntax == Syntax::Turtle {
+            try!(out.write_all(turtle_prefixes().as_bytes()));
+            try!(out.write_all(b"\n"));
+        }
+        Ok(RdfWriter {
+               out: out,
+               syntax: syntax,
+               mode: mode,
+           })
+    }
+
+    /// The entity as a chunk of RDF, for callers formatting in parallel.
+    pub fn format_entity<E: EntityHelpers>(&self, entity: &E) -> WikiResult<String> {
+        Ok(format_triples(&*try!(entity_triples(entity, self.mode)), self.syntax))
+    }
+
+    pub fn write_chunk(&mut self, chunk: &str) -> WikiResult<()> {
+        try!(self.out.write_all(chunk.as_bytes()));
+        Ok(())
+    }
+
+    pub fn write_entity<E: EntityHelpers>(&mut self, entity: &E) -> WikiResult<()> {
+        let chunk = try!(self.format_entity(entity));
+        self.write_chunk(&*chunk)
+    }
+
+    pub fn flush(&mut self) -> WikiResult<()> {
+        try!(self.out.flush());
+        Ok(())
+    }
+}
+
+/// Selects the entities of a subset export.
+#[derive(Debug, Clone, Default)]
+pub struct EntityFilter {
+    ids: Option<HashSet<String>>,
+    /// (property, value): all must be matched by a value of the property,
+    /// any value if None
+    claims: Vec<(String, Option<String>)>,
+}
+
+impl EntityFilter {
+    /// A filter letting every entity through.
+    pub fn new() -> EntityFilter {
+        EntityFilter::default()
+    }
+
+    /// Only these ids. Calls add up.
+    pub fn ids<I: IntoIterator<Item = String>>(mut self, ids: I) -> EntityFilter {
+        self.ids.get_or_insert_with(HashSet::new).extend(ids);
+        self
+    }
+
+    /// Only entities with a "P31" claim, or a "P31=Q5" one.
+    pub fn claim(mut self, spec: &str) -> WikiResult<EntityFilter> {
+        let mut parts = spec.splitn(2, '=');
+        let property = parts.next().unwrap_or("").trim();
+        if !property.starts_with('P') || property[1..].parse::<u32>().is_err() {
+            return Err(format!("bad claim filter {:?}: expected P31 or P31=Q5", spec).into());
+        }
+        let value = parts.next().map(|v| v.trim().to_string());
+        self.claims.push((property.to_string(), value));
+        Ok(self)
+    }
+
+    /// The ids the filter is restricted to, if any.
+    pub fn listed_ids(&self) -> Option<&HashSet<String>> {
+        self.ids.as_ref()
+    }
+
+    pub fn matches<E: EntityHelpers>(&self, entity: &E) -> WikiResult<bool> {
+        if let Some(ref ids) = self.ids {
+            if !ids.contains(try!(entity.get_id())) {
+                return Ok(false);
+            }
+        }
+        if self.claims.is_empty() {
+            return Ok(true);
+        }
+        let statements = try!(entity.get_statements());
+        Ok(self.claims.iter().all(|&(ref property, ref expected)| {
+            statements.iter().any(|s| {
+                &s.property == property &&
+                match (expected, s.value.value()) {
+                    (&None, _) => true,
+                    (&Some(ref expected), Some(value)) => value.to_string() == *expected,
+                    (&Some(_), None) => false,
+                }
+            })
+        }))
+    }
+}
+
+/// Ids listed one per line (as in a QID column), blank lines and `#`
+/// comments skipped.
+pub fn read_ids<R: BufRead>(input: R) -> WikiResult<Vec<String>> {
+    let mut ids = vec![];
+    for line in input.lines() {
+        let line = try!(line);
+        let id = line.split('#').next().unwrap_or("").trim();
+        if !id.is_empty() {
+            ids.push(id.to_string());
+        }
+    }
+    Ok(ids)
+}
+
+/// Buffered stdout, for the exporters writing there.
+pub fn stdout_writer(syntax: Syntax,
+                     mode: Mode)
+                     -> WikiResult<RdfWriter<io::BufWriter<io::Stdout>>> {
+    RdfWriter::new(io::BufWriter::new(io::stdout()), syntax, mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::*;
+    use capitanize_wikidata;
+    use wikidata::{ClaimValue, EntityMessage};
+
+    fn claim(id: &str, rank: &str, property: &str, datatype: &str, datavalue: &str) -> String {
+        format!(r#"{{"id": "{}", "type": "statement", "rank": "{}",
+                    "mainsnak": {{"snaktype": "value", "property": "{}", "datatype": "{}",
+                                  "datavalue": {}}}"#,
+                id,
+                rank,
+                property,
+                datatype,
+                datavalue)
+    }
+
+    fn item(id: u32) -> String {
+        format!(r#"{{"type": "wikibase-entityid",
+                     "value": {{"entity-type": "item", "numeric-id": {}}}}}"#,
+                id)
+    }
+
+    /// Douglas Adams, with a normal and a preferred P31, a deprecated and a
+    /// normal P106, a qualifier, a coordinate and a quantity.
+    fn entity() -> EntityMessage {
+        let start = r#"{"snaktype": "value", "property": "P580", "datatype": "time",
+                        "datavalue": {"type": "time",
+                                      "value": {"time": "+2001-00-00T00:00:00Z",
+                                                "precision": 9}}}"#;
+        let claims =
+            vec![format!("{}}}", claim("Q42$A", "normal", "P31", "wikibase-item", &*item(5))),
+                 format!(r#"{}, "qualifiers": {{"P580": [{}]}}}}"#,
+                         claim("Q42$B", "preferred", "P31", "wikibase-item", &*item(215627)),
+                         start),
+                 format!("{}}}",
+                         claim("Q42$C", "deprecated", "P106", "wikibase-item", &*item(36180))),
+                 format!("{}}}",
+                         claim("Q42$D", "normal", "P106", "wikibase-item", &*item(214917))),
+                 format!("{}}}",
+                         claim("Q42$E",
+                               "normal",
+                               "P625",
+                               "globe-coordinate",
+                               r#"{"type": "globecoordinate",
+                                   "value": {"latitude": 51.5, "longitude": -0.125,
+                                             "globe": "http://www.wikidata.org/entity/Q2"}}"#)),
+                 format!("{}}}",
+                         claim("Q42$F",
+                               "normal",
+                               "P2048",
+                               "quantity",
+                               r#"{"type": "quantity", "value": {"amount": 1.96, "unit": "1"}}"#))];
+        let json = format!(r#"{{"id": "Q42", "type": "item",
+                               "labels": {{"en": {{"language": "en", "value": "Douglas Adams"}}}},
+                               "claims": {{"P31": [{}, {}], "P106": [{}, {}],
+                                           "P625": [{}], "P2048": [{}]}}}}"#,
+                           claims[0],
+                           claims[1],
+                           claims[2],
+                           claims[3],
+                           claims[4],
+                           claims[5]);
+        let value: serde_json::Value = serde_json::from_str(&*json).unwrap();
+        let message = capitanize_wikidata::entity_message(&value).unwrap();
+        EntityMessage::from_builder(&message).unwrap()
+    }
+
+    fn has(triples: &[Triple], s: Term, p: Term, o: Term) -> bool {
+        triples.contains(&(s, p, o))
+    }
+
+    #[test]
+    fn truthy_keeps_the_best_rank() {
+        let triples = entity_triples(&entity(), Mode::Truthy).unwrap();
+        let q42 = Term::iri(WD, "Q42");
+        assert!(has(&*triples, q42.clone(), Term::iri(WDT, "P31"), Term::iri(WD, "Q215627")));
+        assert!(!has(&*triples, q42.clone(), Term::iri(WDT, "P31"), Term::iri(WD, "Q5")));
+        assert!(has(&*triples, q42.clone(), Term::iri(WDT, "P106"), Term::iri(WD, "Q214917")));
+        assert!(!has(&*triples, q42.clone(), Term::iri(WDT, "P106"), Term::iri(WD, "Q36180")));
+        assert!(has(&*triples,
+                    q42.clone(),
+                    Term::iri(RDFS, "label"),
+                    Term::tagged("Douglas Adams", "en")));
+        assert!(has(&*triples,
+                    q42.clone(),
+                    Term::iri(WDT, "P625"),
+                    Term::typed("Point(-0.125 51.5)".to_string(), &*format!("{}wktLiteral", GEO))));
+        assert!(has(&*triples,
+                    q42.clone(),
+                    Term::iri(WDT, "P2048"),
+                    Term::typed("1.96".to_string(), &*format!("{}decimal", XSD))));
+        assert!(triples.iter().all(|t| t.1 != Term::iri(P, "P31")));
+    }
+
+    #[test]
+    fn full_reifies_statements() {
+        let triples = entity_triples(&entity(), Mode::Full).unwrap();
+        let q42 = Term::iri(WD, "Q42");
+        let best = Term::iri(WDS, "Q42-B");
+        let rank = Term::iri(WIKIBASE, "rank");
+        let best_rank = Term::iri(WIKIBASE, "BestRank");
+        let a_type = Term::iri(RDF, "type");
+        assert!(has(&*triples, q42.clone(), a_type.clone(), Term::iri(WIKIBASE, "Item")));
+        assert!(has(&*triples, q42.clone(), Term::iri(P, "P31"), best.clone()));
+        assert!(has(&*triples,
+                    best.clone(),
+                    rank.clone(),
+                    Term::iri(WIKIBASE, "PreferredRank")));
+        assert!(has(&*triples, best.clone(), a_type.clone(), best_rank.clone()));
+        assert!(has(&*triples, best.clone(), Term::iri(PS, "P31"), Term::iri(WD, "Q215627")));
+        assert!(has(&*triples,
+                    best.clone(),
+                    Term::iri(PQ, "P580"),
+                    Term::typed("2001-01-01T00:00:00Z".to_string(),
+                                &*format!("{}dateTime", XSD))));
+        let normal = Term::iri(WDS, "Q42-A");
+        assert!(has(&*triples, normal.clone(), rank.clone(), Term::iri(WIKIBASE, "NormalRank")));
+        assert!(has(&*triples, normal.clone(), Term::iri(PS, "P31"), Term::iri(WD, "Q5")));
+        assert!(!has(&*triples, normal.clone(), a_type.clone(), best_rank.clone()));
+        let deprecated = Term::iri(WDS, "Q42-C");
+        assert!(has(&*triples,
+                    deprecated.clone(),
+                    rank.clone(),
+                    Term::iri(WIKIBASE, "DeprecatedRank")));
+        assert!(!has(&*triples, deprecated.clone(), a_type.clone(), best_rank.clone()));
+        assert!(has(&*triples, Term::iri(WDS, "Q42-D"), a_type.clone(), best_rank.clone()));
+    }
+
+    #[test]
+    fn off_earth_coordinates_name_their_globe() {
+        let mars = ClaimValue::Coordinate {
+            latitude: 4.5,
+            longitude: 137.4,
+            globe: "http://www.wikidata.org/entity/Q111".to_string(),
+        };
+        assert_eq!(value_term(&mars, None),
+                   Term::typed("<http://www.wikidata.org/entity/Q111> Point(137.4 4.5)"
+                                   .to_string(),
+                               &*format!("{}wktLiteral", GEO)));
+    }
+
+    #[test]
+    fn filters() {
+        let entity = entity();
+        let matches = |filter: EntityFilter| filter.matches(&entity).unwrap();
+        assert!(matches(EntityFilter::new()));
+        assert!(matches(EntityFilter::new().claim("P31").unwrap()));
+        assert!(matches(EntityFilter::new().claim("P31=Q215627").unwrap()));
+        assert!(matches(EntityFilter::new().claim("P31=Q5").unwrap().claim("P625").unwrap()));
+        assert!(!matches(EntityFilter::new().claim("P31=Q6").unwrap()));
+        assert!(!matches(EntityFilter::new().claim("P31").unwrap().claim("P19").unwrap()));
+        assert!(matches(EntityFilter::new().ids(vec!["Q1".to_string(), "Q42".to_string()])));
+        assert!(!matches(EntityFilter::new().ids(vec!["Q1".to_string()])));
+        assert!(EntityFilter::new().claim("31").is_err());
+        assert!(EntityFilter::new().claim("Pxx=Q5").is_err());
+    }
+
+    #[test]
+    fn terms() {
+        let mut out = String::new();
+        Term::tagged("Douglas \"Doug\"\nAdams\\", "en").write(Syntax::NTriples, &mut out);
+        assert_eq!(out, "\"Douglas \\\"Doug\\\"\\nAdams\\\\\"@en");
+        let mut out = String::new();
+        Term::iri(WDS, "Q42-F078E5B3").write(Syntax::Turtle, &mut out);
+        assert_eq!(out, "wds:Q42-F078E5B3");
+        let mut out = String::new();
+        Term::Iri("http://example.org/a b".to_string()).write(Syntax::Turtle, &mut out);
+        assert_eq!(out, "<http://example.org/a%20b>");
+        assert_eq!(normalize_time("+1952-03-11T00:00:00Z"), "1952-03-11T00:00:00Z");
+        assert_eq!(normalize_time("-0500-00-00T00:00:00Z"), "-0500-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn turtle_groups_subjects() {
+        let s = Term::iri(WD, "Q42");
+        let triples = vec![(s.clone(), Term::iri(WDT, "P31"), Term::iri(WD, "Q5")),
+                           (s.clone(),
+                            Term::iri(RDFS, "label"),
+                            Term::tagged("Douglas Adams", "en"))];
+        assert_eq!(format_triples(&*triples, Syntax::Turtle),
+                   "wd:Q42\n    wdt:P31 wd:Q5 ;\n    rdfs:label \"Douglas Adams\"@en .\n");
+    }
+}
@@ -0,0 +1,396 @@
+//! External shuffle, for map-reduce outputs larger than memory: (key, value)
+//! pairs are hash-partitioned into sorted runs on disk, then the runs of
+//! each partition are merged, reducing the values of equal keys.
+//!
+//! Every chunk spills at least once, so a partition can have thousands of
+//! runs. They are merged `MERGE_FAN_IN` at a time into intermediate runs
+//! until few enough are left to be opened together.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::iter;
+use std::io::prelude::*;
+use std::marker::PhantomData;
+use std::mem;
+use std::path;
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{self, AtomicUsize, ATOMIC_USIZE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::vec;
+
+use bincode;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use WikiResult;
+
+/// Default partition count.
+pub const PARTITIONS: usize = 64;
+/// Default count of keys a chunk aggregates in memory before spilling them.
+pub const SPILL_KEYS: usize = 1_000_000;
+/// Default count of runs merged at once, each one an open file.
+pub const MERGE_FAN_IN: usize = 64;
+
+static SPILLS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A directory of sorted runs, filled concurrently by the chunks of a
+/// map-reduce. It is removed when dropped.
+pub struct Spill {
+    dir: path::PathBuf,
+    partitions: usize,
+    fan_in: usize,
+    runs: Mutex<Vec<Vec<path::PathBuf>>>,
+    next_run: AtomicUsize,
+}
+
+impl Spill {
+    /// A new spill directory under `parent`.
+    pub fn new(parent: &path::Path, partitions: usize) -> WikiResult<Spill> {
+        try!(fs::create_dir_all(parent));
+        // processes sharing the parent get their own directory: create_dir
+        // fails on an existing one
+        let dir = loop {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            let dir = parent.join(format!("shuffle-{}-{}-{}",
+                                          process::id(),
+                                          nanos,
+                                          SPILLS.fetch_add(1, atomic::Ordering::SeqCst)));
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Ok(Spill {
+               dir: dir,
+               partitions: partitions,
+               fan_in: MERGE_FAN_IN,
+               runs: Mutex::new((0..partitions).map(|_| vec![]).collect()),
+               next_run: AtomicUsize::new(0),
+           })
+    }
+
+    /// Merge at most `fan_in` runs at once (at least 2).
+    pub fn with_fan_in(mut self, fan_in: usize) -> Spill {
+        self.fan_in = ::std::cmp::max(fan_in, 2);
+        self
+    }
+
+    fn partition<K: Hash>(&self, key: &K) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.partitions as u64) as usize
+    }
+
+    /// Write aggregates as one sorted run per partition, emptying them.
+    pub fn spill<K, V>(&self, aggregates: &mut HashMap<K, V>) -> WikiResult<()>
+        where K: Eq + Ord + Hash + Serialize,
+              V: Serialize
+    {
+        let mut partitions: Vec<Vec<(K, V)>> = (0..self.partitions).map(|_| vec![]).collect();
+        for (k, v) in aggregates.drain() {
+            let p = self.partition(&k);
+            partitions[p].push((k, v));
+        }
+        for (p, mut pairs) in partitions.into_iter().enumerate() {
+            if pairs.len() == 0 {
+                continue;
+            }
+            pairs.sort_by(|a, b| a.0.cmp(&b.0));
+            let path = self.run_path(p);
+            let mut out = try!(RunWriter::create(&path));
+            for pair in &pairs {
+                try!(out.write(pair));
+            }
+            try!(out.finish());
+            self.runs.lock().unwrap()[p].push(path);
+        }
+        Ok(())
+    }
+
+    fn run_path(&self, partition: usize) -> path::PathBuf {
+        let run = self.next_run.fetch_add(1, atomic::Ordering::SeqCst);
+        self.dir.join(format!("{:05}-{:08}.run", partition, run))
+    }
+
+    /// Merge the runs of a partition `fan_in` at a time, as many passes as
+    /// it takes to leave `fan_in` runs at most. Merged runs are removed.
+    fn narrow<K, V, R>(&self,
+                       partition: usize,
+                       paths: Vec<path::PathBuf>,
+                       reducer: &R)
+                       -> WikiResult<Vec<path::PathBuf>>
+        where K: Ord + Serialize + DeserializeOwned,
+              V: Serialize + DeserializeOwned,
+              R: Fn(&V, &V) -> V
+    {
+        let mut paths = paths;
+        while paths.len() > self.fan_in {
+            let mut merged = vec![];
+            for group in paths.chunks(self.fan_in) {
+                if group.len() == 1 {
+                    merged.push(group[0].clone());
+                    continue;
+                }
+                let path = self.run_path(partition);
+                {
+                    let mut merge: Merge<K, V> = try!(Merge::open(group));
+                    let mut out = try!(RunWriter::create(&path));
+                    while let Some(pair) = try!(merge.next(reducer)) {
+                        try!(out.write(&pair));
+                    }
+                    try!(out.finish());
+                }
+                for run in group {
+                    try!(fs::remove_file(run));
+                }
+                merged.push(path);
+            }
+            paths = merged;
+        }
+        Ok(paths)
+    }
+
+    /// Merge the runs, one partition after the other. Keys come sorted
+    /// within a partition.
+    pub fn merge<K, V, R>(self, reducer: &R) -> Merged<K, V, R>
+        where K: Ord + Serialize + DeserializeOwned,
+              V: Serialize + DeserializeOwned,
+              R: Fn(&V, &V) -> V
+    {
+        let runs = mem::replace(&mut *self.runs.lock().unwrap(), vec![]);
+        Merged {
+            partitions: runs.into_iter().enumerate(),
+            current: None,
+            reducer: reducer,
+            failed: false,
+            spill: self,
+        }
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A run being written: the pair count, then the pairs. The count is only
+/// known at the end, and written over a placeholder.
+struct RunWriter {
+    out: io::BufWriter<fs::File>,
+    count: u64,
+}
+
+impl RunWriter {
+    fn create(path: &path::Path) -> WikiResult<RunWriter> {
+        let mut out = io::BufWriter::new(try!(fs::File::create(path)));
+        try!(bincode::serialize_into(&mut out, &0u64));
+        Ok(RunWriter {
+               out: out,
+               count: 0,
+           })
+    }
+
+    fn write<K: Serialize, V: Serialize>(&mut self, pair: &(K, V)) -> WikiResult<()> {
+        try!(bincode::serialize_into(&mut self.out, pair));
+        self.count += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> WikiResult<()> {
+        try!(self.out.flush());
+        let file = self.out.get_mut();
+        try!(file.seek(io::SeekFrom::Start(0)));
+        try!(bincode::serialize_into(file, &self.count));
+        Ok(())
+    }
+}
+
+/// A run being read back.
+struct Run<K, V> {
+    reader: io::BufReader<fs::File>,
+    left: u64,
+    _phantom: PhantomData<(K, V)>,
+}
+
+impl<K: DeserializeOwned, V: DeserializeOwned> Run<K, V> {
+    fn open(path: &path::Path) -> WikiResult<Run<K, V>> {
+        let mut reader = io::BufReader::new(try!(fs::File::open(path)));
+        let left = try!(bincode::deserialize_from(&mut reader));
+        Ok(Run {
+               reader: reader,
+               left: left,
+               _phantom: PhantomData,
+           })
+    }
+
+    fn next(&mut self) -> WikiResult<Option<(K, V)>> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        Ok(Some(try!(bincode::deserialize_from(&mut self.reader))))
+    }
+}
+
+/// Smallest pair of a run, ordered so that the heap pops the smallest key.
+struct Head<K, V> {
+    key: K,
+    value: V,
+    run: usize,
+}
+
+impl<K: Ord, V> Ord for Head<K, V> {
+    fn cmp(&self, other: &Head<K, V>) -> Ordering {
+        other.key.cmp(&self.key).then(other.run.cmp(&self.run))
+    }
+}
+
+impl<K: Ord, V> PartialOrd for Head<K, V> {
+    fn partial_cmp(&self, other: &Head<K, V>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord, V> PartialEq for Head<K, V> {
+    fn eq(&self, other: &Head<K, V>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord, V> Eq for Head<K, V> {}
+
+/// K-way merge of the runs of a partition.
+struct Merge<K, V> {
+    runs: Vec<Run<K, V>>,
+    heap: BinaryHeap<Head<K, V>>,
+}
+
+impl<K: Ord + DeserializeOwned, V: DeserializeOwned> Merge<K, V> {
+    fn open(paths: &[path::PathBuf]) -> WikiResult<Merge<K, V>> {
+        let mut merge = Merge {
+            runs: vec![],
+            heap: BinaryHeap::new(),
+        };
+        for path in paths {
+            merge.runs.push(try!(Run::open(path)));
+            let run = merge.runs.len() - 1;
+            try!(merge.refill(run));
+        }
+        Ok(merge)
+    }
+
+    fn refill(&mut self, run: usize) -> WikiResult<()> {
+        if let Some((key, value)) = try!(self.runs[run].next()) {
+            self.heap.push(Head {
+                               key: key,
+                               value: value,
+                               run: run,
+                           });
+        }
+        Ok(())
+    }
+
+    /// Next key, with the values it has in all the runs reduced. A run
+    /// holds a key once at most.
+    fn next<R: Fn(&V, &V) -> V>(&mut self, reducer: &R) -> WikiResult<Option<(K, V)>> {
+        let head = match self.heap.pop() {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        try!(self.refill(head.run));
+        let mut value = head.value;
+        while self.heap.peek().map(|h| h.key == head.key).unwrap_or(false) {
+            let same = self.heap.pop().unwrap();
+            try!(self.refill(same.run));
+            value = reducer(&value, &same.value);
+        }
+        Ok(Some((head.key, value)))
+    }
+}
+
+/// Reduced (key, value) pairs streamed from a spill. Stops after the first
+/// error.
+pub struct Merged<'r, K, V, R: 'r> {
+    partitions: iter::Enumerate<vec::IntoIter<Vec<path::PathBuf>>>,
+    current: Option<Merge<K, V>>,
+    reducer: &'r R,
+    failed: bool,
+    // last, so that the runs are closed before their directory is removed
+    spill: Spill,
+}
+
+impl<'r, K, V, R> Iterator for Merged<'r, K, V, R>
+    where K: Ord + Serialize + DeserializeOwned,
+          V: Serialize + DeserializeOwned,
+          R: Fn(&V, &V) -> V
+{
+    type Item = WikiResult<(K, V)>;
+
+    fn next(&mut self) -> Option<WikiResult<(K, V)>> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            if self.current.is_none() {
+                let (partition, paths) = match self.partitions.next() {
+                    Some(partition) => partition,
+                    None => return None,
+                };
+                let opened = self.spill
+                    .narrow(partition, paths, self.reducer)
+                    .and_then(|paths| Merge::open(&*paths));
+                match opened {
+                    Ok(merge) => self.current = Some(merge),
+                    Err(e) => {
+                        self.failed = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            match self.current.as_mut().unwrap().next(self.reducer) {
+                Ok(Some(pair)) => return Some(Ok(pair)),
+                Ok(None) => self.current = None,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn merges_more_runs_than_the_fan_in() {
+        let spill = Spill::new(&env::temp_dir(), 2).unwrap().with_fan_in(3);
+        for run in 0..10u64 {
+            let mut aggregates: HashMap<u64, u64> = (0..20).map(|k| (k, run)).collect();
+            spill.spill(&mut aggregates).unwrap();
+        }
+        let sum = |a: &u64, b: &u64| a + b;
+        let merged: Vec<(u64, u64)> = spill.merge(&sum).map(|p| p.unwrap()).collect();
+        assert_eq!(merged.len(), 20);
+        for &(_, total) in &merged {
+            assert_eq!(total, 45);
+        }
+        let mut keys: Vec<u64> = merged.iter().map(|&(k, _)| k).collect();
+        keys.sort();
+        assert_eq!(keys, (0..20).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,355 @@
+//! The data tree: `{root}/{stage}/{wiki}/{date}/`, each date directory
+//! getting an `ok` marker once the stage completed for it.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path;
+
+use WikiResult;
+
+/// Environment variable pointing to the root of the data tree.
+pub const DATA_ROOT_VAR: &'static str = "WOLFRAMITE_DATA";
+/// Environment variable pointing to a config file.
+pub const CONFIG_VAR: &'static str = "WOLFRAMITE_CONFIG";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Stage {
+    /// raw dumps, as fetched from dumps.wikimedia.org
+    Download,
+    /// dumps recompressed with snappy
+    Snappy,
+    /// compiled (capnp) pages or entities
+    Cap,
+    /// compiled full-history revisions
+    History,
+    /// wikidata label lookup table
+    Labels,
+    /// wiki title and text lookup tables
+    Cdb,
+    Csv,
+    /// wikidata entities and statements as Parquet tables
+    Parquet,
+    Neo4j,
+}
+
+pub static STAGES: &'static [Stage] = &[Stage::Download,
+                                        Stage::Snappy,
+                                        Stage::Cap,
+                                        Stage::History,
+                                        Stage::Labels,
+                                        Stage::Cdb,
+                                        Stage::Csv,
+                                        Stage::Parquet,
+                                        Stage::Neo4j];
+
+impl Stage {
+    /// Directory name of the stage under the root.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Stage::Download => "download",
+            Stage::Snappy => "snappy",
+            Stage::Cap => "cap",
+            Stage::History => "history",
+            Stage::Labels => "labels",
+            Stage::Cdb => "cdb",
+            Stage::Csv => "csv",
+            Stage::Parquet => "parquet",
+            Stage::Neo4j => "neo4j",
+        }
+    }
+
+    pub fn from_name(name: &str) -> WikiResult<Stage> {
+        match STAGES.iter().find(|s| s.name() == name) {
+            Some(stage) => Ok(*stage),
+            None => Err(format!("unknown stage: {}", name))?,
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DataStore {
+    root: path::PathBuf,
+}
+
+impl DataStore {
+    pub fn new<P: AsRef<path::Path>>(root: P) -> DataStore {
+        DataStore { root: root.as_ref().to_path_buf() }
+    }
+
+    /// The store configured for this process: `$WOLFRAMITE_DATA` if set,
+    /// else the `data_dir` of the config file, else "data" in the current
+    /// directory.
+    ///
+    /// The config file is `$WOLFRAMITE_CONFIG`, or the first of
+    /// `./wolframite.conf` and `~/.wolframite.conf` that exists. It holds
+    /// `key = value` lines, `#` starting a comment.
+    pub fn from_env() -> WikiResult<DataStore> {
+        if let Ok(root) = env::var(DATA_ROOT_VAR) {
+            return Ok(DataStore::new(root));
+        }
+        if let Some(config) = config_file() {
+            if let Some(root) = try!(read_config_value(&*config, "data_dir")) {
+                return Ok(DataStore::new(root));
+            }
+        }
+        Ok(DataStore::new("data"))
+    }
+
+    pub fn root(&self) -> &path::Path {
+        &*self.root
+    }
+
+    /// Directory of a stage for a wiki at a date.
+    pub fn dir(&self, stage: Stage, wiki: &str, date: &str) -> String {
+        format!("{}/{}/{}/{}", self.root.display(), stage.name(), wiki, date)
+    }
+
+    pub fn is_complete(&self, stage: Stage, wiki: &str, date: &str) -> bool {
+        path::Path::new(&*self.dir(stage, wiki, date)).join("ok").exists()
+    }
+
+    /// Create the `ok` marker of a stage directory.
+    pub fn mark_complete(&self, stage: Stage, wiki: &str, date: &str) -> WikiResult<()> {
+        try!(fs::File::create(path::Path::new(&*self.dir(stage, wiki, date)).join("ok")));
+        Ok(())
+    }
+
+    /// Dates present for a stage and a wiki, complete or not, oldest first.
+    /// Only all-digit directory names are dates.
+    pub fn dates(&self, stage: Stage, wiki: &str) -> WikiResult<Vec<String>> {
+        let dir = self.wiki_dir(stage, wiki);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut dates = vec![];
+        for entry in try!(fs::read_dir(&dir)) {
+            let entry = try!(entry);
+            if let Some(name) = entry.file_name().to_str() {
+                if name.len() > 0 && name.chars().all(|c| c.is_digit(10)) &&
+                   try!(entry.file_type()).is_dir() {
+                    dates.push(name.to_string());
+                }
+            }
+        }
+        dates.sort();
+        Ok(dates)
+    }
+
+    /// Complete dates for a stage and a wiki, oldest first.
+    pub fn complete_dates(&self, stage: Stage, wiki: &str) -> WikiResult<Vec<String>> {
+        let dates = try!(self.dates(stage, wiki));
+        Ok(dates.into_iter().filter(|d| self.is_complete(stage, wiki, d)).collect())
+    }
+
+    /// Wikis having a directory for a stage.
+    pub fn wikis(&self, stage: Stage) -> WikiResult<Vec<String>> {
+        let dir = self.root.join(stage.name());
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut wikis = vec![];
+        for entry in try!(fs::read_dir(&dir)) {
+            let entry = try!(entry);
+            if try!(entry.file_type()).is_dir() {
+                if let Some(name) = entry.file_name().to_str() {
+                    wikis.push(name.to_string());
+                }
+            }
+        }
+        wikis.sort();
+        Ok(wikis)
+    }
+
+    /// Latest complete date for a stage and a wiki.
+    pub fn latest(&self, stage: Stage, wiki: &str) -> WikiResult<Option<String>> {
+        Ok(try!(self.complete_dates(stage, wiki)).pop())
+    }
+
+    /// Same as `latest`, but not finding anything is an error.
+    pub fn latest_or_err(&self, stage: Stage, wiki: &str) -> WikiResult<String> {
+        match try!(self.latest(stage, wiki)) {
+            Some(date) => Ok(date),
+            None => {
+                Err(format!("no complete \"{}\" data for {} in {}",
+                            stage,
+                            wiki,
+                            self.root.display()))?
+            }
+        }
+    }
+
+    /// Directory holding the dates of a stage for a wiki.
+    fn wiki_dir(&self, stage: Stage, wiki: &str) -> path::PathBuf {
+        self.root.join(stage.name()).join(wiki)
+    }
+
+    fn lock_path(&self, stage: Stage, wiki: &str, date: &str) -> path::PathBuf {
+        self.wiki_dir(stage, wiki).join(format!(".{}.lock", date))
+    }
+
+    pub fn is_locked(&self, stage: Stage, wiki: &str, date: &str) -> bool {
+        self.lock_path(stage, wiki, date).exists()
+    }
+
+    /// Take the lock of a stage for a wiki and a date, failing if another
+    /// run holds it. The lock is released when dropped.
+    pub fn lock(&self, stage: Stage, wiki: &str, date: &str) -> WikiResult<Lock> {
+        try!(fs::create_dir_all(self.wiki_dir(stage, wiki)));
+        let path = self.lock_path(stage, wiki, date);
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(Lock { path: path }),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(format!("{} {} {} is locked by another run (remove {:?} if there is none)",
+                            stage,
+                            wiki,
+                            date,
+                            path))?
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+
+    /// Staging directories left over by crashed runs (not covered by a
+    /// lock), as (date, path).
+    pub fn leftovers(&self, stage: Stage, wiki: &str) -> WikiResult<Vec<(String, String)>> {
+        let dir = self.wiki_dir(stage, wiki);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut found = vec![];
+        for entry in try!(fs::read_dir(&dir)) {
+            let entry = try!(entry);
+            let name = match entry.file_name().to_str() {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            if !name.starts_with(".") || !(name.ends_with(".tmp") || name.ends_with(".old")) {
+                continue;
+            }
+            let date = name[1..name.len() - 4].to_string();
+            if !self.is_locked(stage, wiki, &*date) {
+                found.push((date, format!("{}", entry.path().display())));
+            }
+        }
+        found.sort();
+        Ok(found)
+    }
+
+    /// Locked, empty, temporary directory to build a stage in. See
+    /// `Staging::commit`.
+    pub fn staging(&self, stage: Stage, wiki: &str, date: &str) -> WikiResult<Staging> {
+        let lock = try!(self.lock(stage, wiki, date));
+        let dir = self.wiki_dir(stage, wiki).join(format!(".{}.tmp", date));
+        if dir.exists() {
+            // leftover of a crashed run: we hold the lock, so it is not in use
+            try!(fs::remove_dir_all(&dir));
+        }
+        try!(fs::create_dir_all(&dir));
+        Ok(Staging {
+               store: self.clone(),
+               stage: stage,
+               wiki: wiki.to_string(),
+               date: date.to_string(),
+               dir: format!("{}", dir.display()),
+               committed: false,
+               _lock: lock,
+           })
+    }
+}
+
+/// A lock file, removed on drop.
+pub struct Lock {
+    path: path::PathBuf,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Stage output under construction, in a hidden sibling of the date
+/// directory (so date listings ignore it). Dropped without a commit, it is
+/// removed.
+pub struct Staging {
+    store: DataStore,
+    stage: Stage,
+    wiki: String,
+    date: String,
+    dir: String,
+    committed: bool,
+    _lock: Lock,
+}
+
+impl Staging {
+    pub fn dir(&self) -> &str {
+        &*self.dir
+    }
+
+    /// Mark the output complete and move it in place, replacing the
+    /// previous one. Readers see either the old or the new directory, both
+    /// complete, except for the instant between the two renames.
+    pub fn commit(mut self) -> WikiResult<()> {
+        try!(fs::File::create(path::Path::new(&*self.dir).join("ok")));
+        let target = self.store.dir(self.stage, &*self.wiki, &*self.date);
+        let old = self.store
+            .wiki_dir(self.stage, &*self.wiki)
+            .join(format!(".{}.old", self.date));
+        if old.exists() {
+            try!(fs::remove_dir_all(&old));
+        }
+        if path::Path::new(&*target).exists() {
+            try!(fs::rename(&*target, &old));
+        }
+        try!(fs::rename(&*self.dir, &*target));
+        self.committed = true;
+        if old.exists() {
+            try!(fs::remove_dir_all(&old));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_dir_all(&*self.dir);
+        }
+    }
+}
+
+fn config_file() -> Option<path::PathBuf> {
+    if let Ok(file) = env::var(CONFIG_VAR) {
+        return Some(path::PathBuf::from(file));
+    }
+    let mut candidates = vec![path::PathBuf::from("wolframite.conf")];
+    if let Some(home) = env::home_dir() {
+        candidates.push(home.join(".wolframite.conf"));
+    }
+    candidates.into_iter().find(|p| p.exists())
+}
+
+fn read_config_value(config: &path::Path, key: &str) -> WikiResult<Option<String>> {
+    let file = try!(fs::File::open(config)
+                        .map_err(|e| format!("can not read config {:?}: {}", config, e)));
+    for line in io::BufReader::new(file).lines() {
+        let line = try!(line);
+        let line = line.splitn(2, '#').next().unwrap();
+        let mut tokens = line.splitn(2, '=');
+        if let (Some(k), Some(v)) = (tokens.next(), tokens.next()) {
+            if k.trim() == key {
+                return Ok(Some(v.trim().to_string()));
+            }
+        }
+    }
+    Ok(None)
+}
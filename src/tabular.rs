@@ -0,0 +1,377 @@
+//! Tabular output for the exporters, with a header naming the columns:
+//!
+//! * CSV as in RFC 4180: fields holding a comma, a double quote, CR or LF are
+//!   quoted, with their double quotes doubled, and lines end with CRLF,
+//! * TSV, one line per row: backslash, tab, CR and LF are escaped as `\\`,
+//!   `\t`, `\r` and `\n`, as most TSV readers (PostgreSQL COPY, mysqlimport)
+//!   expect,
+//! * JSON Lines, one object per row, keyed by column name, and no header.
+//!
+//! Fields are written as they are otherwise: nothing is ever dropped.
+
+use std::io;
+use std::io::prelude::*;
+
+use serde_json;
+
+use WikiResult;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Tsv,
+    JsonLines,
+}
+
+pub const FORMATS: &'static [&'static str] = &["csv", "tsv", "jsonl"];
+
+impl Format {
+    pub fn from_name(name: &str) -> WikiResult<Format> {
+        match name {
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            "jsonl" => Ok(Format::JsonLines),
+            _ => {
+                Err(format!("unknown format {:?} (expected one of {})",
+                            name,
+                            FORMATS.join(", ")))?
+            }
+        }
+    }
+
+    /// File extension, without the dot.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Csv => "csv",
+            Format::Tsv => "tsv",
+            Format::JsonLines => "jsonl",
+        }
+    }
+}
+
+fn csv_field(field: &str, line: &mut String) {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\r' || c == '\n') {
+        line.push('"');
+        line.push_str(&*field.replace('"', "\"\""));
+        line.push('"');
+    } else {
+        line.push_str(field);
+    }
+}
+
+fn tsv_field(field: &str, line: &mut String) {
+    for c in field.chars() {
+        match c {
+            '\\' => line.push_str("\\\\"),
+            '\t' => line.push_str("\\t"),
+            '\r' => line.push_str("\\r"),
+            '\n' => line.push_str("\\n"),
+            c => line.push(c),
+        }
+    }
+}
+
+/// A row (or the header) as a complete line of `format`.
+pub fn format_row<S: AsRef<str>>(format: Format,
+                                 columns: &[String],
+                                 row: &[S])
+                                 -> WikiResult<String> {
+    if row.len() != columns.len() {
+        Err(format!("row of {} fields for {} columns", row.len(), columns.len()))?
+    }
+    let mut line = String::new();
+    match format {
+        Format::Csv => {
+            for (i, field) in row.iter().enumerate() {
+                if i > 0 {
+                    line.push(',');
+                }
+                csv_field(field.as_ref(), &mut line);
+            }
+            line.push_str("\r\n");
+        }
+        Format::Tsv => {
+            for (i, field) in row.iter().enumerate() {
+                if i > 0 {
+                    line.push('\t');
+                }
+                tsv_field(field.as_ref(), &mut line);
+            }
+            line.push('\n');
+        }
+        Format::JsonLines => {
+            line.push('{');
+            for (i, (column, field)) in columns.iter().zip(row.iter()).enumerate() {
+                if i > 0 {
+                    line.push(',');
+                }
+                line.push_str(&*try!(serde_json::to_string(column)));
+                line.push(':');
+                line.push_str(&*try!(serde_json::to_string(field.as_ref())));
+            }
+            line.push_str("}\n");
+        }
+    }
+    Ok(line)
+}
+
+/// Writes rows of a fixed set of columns.
+pub struct TableWriter<W: Write> {
+    out: W,
+    format: Format,
+    columns: Vec<String>,
+}
+
+impl<W: Write> TableWriter<W> {
+    /// A table writer on `out`, the header written already.
+    pub fn new(out: W, format: Format, columns: &[&str]) -> WikiResult<TableWriter<W>> {
+        let mut writer = TableWriter::headless(out, format, columns);
+        if format != Format::JsonLines {
+            let header = try!(format_row(format, &*writer.columns, columns));
+            try!(writer.out.write_all(header.as_bytes()));
+        }
+        Ok(writer)
+    }
+
+    /// A table writer with no header, for parts of a table whose header
+    /// goes to a file of its own.
+    pub fn headless(out: W, format: Format, columns: &[&str]) -> TableWriter<W> {
+        TableWriter {
+            out: out,
+            format: format,
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &*self.columns
+    }
+
+    pub fn write_row<S: AsRef<str>>(&mut self, row: &[S]) -> WikiResult<()> {
+        let line = try!(format_row(self.format, &*self.columns, row));
+        try!(self.out.write_all(line.as_bytes()));
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> WikiResult<()> {
+        try!(self.out.flush());
+        Ok(())
+    }
+}
+
+/// Reads back rows written by a `TableWriter` with the same columns. The
+/// header is checked, and not returned.
+pub struct TableReader<R: BufRead> {
+    input: R,
+    format: Format,
+    columns: Vec<String>,
+}
+
+impl<R: BufRead> TableReader<R> {
+    pub fn new(input: R, format: Format, columns: &[&str]) -> WikiResult<TableReader<R>> {
+        let mut reader = TableReader {
+            input: input,
+            format: format,
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+        };
+        if format != Format::JsonLines {
+            let header = try!(reader.read_row());
+            if header.as_ref() != Some(&reader.columns) {
+                Err(format!("expected a header of {:?}, found {:?}", reader.columns, header))?
+            }
+        }
+        Ok(reader)
+    }
+
+    fn read_row(&mut self) -> WikiResult<Option<Vec<String>>> {
+        let mut line = String::new();
+        if try!(self.input.read_line(&mut line)) == 0 {
+            return Ok(None);
+        }
+        match self.format {
+            Format::Csv => {
+                // a quoted field may hold line breaks: read on until the
+                // quotes balance
+                while line.chars().filter(|&c| c == '"').count() % 2 == 1 {
+                    if try!(self.input.read_line(&mut line)) == 0 {
+                        Err("unterminated quoted CSV field")?
+                    }
+                }
+                let line = line.trim_right_matches('\n').trim_right_matches('\r');
+                parse_csv(line).map(Some)
+            }
+            Format::Tsv => {
+                let line = line.trim_right_matches('\n');
+                line.split('\t').map(unescape_tsv).collect::<WikiResult<Vec<String>>>().map(Some)
+            }
+            Format::JsonLines => {
+                let value: serde_json::Value = try!(serde_json::from_str(&*line));
+                let mut row = vec![];
+                for column in &self.columns {
+                    match value.get(column).and_then(|v| v.as_str()) {
+                        Some(field) => row.push(field.to_string()),
+                        None => Err(format!("no string {:?} in {}", column, line.trim()))?,
+                    }
+                }
+                Ok(Some(row))
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TableReader<R> {
+    type Item = WikiResult<Vec<String>>;
+
+    fn next(&mut self) -> Option<WikiResult<Vec<String>>> {
+        match self.read_row() {
+            Ok(Some(row)) => {
+                if row.len() == self.columns.len() {
+                    Some(Ok(row))
+                } else {
+                    Some(Err(format!("row of {} fields for {} columns",
+                                     row.len(),
+                                     self.columns.len())
+                                 .into()))
+                }
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn parse_csv(line: &str) -> WikiResult<Vec<String>> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(::std::mem::replace(&mut field, String::new())),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        Err("unterminated quoted CSV field")?
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+fn unescape_tsv(field: &str) -> WikiResult<String> {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => result.push('\\'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('n') => result.push('\n'),
+            other => Err(format!("bad TSV escape \\{:?} in {:?}", other, field))?,
+        }
+    }
+    Ok(result)
+}
+
+/// Buffered stdout, for the exporters writing there.
+pub fn stdout_writer(format: Format,
+                     columns: &[&str])
+                     -> WikiResult<TableWriter<io::BufWriter<io::Stdout>>> {
+    TableWriter::new(io::BufWriter::new(io::stdout()), format, columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels() -> Vec<&'static str> {
+        vec!["Douglas Adams",
+             "Douglas \"Doug\" Adams",
+             "\"",
+             "\"\"quoted\"\"",
+             "Paris, France",
+             ",leading and trailing,",
+             "l'apostrophe d'Ulysse",
+             "tab\there",
+             "line\nbreak",
+             "windows\r\nline",
+             "ends with a newline\n",
+             "\\t is not a tab, C:\\path\\",
+             "",
+             "  spaces  ",
+             "Москва, Россия",
+             "北京市\t中华人民共和国",
+             "東京都「とうきょうと」",
+             "القاهرة، مصر",
+             "תל אביב-יפו",
+             "नई दिल्ली, \"भारत\"",
+             "Ἀθῆναι\nἙλλάς",
+             "서울특별시",
+             "กรุงเทพมหานคร",
+             "🦀, \"crab\"\t🦀"]
+    }
+
+    fn round_trip(format: Format) {
+        let columns = ["id", "label,with \"quotes\"", "description"];
+        let rows: Vec<Vec<String>> = labels()
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                     vec![format!("Q{}", i),
+                          label.to_string(),
+                          labels()[labels().len() - 1 - i].to_string()]
+                 })
+            .collect();
+        let mut out = vec![];
+        {
+            let mut writer = TableWriter::new(&mut out, format, &columns).unwrap();
+            for row in &rows {
+                writer.write_row(&row[..]).unwrap();
+            }
+        }
+        let reader = TableReader::new(&*out, format, &columns).unwrap();
+        let read: Vec<Vec<String>> = reader.map(|r| r.unwrap()).collect();
+        assert_eq!(read, rows);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        round_trip(Format::Csv);
+    }
+
+    #[test]
+    fn tsv_round_trip() {
+        round_trip(Format::Tsv);
+    }
+
+    #[test]
+    fn jsonl_round_trip() {
+        round_trip(Format::JsonLines);
+    }
+
+    #[test]
+    fn csv_is_rfc_4180() {
+        let columns = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(format_row(Format::Csv, &*columns, &["x", "y, \"z\""][..]).unwrap(),
+                   "x,\"y, \"\"z\"\"\"\r\n");
+        assert_eq!(format_row(Format::Tsv, &*columns, &["x\ty", "z\n"][..]).unwrap(),
+                   "x\\ty\tz\\n\n");
+    }
+}
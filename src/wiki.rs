@@ -1,38 +1,85 @@
 use WikiError;
 use WikiResult;
 use BoxedIter;
+use mapred::Record;
+use blocks;
 use helpers;
+use store::{DataStore, Stage};
 
 use std::io;
 use std::fs;
+use std::path;
 use std::error::Error;
-
-use snappy_framed::read::SnappyFramedDecoder;
-use snappy_framed::read::CrcMode::Ignore;
+use std::sync::Mutex;
 
 use capnp;
 use capnp::serialize_packed;
+use capnp::serialize;
 use capnp::serialize::OwnedSegments;
-use capnp::message::Reader;
+use capnp::message::{Allocator, Builder, Reader};
+
+use tinycdb::Cdb;
 
 pub use capn_wiki::wiki_capnp::page as Page;
+pub use capn_wiki::wiki_capnp::revision as Revision;
+pub use capn_wiki::wiki_capnp::contributor as Contributor;
+use capn_wiki::wiki_capnp::site_info;
+use capn_wiki::wiki_capnp::Case;
+
+/// Part files matching a glob, in name order.
+fn parts(glob: &str) -> WikiResult<Vec<::std::path::PathBuf>> {
+    let mut parts = vec![];
+    for part in try!(::glob::glob(glob)) {
+        parts.push(try!(part));
+    }
+    Ok(parts)
+}
 
 pub struct Wiki {
+    store: DataStore,
     wiki: String,
     date: String,
 }
 
 impl Wiki {
-    pub fn for_date(wiki: &str, date: &str) -> WikiResult<Wiki> {
+    pub fn for_date(store: &DataStore, wiki: &str, date: &str) -> WikiResult<Wiki> {
         Ok(Wiki {
+               store: store.clone(),
                wiki: wiki.to_string(),
                date: date.to_string(),
            })
     }
 
-    pub fn latest_compiled(wiki: &str) -> WikiResult<Wiki> {
-        let date = helpers::latest("cap", wiki).unwrap().unwrap();
-        Wiki::for_date(wiki, &*date)
+    pub fn latest_compiled(store: &DataStore, wiki: &str) -> WikiResult<Wiki> {
+        let date = try!(store.latest_or_err(Stage::Cap, wiki));
+        Wiki::for_date(store, wiki, &*date)
+    }
+
+    pub fn name(&self) -> &str {
+        &*self.wiki
+    }
+
+    pub fn date(&self) -> &str {
+        &*self.date
+    }
+
+    /// Directory of the raw dump files this compilation comes from.
+    pub fn download_dir(&self) -> String {
+        self.store.dir(Stage::Download, &*self.wiki, &*self.date)
+    }
+
+    /// Site metadata (name, base url, namespaces) captured from the dump
+    /// header by capitanize.
+    pub fn siteinfo(&self) -> WikiResult<SiteInfo> {
+        let cap_root = self.store.dir(Stage::Cap, &*self.wiki, &*self.date);
+        let glob = cap_root.clone() + "/*-siteinfo.cap";
+        let file = try!(try!(::glob::glob(&glob))
+                            .next()
+                            .ok_or(format!("no siteinfo found in {}", cap_root)));
+        let file = try!(fs::File::open(try!(file)));
+        let message = try!(serialize_packed::read_message(&mut io::BufReader::new(file),
+                                                          capnp::message::ReaderOptions::new()));
+        SiteInfo::from_reader(try!(message.get_root::<site_info::Reader>()))
     }
 
     pub fn page_iter(&self) -> WikiResult<BoxedIter<WikiResult<MessageAndPage>>> {
@@ -41,30 +88,317 @@ impl Wiki {
     }
 
     pub fn page_iter_iter(&self) -> WikiResult<BoxedIter<BoxedIter<WikiResult<MessageAndPage>>>> {
-        let cap_root = helpers::data_dir_for("cap", &*self.wiki, &*self.date);
-        let glob = cap_root.clone() + "/*cap.snap";
-        let mut readers: Vec<BoxedIter<WikiResult<MessageAndPage>>> = vec![];
-        for file in try!(::glob::glob(&glob)) {
-            let file = file.unwrap();
-            readers
-                .push(Box::new(PagesReader::new(SnappyFramedDecoder::new(fs::File::open(file)
-                                                                             .unwrap(),
-                                                                         Ignore))));
+        let cap_root = self.store.dir(Stage::Cap, &*self.wiki, &*self.date);
+        let parts = try!(parts(&*(cap_root + "/*cap.snap")));
+        let splits = try!(blocks::splits(parts, blocks::SPLIT_BYTES));
+        Ok(Box::new(blocks::split_iters(splits, PagesReader::new).into_iter()))
+    }
+}
+
+/// Full-history compilation of a wiki (see `capitanize_wiki::process_history`).
+pub struct WikiHistory {
+    store: DataStore,
+    wiki: String,
+    date: String,
+    /// page id to "part offset" of its first revision, absent from
+    /// histories compiled before there was one
+    pages: Option<Mutex<Box<Cdb>>>,
+}
+
+impl WikiHistory {
+    pub fn for_date(store: &DataStore, wiki: &str, date: &str) -> WikiResult<WikiHistory> {
+        let pages_file = store.dir(Stage::History, wiki, date) + "/pages";
+        let pages = if path::Path::new(&*pages_file).exists() {
+            Some(Mutex::new(try!(Cdb::open(path::Path::new(&*pages_file))
+                                     .map_err(|e| format!("Cdb Error: {:?}", e)))))
+        } else {
+            None
+        };
+        Ok(WikiHistory {
+               store: store.clone(),
+               wiki: wiki.to_string(),
+               date: date.to_string(),
+               pages: pages,
+           })
+    }
+
+    pub fn latest_compiled(store: &DataStore, wiki: &str) -> WikiResult<WikiHistory> {
+        let date = try!(store.latest_or_err(Stage::History, wiki));
+        WikiHistory::for_date(store, wiki, &*date)
+    }
+
+    pub fn revision_iter(&self) -> WikiResult<BoxedIter<WikiResult<MessageAndRevision>>> {
+        let it = try!(self.revision_iter_iter());
+        Ok(Box::new(it.flat_map(|i| i)))
+    }
+
+    pub fn revision_iter_iter(&self)
+                              -> WikiResult<BoxedIter<BoxedIter<WikiResult<MessageAndRevision>>>> {
+        let hist_root = self.store.dir(Stage::History, &*self.wiki, &*self.date);
+        let parts = try!(parts(&*(hist_root + "/*hist.snap")));
+        let splits = try!(blocks::splits(parts, blocks::SPLIT_BYTES));
+        Ok(Box::new(blocks::split_iters(splits, RevisionsReader::new).into_iter()))
+    }
+
+    /// Where the first revision of a page is: its part file, and the offset
+    /// of the block holding it.
+    fn page_location(&self, page_id: u64) -> WikiResult<Option<(path::PathBuf, u64)>> {
+        let pages = match self.pages {
+            Some(ref pages) => pages,
+            None => return Ok(None),
+        };
+        let mut lock = pages.lock().unwrap();
+        let location = match (*lock).find(page_id.to_string().as_bytes()) {
+            Some(location) => {
+                try!(::std::str::from_utf8(location).map_err(|e| e.to_string())).to_string()
+            }
+            None => return Ok(None),
+        };
+        let mut fields = location.split(' ');
+        match (fields.next(), fields.next().and_then(|o| o.parse().ok())) {
+            (Some(part), Some(offset)) => {
+                let hist_root = self.store.dir(Stage::History, &*self.wiki, &*self.date);
+                Ok(Some((path::Path::new(&hist_root).join(part), offset)))
+            }
+            _ => Err(format!("bad page location {:?} for {}", location, page_id))?,
+        }
+    }
+
+    /// Revisions from a page location on: those of the page, then on over
+    /// the next parts in case the page runs past the end of its part.
+    fn revisions_from(&self,
+                      part: path::PathBuf,
+                      offset: u64)
+                      -> WikiResult<BoxedIter<WikiResult<MessageAndRevision>>> {
+        let hist_root = self.store.dir(Stage::History, &*self.wiki, &*self.date);
+        let next_parts: Vec<blocks::Split> = try!(parts(&*(hist_root + "/*hist.snap")))
+            .into_iter()
+            .filter(|p| *p > part)
+            .map(|p| {
+                     blocks::Split {
+                         part: p,
+                         offset: 0,
+                         length: None,
+                     }
+                 })
+            .collect();
+        let first = blocks::Split {
+            part: part,
+            offset: offset,
+            length: None,
+        };
+        let mut splits = vec![first];
+        splits.extend(next_parts);
+        let it = splits.into_iter().flat_map(|split| -> BoxedIter<WikiResult<MessageAndRevision>> {
+            match split.open() {
+                Ok(input) => Box::new(RevisionsReader::new(input)),
+                Err(e) => Box::new(Some(Err(e)).into_iter()),
+            }
+        });
+        Ok(Box::new(it))
+    }
+
+    /// All revisions of a page, oldest first. Pages are contiguous in the
+    /// history dumps, so the read stops right after the page. Histories
+    /// compiled with a `pages` index are read from where the page starts,
+    /// others from the beginning.
+    pub fn page_revisions(&self, page_id: u64) -> WikiResult<Vec<MessageAndRevision>> {
+        let revisions = match try!(self.page_location(page_id)) {
+            Some((part, offset)) => try!(self.revisions_from(part, offset)),
+            None if self.pages.is_some() => return Ok(vec![]),
+            None => try!(self.revision_iter()),
+        };
+        let mut found: Vec<(String, MessageAndRevision)> = vec![];
+        for revision in revisions {
+            let revision = try!(revision);
+            let (this_page, timestamp) = {
+                let reader = try!(revision.as_revision_reader());
+                (reader.get_page_id(), try!(reader.get_timestamp()).to_string())
+            };
+            if this_page == page_id {
+                found.push((timestamp, revision));
+            } else if found.len() > 0 {
+                break;
+            }
+        }
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(found.into_iter().map(|pair| pair.1).collect())
+    }
+
+    /// The revision of the page that was current at `timestamp` (an ISO 8601
+    /// prefix like "2015-06-01" or "2015-06-01T12:00:00Z").
+    ///
+    /// Revision timestamps are compared with `timestamp` as strings, and a
+    /// full timestamp sorts after its own date prefix: "2015-06-01" gives
+    /// the revision current at the start of that day, none of the edits
+    /// made on the day. Pass "2015-06-01T23:59:59Z" to include them.
+    pub fn page_as_of(&self,
+                      page_id: u64,
+                      timestamp: &str)
+                      -> WikiResult<Option<MessageAndRevision>> {
+        let mut result = None;
+        for revision in try!(self.page_revisions(page_id)) {
+            if try!(try!(revision.as_revision_reader()).get_timestamp()) > timestamp {
+                break;
+            }
+            result = Some(revision);
         }
-        Ok(Box::new(readers.into_iter()))
+        Ok(result)
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TitleCase {
+    FirstLetter,
+    CaseSensitive,
+}
+
+impl TitleCase {
+    fn from_capnp(case: Case) -> TitleCase {
+        match case {
+            Case::FirstLetter => TitleCase::FirstLetter,
+            Case::CaseSensitive => TitleCase::CaseSensitive,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    pub id: i16,
+    pub canonical: String,
+    pub name: String,
+    pub case: TitleCase,
+}
+
+#[derive(Debug, Clone)]
+pub struct SiteInfo {
+    pub sitename: String,
+    pub dbname: String,
+    pub base: String,
+    pub generator: String,
+    pub case: TitleCase,
+    pub namespaces: Vec<Namespace>,
+}
+
+impl SiteInfo {
+    fn from_reader(reader: site_info::Reader) -> WikiResult<SiteInfo> {
+        let mut namespaces = vec![];
+        for ns in try!(reader.get_namespaces()).iter() {
+            namespaces.push(Namespace {
+                                id: ns.get_id(),
+                                canonical: try!(ns.get_canonical()).to_string(),
+                                name: try!(ns.get_name()).to_string(),
+                                case: TitleCase::from_capnp(try!(ns.get_case())),
+                            });
+        }
+        Ok(SiteInfo {
+               sitename: try!(reader.get_sitename()).to_string(),
+               dbname: try!(reader.get_dbname()).to_string(),
+               base: try!(reader.get_base()).to_string(),
+               generator: try!(reader.get_generator()).to_string(),
+               case: TitleCase::from_capnp(try!(reader.get_case())),
+               namespaces: namespaces,
+           })
+    }
+
+    /// Lookup a namespace by canonical or local name ("Category", "Catégorie").
+    /// Case and underscores are ignored.
+    pub fn namespace(&self, name: &str) -> Option<&Namespace> {
+        let wanted = normalize_namespace_name(name);
+        self.namespaces
+            .iter()
+            .find(|ns| {
+                      normalize_namespace_name(&*ns.canonical) == wanted ||
+                      normalize_namespace_name(&*ns.name) == wanted
+                  })
+    }
+
+    pub fn namespace_by_id(&self, id: i16) -> Option<&Namespace> {
+        self.namespaces.iter().find(|ns| ns.id == id)
+    }
+
+    pub fn namespace_id(&self, name: &str) -> Option<i16> {
+        self.namespace(name).map(|ns| ns.id)
+    }
+}
+
+fn normalize_namespace_name(name: &str) -> String {
+    name.trim().replace("_", " ").to_lowercase()
+}
+
 pub struct MessageAndPage {
     message: Reader<OwnedSegments>,
 }
 
 impl MessageAndPage {
+    /// Freeze a freshly compiled page into the same form the `cap` files
+    /// are read into.
+    pub fn from_builder<A: Allocator>(builder: &Builder<A>) -> WikiResult<MessageAndPage> {
+        let mut buffer: Vec<u8> = vec![];
+        try!(serialize::write_message(&mut buffer, builder));
+        let message = try!(serialize::read_message(&mut io::Cursor::new(buffer),
+                                                   capnp::message::ReaderOptions::new()));
+        Ok(MessageAndPage { message: message })
+    }
+
     pub fn as_page_reader(&self) -> WikiResult<Page::Reader> {
         self.message.get_root().map_err(|e| WikiError::from(e))
     }
 }
 
+impl Record for WikiResult<MessageAndPage> {
+    fn record_id(&self) -> Option<String> {
+        let page = match *self {
+            Ok(ref page) => page,
+            Err(_) => return None,
+        };
+        page.as_page_reader()
+            .and_then(|p| Ok(try!(p.get_title()).to_string()))
+            .ok()
+    }
+}
+
+pub struct MessageAndRevision {
+    message: Reader<OwnedSegments>,
+}
+
+impl MessageAndRevision {
+    pub fn as_revision_reader(&self) -> WikiResult<Revision::Reader> {
+        self.message.get_root().map_err(|e| WikiError::from(e))
+    }
+}
+
+/// Whether an account name follows the bot naming convention: a word of
+/// the name is "bot" (any case, "Bot 2" or "bot_42" too), or ends with a
+/// capitalized "Bot" or "BOT" ("ClueBot NG", "AnomieBOT"). A heuristic:
+/// XML dumps carry no user groups, so flagged bots with other names
+/// ("Cydebot") are missed, while "Talbot" or "Abbot" are not taken for bots.
+pub fn is_bot_name(username: &str) -> bool {
+    username.split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.trim_right_matches(|c: char| c.is_digit(10)))
+        .any(|word| {
+            word.to_lowercase() == "bot" || word.ends_with("Bot") || word.ends_with("BOT")
+        })
+}
+
+/// Bot edits are recognized by the account name, see `is_bot_name`.
+pub fn is_bot_edit(revision: Revision::Reader) -> WikiResult<bool> {
+    match try!(try!(revision.get_contributor()).which()) {
+        Contributor::User(user) => Ok(is_bot_name(try!(user.get_username()))),
+        _ => Ok(false),
+    }
+}
+
+/// Check the page text against the revision sha1 from the dump. None for
+/// redirects, whose text is not kept.
+pub fn verify_sha1(page: Page::Reader) -> WikiResult<Option<bool>> {
+    let expected = try!(try!(page.get_revision()).get_sha1());
+    match try!(page.which()) {
+        Page::Text(text) => Ok(Some(helpers::sha1_base36(try!(text).as_bytes()) == expected)),
+        Page::Redirect(_) => Ok(None),
+    }
+}
+
 pub struct PagesReader<R: io::Read> {
     options: capnp::message::ReaderOptions,
     stream: io::BufReader<R>,
@@ -95,3 +429,78 @@ impl<R: io::Read> Iterator for PagesReader<R> {
         }
     }
 }
+
+pub struct RevisionsReader<R: io::Read> {
+    options: capnp::message::ReaderOptions,
+    stream: io::BufReader<R>,
+}
+
+impl<R: io::Read> RevisionsReader<R> {
+    pub fn new(r: R) -> RevisionsReader<R> {
+        RevisionsReader {
+            options: capnp::message::ReaderOptions::new(),
+            stream: io::BufReader::new(r),
+        }
+    }
+}
+
+impl<R: io::Read> Iterator for RevisionsReader<R> {
+    type Item = WikiResult<MessageAndRevision>;
+
+    fn next(&mut self) -> Option<WikiResult<MessageAndRevision>> {
+        match serialize_packed::read_message(&mut self.stream, self.options) {
+            Ok(msg) => Some(Ok(MessageAndRevision { message: msg })),
+            Err(err) => {
+                if err.description().contains("Premature EOF") {
+                    return None;
+                } else {
+                    return Some(Err(WikiError::from(err)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(text: Option<&str>, sha1: &str) -> Builder<capnp::message::HeapAllocator> {
+        let mut message = Builder::new_default();
+        {
+            let mut page = message.init_root::<Page::Builder>();
+            match text {
+                Some(text) => page.set_text(text),
+                None => page.set_redirect("Albert Einstein"),
+            }
+            page.init_revision().set_sha1(sha1);
+        }
+        message
+    }
+
+    #[test]
+    fn sha1() {
+        let text = "'''Zürich''' is the largest city in Switzerland.";
+        let good = page(Some(text), "mj88i4c2o0cd8mwjfhryke7ewfduc1z");
+        let bad = page(Some("vandalized"), "mj88i4c2o0cd8mwjfhryke7ewfduc1z");
+        let redirect = page(None, "2909q6owlhq2w7vmk1lfqbur72n8361");
+        let check = |message: &Builder<capnp::message::HeapAllocator>| {
+            let message = MessageAndPage::from_builder(message).unwrap();
+            verify_sha1(message.as_page_reader().unwrap()).unwrap()
+        };
+        assert_eq!(check(&good), Some(true));
+        assert_eq!(check(&bad), Some(false));
+        assert_eq!(check(&redirect), None);
+    }
+
+    #[test]
+    fn bot_names() {
+        for bot in &["ClueBot NG", "AnomieBOT", "SineBot", "Bot1058", "Some bot",
+                     "Helper_Bot_2"] {
+            assert!(is_bot_name(bot), "{} is a bot", bot);
+        }
+        for human in &["Talbot", "Abbot", "Jimbo Wales", "Botticelli fan", "Robotics"] {
+            assert!(!is_bot_name(human), "{} is not a bot", human);
+        }
+    }
+}
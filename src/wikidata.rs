@@ -4,15 +4,18 @@ use std::error::Error;
 
 use std::sync::Mutex;
 
-use helpers;
+use store::{DataStore, Stage};
 
 use capnp;
 use capnp::{text, traits};
-use capnp::message::Reader;
+use capnp::message::{Allocator, Builder, Reader};
+use capnp::serialize;
 use capnp::serialize::OwnedSegments;
 use WikiError;
 use WikiResult;
 use BoxedIter;
+use mapred::Record;
+use blocks;
 
 pub use capn_wiki::wiki_capnp::page as Page;
 pub use capn_wiki::wiki_capnp::entity as Entity;
@@ -36,52 +39,74 @@ pub type EntityIter = Iterator<Item = WikiResult<EntityMessage>> + Send;
 pub type EntityIterIter = Iterator<Item = Box<EntityIter>> + Send;
 
 pub struct Wikidata {
+    store: DataStore,
     pub date: String,
     labels: Mutex<Box<Cdb>>,
+    /// absent from label tables built before there was one
+    ids: Option<Mutex<Box<Cdb>>>,
 }
 
 impl Wikidata {
-    fn for_date(date: &str) -> WikiResult<Wikidata> {
-        let labels_file = helpers::data_dir_for("labels", "wikidata", date) + "/labels";
+    pub fn for_date(store: &DataStore, date: &str) -> WikiResult<Wikidata> {
+        let labels_file = store.dir(Stage::Labels, "wikidata", date) + "/labels";
         let labels =
             try!(Cdb::open(path::Path::new(&*labels_file)).map_err(|e| {
                                                                        format!("Cdb Error: {:?}", e)
                                                                    }));
+        let ids_file = store.dir(Stage::Labels, "wikidata", date) + "/ids";
+        let ids = if path::Path::new(&*ids_file).exists() {
+            Some(Mutex::new(try!(Cdb::open(path::Path::new(&*ids_file))
+                                     .map_err(|e| format!("Cdb Error: {:?}", e)))))
+        } else {
+            None
+        };
         Ok(Wikidata {
+               store: store.clone(),
                date: date.to_string(),
                labels: Mutex::new(labels),
+               ids: ids,
            })
     }
 
-    pub fn latest_compiled() -> WikiResult<Wikidata> {
-        let date1 = helpers::latest("labels", "wikidata").unwrap().unwrap();
-        let date2 = helpers::latest("cap", "wikidata").unwrap().unwrap();
-        if date1 != date2 {
-            Err("latest wikidate seems only partially compiled".to_string())?
-        } else {
-            Wikidata::for_date(&*date1)
+    /// Latest date for which both the entities and the labels are compiled.
+    pub fn latest_compiled(store: &DataStore) -> WikiResult<Wikidata> {
+        let labels = try!(store.complete_dates(Stage::Labels, "wikidata"));
+        let date = try!(store.complete_dates(Stage::Cap, "wikidata"))
+            .into_iter()
+            .filter(|d| labels.contains(d))
+            .last();
+        match date {
+            Some(date) => Wikidata::for_date(store, &*date),
+            None => {
+                Err(format!("no wikidata with both cap and labels complete in {}",
+                            store.root().display()))?
+            }
         }
     }
 
     // "static" iterators
-    pub fn cap_files_for_date(date: &str) -> WikiResult<BoxedIter<WikiResult<path::PathBuf>>> {
-        let cap_root = helpers::data_dir_for("cap", "wikidata", date);
+    pub fn cap_files_for_date(store: &DataStore,
+                              date: &str)
+                              -> WikiResult<BoxedIter<WikiResult<path::PathBuf>>> {
+        let cap_root = store.dir(Stage::Cap, "wikidata", date);
         let glob = cap_root.clone() + "/*cap.gz";
         Ok(Box::new(try!(::glob::glob(&glob)).map(|f| f.map_err(|e| WikiError::from(e)))))
     }
 
-    pub fn entity_iter_for_date(date: &str) -> WikiResult<BoxedIter<WikiResult<EntityMessage>>> {
-        Ok(Box::new(try!(Wikidata::entity_iter_iter_for_date(date)).flat_map(|it| it)))
+    pub fn entity_iter_for_date(store: &DataStore,
+                                date: &str)
+                                -> WikiResult<BoxedIter<WikiResult<EntityMessage>>> {
+        Ok(Box::new(try!(Wikidata::entity_iter_iter_for_date(store, date)).flat_map(|it| it)))
     }
 
     pub fn entity_iter_iter_for_date
-        (date: &str)
+        (store: &DataStore,
+         date: &str)
          -> WikiResult<BoxedIter<BoxedIter<WikiResult<EntityMessage>>>> {
-        let mut files: Vec<Box<EntityIter>> = vec![];
-        for entry in try!(Wikidata::cap_files_for_date(date)) {
-            files.push(try!(Wikidata::entity_iter_for_file(try!(entry))));
-        }
-        Ok(Box::new(files.into_iter()))
+        let parts: Vec<path::PathBuf> =
+            try!(try!(Wikidata::cap_files_for_date(store, date)).collect());
+        let splits = try!(blocks::splits(parts, blocks::SPLIT_BYTES));
+        Ok(Box::new(blocks::split_iters(splits, EntityReader::for_reader).into_iter()))
     }
 
     pub fn entity_iter_for_file(filename: path::PathBuf)
@@ -96,15 +121,15 @@ impl Wikidata {
 
     // members iterators
     pub fn cap_files(&self) -> WikiResult<BoxedIter<WikiResult<path::PathBuf>>> {
-        Wikidata::cap_files_for_date(&self.date)
+        Wikidata::cap_files_for_date(&self.store, &self.date)
     }
 
     pub fn entity_iter(&self) -> WikiResult<BoxedIter<WikiResult<EntityMessage>>> {
-        Wikidata::entity_iter_for_date(&self.date)
+        Wikidata::entity_iter_for_date(&self.store, &self.date)
     }
 
     pub fn entity_iter_iter(&self) -> WikiResult<BoxedIter<BoxedIter<WikiResult<EntityMessage>>>> {
-        Wikidata::entity_iter_iter_for_date(&self.date)
+        Wikidata::entity_iter_iter_for_date(&self.store, &self.date)
     }
 
     pub fn get_label(&self, key: &str) -> Option<String> {
@@ -114,6 +139,38 @@ impl Wikidata {
             .map(|x| ::std::str::from_utf8(x).unwrap().to_string())
     }
 
+    /// Name of the part file holding an entity.
+    pub fn part_of(&self, id: &str) -> WikiResult<Option<String>> {
+        let ids = try!(self.ids
+                           .as_ref()
+                           .ok_or(format!("no id index for wikidata {}, rebuild it with \
+                                           wikidata_cdb",
+                                          self.date)));
+        let mut lock = ids.lock().unwrap();
+        match (*lock).find(id.as_bytes()) {
+            Some(part) => Ok(Some(try!(::std::str::from_utf8(part).map_err(|e| e.to_string()))
+                                      .to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Look an entity up, scanning only the part file holding it.
+    pub fn get_entity(&self, id: &str) -> WikiResult<Option<EntityMessage>> {
+        let part = match try!(self.part_of(id)) {
+            Some(part) => part,
+            None => return Ok(None),
+        };
+        let file = path::PathBuf::from(self.store.dir(Stage::Cap, "wikidata", &*self.date))
+            .join(part);
+        for entity in try!(Wikidata::entity_iter_for_file(file)) {
+            let entity = try!(entity);
+            if try!(entity.get_id()) == id {
+                return Ok(Some(entity));
+            }
+        }
+        Ok(None)
+    }
+
     pub fn triplets_iter_iter(&self) -> WikiResult<BoxedIter<BoxedIter<WikidataTriplet>>> {
         Ok(Box::new(try!(self.entity_iter_iter()).map(
             |entity_iter:Box<EntityIter>| -> Box<Iterator<Item=WikidataTriplet>+Send> {
@@ -184,7 +241,7 @@ impl <'a,V> MapWrapper<V> for Map::Reader<'a,text::Owned,V>
 }
 */
 
-#[derive(Clone,Copy,PartialEq,Debug,Hash,Eq)]
+#[derive(Clone,Copy,PartialEq,Debug,Hash,Eq,PartialOrd,Ord,Serialize,Deserialize)]
 pub enum EntityRef {
     Property(u32),
     Item(u32),
@@ -238,10 +295,152 @@ impl ::std::hash::Hash for EntityRef {
 */
 
 
+/// A sitelink, detached from its message. Badges are item ids (like
+/// "Q17437796" for featured articles).
+#[derive(Clone, Debug, PartialEq)]
+pub struct SitelinkEntry {
+    pub site: String,
+    pub title: String,
+    pub badges: Vec<String>,
+}
+
+/// A snak value, detached from its message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClaimValue {
+    Entity(EntityRef),
+    String(String),
+    Time {
+        time: String,
+        precision: u8,
+        calendar: String,
+    },
+    Quantity { amount: f64, unit: String },
+    Coordinate {
+        latitude: f64,
+        longitude: f64,
+        globe: String,
+    },
+    Text { language: String, text: String },
+}
+
+impl ClaimValue {
+    fn of(value: DataValue::Reader) -> WikiResult<ClaimValue> {
+        Ok(match try!(value.which()) {
+               DataValue::String(s) => ClaimValue::String(try!(s).to_string()),
+               DataValue::Wikibaseentityid(r) => {
+                   ClaimValue::Entity(EntityRef::from_wikibaseentityid(try!(r)))
+               }
+               DataValue::Time(t) => {
+                   let t = try!(t);
+                   ClaimValue::Time {
+                       time: try!(t.get_time()).to_string(),
+                       precision: t.get_precision(),
+                       calendar: try!(t.get_calendarmodel()).to_string(),
+                   }
+               }
+               DataValue::Quantity(q) => {
+                   let q = try!(q);
+                   ClaimValue::Quantity {
+                       amount: q.get_amount(),
+                       unit: try!(q.get_unit()).to_string(),
+                   }
+               }
+               DataValue::Globecoordinate(g) => {
+                   let g = try!(g);
+                   ClaimValue::Coordinate {
+                       latitude: g.get_latitude(),
+                       longitude: g.get_longitude(),
+                       globe: try!(g.get_globe()).to_string(),
+                   }
+               }
+               DataValue::Monolingualtext(m) => {
+                   let m = try!(m);
+                   let text = match try!(m.which()) {
+                       MonolingualText::Value(t) => try!(t).to_string(),
+                       MonolingualText::Removed(()) => String::new(),
+                   };
+                   ClaimValue::Text {
+                       language: try!(m.get_language()).to_string(),
+                       text: text,
+                   }
+               }
+           })
+    }
+}
+
+/// Plain text rendering: ids for entities, the amount followed by the unit
+/// (unless it is "1") for quantities, WKT points for coordinates.
+impl ::std::fmt::Display for ClaimValue {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        match *self {
+            ClaimValue::Entity(ref e) => write!(f, "{}", e),
+            ClaimValue::String(ref s) => write!(f, "{}", s),
+            ClaimValue::Time { ref time, .. } => write!(f, "{}", time),
+            ClaimValue::Quantity { amount, ref unit } if unit == "1" => write!(f, "{}", amount),
+            ClaimValue::Quantity { amount, ref unit } => write!(f, "{} {}", amount, unit),
+            ClaimValue::Coordinate { latitude, longitude, .. } => {
+                write!(f, "Point({} {})", longitude, latitude)
+            }
+            ClaimValue::Text { ref text, .. } => write!(f, "{}", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnakValue {
+    Value(ClaimValue),
+    /// unknown value
+    SomeValue,
+    NoValue,
+}
+
+impl SnakValue {
+    fn of(snak: Snak::Reader) -> WikiResult<SnakValue> {
+        Ok(match try!(snak.which()) {
+               Snak::Value(v) => SnakValue::Value(try!(ClaimValue::of(try!(v)))),
+               Snak::Somevalue(()) => SnakValue::SomeValue,
+               Snak::Novalue(()) => SnakValue::NoValue,
+           })
+    }
+
+    pub fn value(&self) -> Option<&ClaimValue> {
+        match *self {
+            SnakValue::Value(ref v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// A claim, detached from its message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    pub id: String,
+    pub property: String,
+    /// "preferred", "normal" or "deprecated"
+    pub rank: &'static str,
+    pub datatype: String,
+    pub value: SnakValue,
+    /// (property, value), a property repeating when it has several values.
+    /// Empty for entities compiled before qualifiers were.
+    pub qualifiers: Vec<(String, SnakValue)>,
+}
+
 pub struct EntityMessage {
     message: Reader<OwnedSegments>,
 }
 
+impl EntityMessage {
+    /// Freeze a freshly compiled entity into the same form the `cap` files
+    /// are read into.
+    pub fn from_builder<A: Allocator>(builder: &Builder<A>) -> WikiResult<EntityMessage> {
+        let mut buffer: Vec<u8> = vec![];
+        try!(serialize::write_message(&mut buffer, builder));
+        let message = try!(serialize::read_message(&mut io::Cursor::new(buffer),
+                                                   capnp::message::ReaderOptions::new()));
+        Ok(EntityMessage { message: message })
+    }
+}
+
 pub trait EntityHelpers {
     fn as_entity_reader(&self) -> WikiResult<Entity::Reader>;
 
@@ -253,11 +452,24 @@ pub trait EntityHelpers {
         Ok(try!(try!(self.as_entity_reader()).get_labels()))
     }
 
-    /*
-    fn get_sitelinks(&self) -> WikiResult<Map::Reader<text::Owned,SiteLink::Owned>> {
-        Ok(try!(try!(self.as_entity_reader()).get_sitelinks()))
+    fn get_sitelinks(&self) -> WikiResult<Box<Iterator<Item = SitelinkEntry> + Send>> {
+        let mut result = vec![];
+        for entry in try!(try!(try!(self.as_entity_reader()).get_sitelinks()).get_entries())
+                .iter() {
+            let sitelink = try!(entry.get_value());
+            let mut badges = vec![];
+            for badge in try!(sitelink.get_badges()).iter() {
+                badges.push(try!(badge).to_string());
+            }
+            result.push(SitelinkEntry {
+                            site: try!(entry.get_key()).to_string(),
+                            title: try!(sitelink.get_title()).to_string(),
+                            badges: badges,
+                        });
+        }
+        Ok(Box::new(result.into_iter()))
     }
-*/
+
     fn get_descriptions(&self) -> WikiResult<Map::Reader<text::Owned, MonolingualText::Owned>> {
         Ok(try!(try!(self.as_entity_reader()).get_descriptions()))
     }
@@ -366,6 +578,34 @@ pub trait EntityHelpers {
         Ok(Box::new(result.into_iter()))
     }
 
+    fn get_statements(&self) -> WikiResult<Vec<Statement>> {
+        let mut result = vec![];
+        for claims in try!(try!(try!(self.as_entity_reader()).get_claims()).get_entries()).iter() {
+            let property = try!(claims.get_key()).to_string();
+            for claim in try!(claims.get_value()).iter() {
+                let snak = try!(claim.get_mainsnak());
+                let mut qualifiers = vec![];
+                for qualifier in try!(try!(claim.get_qualifiers()).get_entries()).iter() {
+                    qualifiers.push((try!(qualifier.get_key()).to_string(),
+                                     try!(SnakValue::of(try!(qualifier.get_value())))));
+                }
+                result.push(Statement {
+                                id: try!(claim.get_id()).to_string(),
+                                property: property.clone(),
+                                rank: match try!(claim.get_rank()) {
+                                    Claim::Rank::Preferred => "preferred",
+                                    Claim::Rank::Normal => "normal",
+                                    Claim::Rank::Deprecated => "deprecated",
+                                },
+                                datatype: try!(snak.get_datatype()).to_string(),
+                                value: try!(SnakValue::of(snak)),
+                                qualifiers: qualifiers,
+                            });
+            }
+        }
+        Ok(result)
+    }
+
     fn as_ref(&self) -> EntityRef {
         EntityRef::from_id(&self.get_id().unwrap())
     }
@@ -384,6 +624,15 @@ impl EntityHelpers for EntityMessage {
     }
 }
 
+impl Record for WikiResult<EntityMessage> {
+    fn record_id(&self) -> Option<String> {
+        match *self {
+            Ok(ref entity) => entity.get_id().ok().map(|id| id.to_string()),
+            Err(_) => None,
+        }
+    }
+}
+
 pub struct EntityReader<R: io::Read> {
     options: capnp::message::ReaderOptions,
     stream: io::BufReader<R>,
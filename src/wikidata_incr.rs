@@ -0,0 +1,250 @@
+//! Incremental Wikidata updates: applies changed entities on top of a
+//! compiled date, producing a new date. Part files without any changed
+//! entity are hard-linked from the base date, the other ones are rewritten.
+//!
+//! Updates come either as JSON files, one entity per line as in the full
+//! dump (`{"id": "Q42", "deleted": true}` removing an entity), or as the
+//! XML "incr" dumps (`wikidatawiki-{date}-pages-meta-hist-incr.xml.bz2`),
+//! whose last revision of each page holds the entity JSON.
+//!
+//! The XML "incr" dumps only hold the pages edited in the period: a deleted
+//! page is just absent, so entities deleted upstream stay in the updated
+//! date. Merged entities do show up, as redirects, and are removed. Only
+//! JSON updates can delete.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path;
+
+use bzip2::read::BzDecoder;
+use flate2::read::MultiGzDecoder;
+use serde_json;
+use serde_json::value::Value;
+
+use capnp::message::Builder;
+use tinycdb::Cdb;
+
+use WikiResult;
+use blocks;
+use blocks::BlockWriter;
+use capitanize_wikidata;
+use capitanize_wikidata::IndexedEntity;
+use helpers;
+use store::{DataStore, Stage};
+use wikidata::{EntityHelpers, EntityMessage, Wikidata};
+
+pub enum Change {
+    Replace(Value),
+    Delete,
+}
+
+fn open(file: &path::Path) -> WikiResult<Box<Read>> {
+    let name = file.to_str().unwrap_or("");
+    let raw = try!(fs::File::open(file));
+    if name.ends_with(".gz") {
+        Ok(Box::new(try!(MultiGzDecoder::new(raw))))
+    } else if name.ends_with(".bz2") {
+        Ok(Box::new(BzDecoder::new(raw)))
+    } else {
+        Ok(Box::new(raw))
+    }
+}
+
+/// Entity id of a page of an incr dump ("Q42", "Property:P31").
+fn id_from_title(title: &str) -> String {
+    title.rsplit(':').next().unwrap_or(title).to_string()
+}
+
+/// The change an entity JSON value carries. Redirects (merged entities)
+/// remove the redirected entity.
+fn change_of(value: Value, fallback_id: Option<&str>) -> WikiResult<(String, Change)> {
+    let id = value
+        .pointer("/id")
+        .or(value.pointer("/entity"))
+        .and_then(|v| v.as_str())
+        .or(fallback_id)
+        .map(|s| s.to_string());
+    let id = try!(id.ok_or(format!("no entity id in update: {}", value)));
+    let deleted = value.pointer("/deleted").and_then(|v| v.as_bool()).unwrap_or(false) ||
+                  value.pointer("/redirect").is_some();
+    if deleted {
+        Ok((id, Change::Delete))
+    } else {
+        Ok((id, Change::Replace(value)))
+    }
+}
+
+/// Read an update file into `changes`, later changes of an entity
+/// overriding earlier ones. `.gz` files may be several gzip members
+/// concatenated. XML dumps never delete (see the module documentation).
+pub fn read_updates(file: &path::Path, changes: &mut HashMap<String, Change>) -> WikiResult<()> {
+    let name = file.to_str().unwrap_or("");
+    if name.contains(".xml") {
+        for page in try!(helpers::pages_from_xml(try!(open(file)))) {
+            let page = try!(page);
+            let json = page.latest_revision()
+                .and_then(|r| match r.model {
+                              Some(ref model) if model.starts_with("wikibase-") => r.text.as_ref(),
+                              _ => None,
+                          });
+            if let Some(json) = json {
+                let value: Value = try!(serde_json::from_str(json));
+                let (id, change) = try!(change_of(value, Some(&*id_from_title(&*page.title))));
+                changes.insert(id, change);
+            }
+        }
+    } else {
+        for line in io::BufReader::new(try!(open(file))).lines() {
+            let line = try!(line);
+            let line = line.trim().trim_right_matches(',');
+            if line.is_empty() || line == "[" || line == "]" {
+                continue;
+            }
+            let value: Value = try!(serde_json::from_str(line)
+                                        .map_err(|e| format!("{:?}: {}", file, e)));
+            let (id, change) = try!(change_of(value, None));
+            changes.insert(id, change);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct UpdateReport {
+    /// part files linked from the base date
+    pub reused: usize,
+    pub rewritten: usize,
+    pub replaced: usize,
+    pub added: usize,
+    pub deleted: usize,
+}
+
+/// Write a changed entity, returning what the lookup tables need of it.
+fn write_change(out: &mut BlockWriter, value: &Value, part: &str) -> WikiResult<IndexedEntity> {
+    let mut message = try!(capitanize_wikidata::entity_message(value));
+    try!(out.write_message(&mut message));
+    IndexedEntity::of(&try!(EntityMessage::from_builder(&message)), part)
+}
+
+/// Hard-link (or copy) a part file into another date, with its block index
+/// if it has one.
+fn link_part(part: &path::Path, target: &path::Path) -> WikiResult<()> {
+    let mut files = vec![(part.to_path_buf(), target.to_path_buf())];
+    let index = blocks::index_path(part);
+    if index.exists() {
+        files.push((index, blocks::index_path(target)));
+    }
+    for (from, to) in files {
+        if fs::hard_link(&from, &to).is_err() {
+            try!(fs::copy(&from, &to));
+        }
+    }
+    Ok(())
+}
+
+/// Compile the wikidata of `date` from the one of `base` and the update
+/// files, with its label and id lookup tables. The base date needs its id
+/// lookup table (built by `wikidata_cdb`).
+pub fn apply(store: &DataStore,
+             base: &str,
+             date: &str,
+             updates: &[path::PathBuf])
+             -> WikiResult<UpdateReport> {
+    if base >= date {
+        Err(format!("updated date {} must be after its base {}", date, base))?
+    }
+    let mut changes = HashMap::new();
+    for update in updates {
+        try!(read_updates(&*update, &mut changes));
+    }
+    let wikidata = try!(Wikidata::for_date(store, base));
+    let mut touched = HashSet::new();
+    let mut added = vec![];
+    for (id, change) in &changes {
+        match (try!(wikidata.part_of(id)), change) {
+            (Some(part), _) => {
+                touched.insert(part);
+            }
+            (None, &Change::Replace(_)) => added.push(id.clone()),
+            (None, &Change::Delete) => (),
+        }
+    }
+    added.sort();
+
+    let mut report = UpdateReport::default();
+    // lookup table entries of the replaced and added entities; the other
+    // ones are carried over from the base tables
+    let mut overlay: Vec<IndexedEntity> = vec![];
+    let cap = try!(store.staging(Stage::Cap, "wikidata", date));
+    for part in try!(wikidata.cap_files()) {
+        let part = try!(part);
+        let name = part.file_name().unwrap().to_str().unwrap().to_string();
+        let target = path::Path::new(cap.dir()).join(&*name);
+        if !touched.contains(&name) {
+            try!(link_part(&part, &target));
+            report.reused += 1;
+            continue;
+        }
+        let mut out = try!(BlockWriter::create(&target));
+        for entity in try!(Wikidata::entity_iter_for_file(part.clone())) {
+            let entity = try!(entity);
+            match changes.get(try!(entity.get_id())) {
+                Some(&Change::Delete) => report.deleted += 1,
+                Some(&Change::Replace(ref value)) => {
+                    overlay.push(try!(write_change(&mut out, value, &*name)));
+                    report.replaced += 1;
+                }
+                None => {
+                    let mut message = Builder::new_default();
+                    try!(message.set_root(try!(entity.as_entity_reader())));
+                    try!(out.write_message(&mut message));
+                }
+            }
+        }
+        try!(out.finish());
+        report.rewritten += 1;
+    }
+    if added.len() > 0 {
+        let name = format!("wikidata-{}-incr-part-00000.cap.gz", date);
+        let target = path::Path::new(cap.dir()).join(&*name);
+        let mut out = try!(BlockWriter::create(&target));
+        for id in &added {
+            if let Some(&Change::Replace(ref value)) = changes.get(id) {
+                overlay.push(try!(write_change(&mut out, value, &*name)));
+            }
+        }
+        try!(out.finish());
+        report.added = added.len();
+    }
+    try!(cap.commit());
+
+    // the base tables are streamed through, leaving out the changed ids,
+    // then the changed entities are added
+    let labels = try!(store.staging(Stage::Labels, "wikidata", date));
+    let base_labels_dir = store.dir(Stage::Labels, "wikidata", base);
+    let mut base_ids = try!(Cdb::open(&*path::Path::new(&*base_labels_dir).join("ids"))
+                                .map_err(|e| format!("Cdb Error: {:?}", e)));
+    let mut base_labels = try!(Cdb::open(&*path::Path::new(&*base_labels_dir).join("labels"))
+                                   .map_err(|e| format!("Cdb Error: {:?}", e)));
+    {
+        let unchanged = base_ids
+            .iter()
+            .filter(|&(id, _)| {
+                        !changes.contains_key(&*String::from_utf8_lossy(id))
+                    })
+            .map(|(id, part)| {
+                let label = base_labels.find(id).map(|l| String::from_utf8_lossy(l).to_string());
+                Ok(IndexedEntity {
+                       id: String::from_utf8_lossy(id).to_string(),
+                       label: label,
+                       part: String::from_utf8_lossy(part).to_string(),
+                   })
+            });
+        let entities = unchanged.chain(overlay.into_iter().map(|e| Ok(e)));
+        try!(capitanize_wikidata::write_indexes(path::Path::new(labels.dir()), entities));
+    }
+    try!(labels.commit());
+    Ok(report)
+}
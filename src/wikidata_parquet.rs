@@ -0,0 +1,456 @@
+//! Parquet export of compiled Wikidata, one file per compiled part in each
+//! of the `entities/` and `statements/` directories of the parquet stage.
+//! Strings are stored as they are (UTF8 binary columns), with no escaping.
+//!
+//! `entities`, one row per entity:
+//!
+//! ```text
+//! message entities {
+//!   required binary id (UTF8);              -- "Q42"
+//!   required binary type (UTF8);            -- "item" or "property"
+//!   optional group labels (MAP) {           -- language to label
+//!     repeated group key_value {
+//!       required binary key (UTF8);
+//!       required binary value (UTF8);
+//!     }
+//!   }
+//!   optional group descriptions (MAP) {     -- language to description
+//!     repeated group key_value {
+//!       required binary key (UTF8);
+//!       required binary value (UTF8);
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! `statements`, one row per claim. The value goes to the typed columns of
+//! its kind, the other ones are null; `snak_type` tells unknown ("somevalue")
+//! and absent ("novalue") values, which fill none.
+//!
+//! ```text
+//! message statements {
+//!   required binary id (UTF8);              -- claim GUID
+//!   required binary subject (UTF8);         -- "Q42"
+//!   required binary property (UTF8);        -- "P31"
+//!   required binary rank (UTF8);            -- preferred, normal, deprecated
+//!   required binary snak_type (UTF8);       -- value, somevalue, novalue
+//!   optional binary datatype (UTF8);        -- wikibase-item, time, ...
+//!   optional binary entity_value (UTF8);
+//!   optional binary string_value (UTF8);    -- strings, external ids, urls...
+//!   optional binary time_value (UTF8);      -- "+1952-03-11T00:00:00Z"
+//!   optional int32 time_precision;          -- 11 for a day, 9 for a year
+//!   optional binary time_calendar (UTF8);
+//!   optional double quantity_amount;
+//!   optional binary quantity_unit (UTF8);   -- "1" for none
+//!   optional double latitude;
+//!   optional double longitude;
+//!   optional binary globe (UTF8);
+//!   optional binary text_language (UTF8);   -- monolingual text
+//!   optional binary text_value (UTF8);
+//!   optional group qualifiers (MAP) {       -- property to value, repeating
+//!     repeated group key_value {            -- for several values
+//!       required binary key (UTF8);
+//!       optional binary value (UTF8);       -- as text, null if unknown
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! Rows are written by groups of `ROW_GROUP_ROWS`.
+
+use std::fs;
+use std::path;
+use std::rc::Rc;
+
+use parquet::basic::Compression;
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{FileWriter, RowGroupWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+
+use capnp::text;
+
+use WikiResult;
+use blocks;
+use wikidata::{ClaimValue, EntityHelpers, EntityMessage, EntityReader, EntityType, Map,
+               MonolingualText, SnakValue};
+
+pub const ROW_GROUP_ROWS: usize = 100_000;
+
+const ENTITIES: &'static str = "
+message entities {
+  required binary id (UTF8);
+  required binary type (UTF8);
+  optional group labels (MAP) {
+    repeated group key_value {
+      required binary key (UTF8);
+      required binary value (UTF8);
+    }
+  }
+  optional group descriptions (MAP) {
+    repeated group key_value {
+      required binary key (UTF8);
+      required binary value (UTF8);
+    }
+  }
+}";
+
+const STATEMENTS: &'static str = "
+message statements {
+  required binary id (UTF8);
+  required binary subject (UTF8);
+  required binary property (UTF8);
+  required binary rank (UTF8);
+  required binary snak_type (UTF8);
+  optional binary datatype (UTF8);
+  optional binary entity_value (UTF8);
+  optional binary string_value (UTF8);
+  optional binary time_value (UTF8);
+  optional int32 time_precision;
+  optional binary time_calendar (UTF8);
+  optional double quantity_amount;
+  optional binary quantity_unit (UTF8);
+  optional double latitude;
+  optional double longitude;
+  optional binary globe (UTF8);
+  optional binary text_language (UTF8);
+  optional binary text_value (UTF8);
+  optional group qualifiers (MAP) {
+    repeated group key_value {
+      required binary key (UTF8);
+      optional binary value (UTF8);
+    }
+  }
+}";
+
+enum Values {
+    Bytes(Vec<ByteArray>),
+    Int32(Vec<i32>),
+    Double(Vec<f64>),
+}
+
+/// Values and levels of a leaf column, for the current row group.
+struct Column {
+    values: Values,
+    max_def: i16,
+    max_rep: i16,
+    def: Vec<i16>,
+    rep: Vec<i16>,
+}
+
+impl Column {
+    fn new(values: Values, max_def: i16, max_rep: i16) -> Column {
+        Column {
+            values: values,
+            max_def: max_def,
+            max_rep: max_rep,
+            def: vec![],
+            rep: vec![],
+        }
+    }
+
+    fn bytes(max_def: i16, max_rep: i16) -> Column {
+        Column::new(Values::Bytes(vec![]), max_def, max_rep)
+    }
+
+    fn int32(max_def: i16) -> Column {
+        Column::new(Values::Int32(vec![]), max_def, 0)
+    }
+
+    fn double(max_def: i16) -> Column {
+        Column::new(Values::Double(vec![]), max_def, 0)
+    }
+
+    fn levels(&mut self, def: i16, rep: i16) {
+        self.def.push(def);
+        self.rep.push(rep);
+    }
+
+    fn push_str(&mut self, value: Option<&str>, rep: i16) {
+        match (value, &mut self.values) {
+            (Some(v), &mut Values::Bytes(ref mut values)) => {
+                values.push(ByteArray::from(v.as_bytes().to_vec()))
+            }
+            (None, _) => (),
+            _ => panic!("not a binary column"),
+        }
+        let def = if value.is_some() { self.max_def } else { self.max_def - 1 };
+        self.levels(def, rep);
+    }
+
+    fn push_int32(&mut self, value: Option<i32>) {
+        match (value, &mut self.values) {
+            (Some(v), &mut Values::Int32(ref mut values)) => values.push(v),
+            (None, _) => (),
+            _ => panic!("not an int32 column"),
+        }
+        let def = if value.is_some() { self.max_def } else { self.max_def - 1 };
+        self.levels(def, 0);
+    }
+
+    fn push_double(&mut self, value: Option<f64>) {
+        match (value, &mut self.values) {
+            (Some(v), &mut Values::Double(ref mut values)) => values.push(v),
+            (None, _) => (),
+            _ => panic!("not a double column"),
+        }
+        let def = if value.is_some() { self.max_def } else { self.max_def - 1 };
+        self.levels(def, 0);
+    }
+
+    fn write(&mut self, writer: &mut ColumnWriter) -> WikiResult<()> {
+        {
+            let def = if self.max_def > 0 { Some(&*self.def) } else { None };
+            let rep = if self.max_rep > 0 { Some(&*self.rep) } else { None };
+            match (&self.values, writer) {
+                (&Values::Bytes(ref v), &mut ColumnWriter::ByteArrayColumnWriter(ref mut w)) => {
+                    try!(w.write_batch(v, def, rep));
+                }
+                (&Values::Int32(ref v), &mut ColumnWriter::Int32ColumnWriter(ref mut w)) => {
+                    try!(w.write_batch(v, def, rep));
+                }
+                (&Values::Double(ref v), &mut ColumnWriter::DoubleColumnWriter(ref mut w)) => {
+                    try!(w.write_batch(v, def, rep));
+                }
+                _ => Err("parquet column type does not match the schema")?,
+            }
+        }
+        match self.values {
+            Values::Bytes(ref mut v) => v.clear(),
+            Values::Int32(ref mut v) => v.clear(),
+            Values::Double(ref mut v) => v.clear(),
+        }
+        self.def.clear();
+        self.rep.clear();
+        Ok(())
+    }
+}
+
+/// A Parquet file being written, row group by row group.
+struct Table {
+    writer: SerializedFileWriter<fs::File>,
+    columns: Vec<Column>,
+    rows: usize,
+}
+
+impl Table {
+    fn create(path: &path::Path, schema: &str, columns: Vec<Column>) -> WikiResult<Table> {
+        let schema = Rc::new(try!(parse_message_type(schema)));
+        let props = Rc::new(WriterProperties::builder()
+                                .set_compression(Compression::SNAPPY)
+                                .build());
+        Ok(Table {
+               writer: try!(SerializedFileWriter::new(try!(fs::File::create(path)),
+                                                      schema,
+                                                      props)),
+               columns: columns,
+               rows: 0,
+           })
+    }
+
+    fn str(&mut self, column: usize, value: &str) {
+        self.columns[column].push_str(Some(value), 0);
+    }
+
+    fn opt_str(&mut self, column: usize, value: Option<&str>) {
+        self.columns[column].push_str(value, 0);
+    }
+
+    /// Entries of a map, as its key and value columns.
+    fn map(&mut self, key: usize, value: usize, entries: &[(String, Option<String>)]) {
+        if entries.len() == 0 {
+            // present, but empty
+            self.columns[key].levels(1, 0);
+            self.columns[value].levels(1, 0);
+            return;
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            let rep = if i == 0 { 0 } else { 1 };
+            self.columns[key].push_str(Some(&*entry.0), rep);
+            self.columns[value].push_str(entry.1.as_ref().map(|s| &**s), rep);
+        }
+    }
+
+    fn end_row(&mut self) -> WikiResult<()> {
+        self.rows += 1;
+        if self.rows >= ROW_GROUP_ROWS {
+            try!(self.flush());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> WikiResult<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let mut group = try!(self.writer.next_row_group());
+        let mut i = 0;
+        while let Some(mut column) = try!(group.next_column()) {
+            try!(self.columns[i].write(&mut column));
+            try!(group.close_column(column));
+            i += 1;
+        }
+        try!(self.writer.close_row_group(group));
+        self.rows = 0;
+        Ok(())
+    }
+
+    fn close(mut self) -> WikiResult<()> {
+        try!(self.flush());
+        try!(self.writer.close());
+        Ok(())
+    }
+}
+
+fn monolingual_entries(map: Map::Reader<text::Owned, MonolingualText::Owned>)
+                       -> WikiResult<Vec<(String, Option<String>)>> {
+    let mut entries = vec![];
+    for entry in try!(map.get_entries()).iter() {
+        if let MonolingualText::Value(t) = try!(try!(entry.get_value()).which()) {
+            entries.push((try!(entry.get_key()).to_string(), Some(try!(t).to_string())));
+        }
+    }
+    Ok(entries)
+}
+
+fn write_entity(table: &mut Table, entity: &EntityMessage) -> WikiResult<()> {
+    let reader = try!(entity.as_entity_reader());
+    table.str(0, try!(reader.get_id()));
+    table.str(1,
+              match try!(reader.get_type()) {
+                  EntityType::Item => "item",
+                  EntityType::Property => "property",
+              });
+    table.map(2, 3, &*try!(monolingual_entries(try!(reader.get_labels()))));
+    table.map(4, 5, &*try!(monolingual_entries(try!(reader.get_descriptions()))));
+    table.end_row()
+}
+
+fn write_statements(table: &mut Table, entity: &EntityMessage) -> WikiResult<()> {
+    let subject = try!(entity.get_id()).to_string();
+    for statement in try!(entity.get_statements()) {
+        table.str(0, &*statement.id);
+        table.str(1, &*subject);
+        table.str(2, &*statement.property);
+        table.str(3, statement.rank);
+        table.str(4,
+                  match statement.value {
+                      SnakValue::Value(_) => "value",
+                      SnakValue::SomeValue => "somevalue",
+                      SnakValue::NoValue => "novalue",
+                  });
+        table.opt_str(5,
+                      if statement.datatype.is_empty() {
+                          None
+                      } else {
+                          Some(&*statement.datatype)
+                      });
+        let value = statement.value.value();
+        let entity_value = match value {
+            Some(&ClaimValue::Entity(ref e)) => Some(e.get_id()),
+            _ => None,
+        };
+        table.opt_str(6, entity_value.as_ref().map(|s| &**s));
+        table.opt_str(7,
+                      match value {
+                          Some(&ClaimValue::String(ref s)) => Some(&**s),
+                          _ => None,
+                      });
+        let (time, precision, calendar) = match value {
+            Some(&ClaimValue::Time { ref time, precision, ref calendar }) => {
+                (Some(&**time), Some(precision as i32), Some(&**calendar))
+            }
+            _ => (None, None, None),
+        };
+        table.opt_str(8, time);
+        table.columns[9].push_int32(precision);
+        table.opt_str(10, calendar);
+        let (amount, unit) = match value {
+            Some(&ClaimValue::Quantity { amount, ref unit }) => (Some(amount), Some(&**unit)),
+            _ => (None, None),
+        };
+        table.columns[11].push_double(amount);
+        table.opt_str(12, unit);
+        let (latitude, longitude, globe) = match value {
+            Some(&ClaimValue::Coordinate { latitude, longitude, ref globe }) => {
+                (Some(latitude), Some(longitude), Some(&**globe))
+            }
+            _ => (None, None, None),
+        };
+        table.columns[13].push_double(latitude);
+        table.columns[14].push_double(longitude);
+        table.opt_str(15, globe);
+        let (language, text) = match value {
+            Some(&ClaimValue::Text { ref language, ref text }) => (Some(&**language), Some(&**text)),
+            _ => (None, None),
+        };
+        table.opt_str(16, language);
+        table.opt_str(17, text);
+        let qualifiers: Vec<(String, Option<String>)> = statement.qualifiers
+            .iter()
+            .map(|&(ref p, ref v)| (p.clone(), v.value().map(|v| v.to_string())))
+            .collect();
+        table.map(18, 19, &*qualifiers);
+        try!(table.end_row());
+    }
+    Ok(())
+}
+
+/// Export a compiled part as `{entities}/{stem}.parquet` and
+/// `{statements}/{stem}.parquet`. Returns the count of entities.
+pub fn export_part(part: &path::Path,
+                   entities: &path::Path,
+                   statements: &path::Path)
+                   -> WikiResult<usize> {
+    let name = try!(part.file_name()
+                        .and_then(|n| n.to_str())
+                        .ok_or(format!("bad part name {:?}", part)));
+    let stem = name.trim_right_matches(".gz").trim_right_matches(".cap");
+    let file = format!("{}.parquet", stem);
+    let mut entity_table = try!(Table::create(&*entities.join(&*file),
+                                              ENTITIES,
+                                              vec![Column::bytes(0, 0),
+                                                   Column::bytes(0, 0),
+                                                   Column::bytes(2, 1),
+                                                   Column::bytes(2, 1),
+                                                   Column::bytes(2, 1),
+                                                   Column::bytes(2, 1)]));
+    let mut statement_table = try!(Table::create(&*statements.join(&*file),
+                                                 STATEMENTS,
+                                                 vec![Column::bytes(0, 0),
+                                                      Column::bytes(0, 0),
+                                                      Column::bytes(0, 0),
+                                                      Column::bytes(0, 0),
+                                                      Column::bytes(0, 0),
+                                                      Column::bytes(1, 0),
+                                                      Column::bytes(1, 0),
+                                                      Column::bytes(1, 0),
+                                                      Column::bytes(1, 0),
+                                                      Column::int32(1),
+                                                      Column::bytes(1, 0),
+                                                      Column::double(1),
+                                                      Column::bytes(1, 0),
+                                                      Column::double(1),
+                                                      Column::double(1),
+                                                      Column::bytes(1, 0),
+                                                      Column::bytes(1, 0),
+                                                      Column::bytes(1, 0),
+                                                      Column::bytes(2, 1),
+                                                      Column::bytes(3, 1)]));
+    let mut count = 0;
+    let split = blocks::Split {
+        part: part.to_path_buf(),
+        offset: 0,
+        length: None,
+    };
+    for entity in EntityReader::for_reader(try!(split.open())) {
+        let entity = try!(entity);
+        try!(write_entity(&mut entity_table, &entity));
+        try!(write_statements(&mut statement_table, &entity));
+        count += 1;
+    }
+    try!(entity_table.close());
+    try!(statement_table.close());
+    Ok(count)
+}